@@ -1,35 +1,83 @@
 #[global_allocator]
 pub static GLOBAL: GlobalAllocator = GlobalAllocator;
 
-/// Allocates a chunk of memory.
+/// Allocates a chunk of memory, growing the heap if it is exhausted.
+///
+/// Returns a null pointer if the heap cannot satisfy the request.
+#[cfg_attr(feature = "alloc-tracing", link_section = "alloc_text")]
 #[no_mangle]
 pub extern "C" fn __rust_allocate(size: usize, align: usize) -> *mut u8 {
-   let layout = Layout::from_size_align(size, align);
-   unsafe {
-      HEAP
-         .lock()
-         .as_mut()
-         .expect("must first initialise heap before allocating memory")
-         .allocate(layout)
-         .unwrap()
-         .as_ptr()
-   }
+   let layout = match Layout::from_size_align(size, align) {
+      Ok(layout) => layout,
+      Err(_) => return ptr::null_mut(),
+   };
+
+   #[cfg(not(feature = "redzones"))]
+   let pointer = heap_allocate(layout);
+
+   // The `redzones` feature surrounds every allocation with redzones; see `redzone`.
+   #[cfg(feature = "redzones")]
+   let pointer = match redzone::guarded_layout(layout) {
+      Some(guarded) => unsafe{ redzone::arm(heap_allocate(guarded), layout) },
+      None => ptr::null_mut(),
+   };
+
+   #[cfg(feature = "alloc-tracing")]
+   trace::record_allocation(pointer, layout);
+
+   return pointer;
 }
 
-/// Frees a chunk of memory.
+/// Frees a chunk of memory. Freeing a null pointer does nothing.
 #[no_mangle]
 pub extern "C" fn __rust_deallocate(pointer: *mut u8, oldSize: usize, align: usize) {
-   let layout = Layout::from_size_align(oldSize, align);
+   if pointer.is_null() {
+      return;
+   }
+
+   // The pair was valid when the block was allocated.
+   let layout = unsafe{ Layout::from_size_align_unchecked(oldSize, align) };
+
+   // Before the block is back in the heap, where another CPU could take it and record it.
+   #[cfg(feature = "alloc-tracing")]
+   trace::record_free(pointer);
+
+   #[cfg(not(feature = "redzones"))]
+   unsafe{ heap_deallocate(pointer, layout) };
+
+   // The block is checked and poisoned, then held back so that later writes through a dangling
+   // pointer land in poison instead of someone else's allocation.
+   #[cfg(feature = "redzones")]
+   unsafe {
+      redzone::quarantine(pointer, layout);
+      while let Some((block, guarded)) = redzone::evict() {
+         heap_deallocate(block, guarded);
+      }
+   }
+}
+
+fn heap_allocate(layout: Layout) -> *mut u8 {
    unsafe {
       HEAP
          .lock()
          .as_mut()
-         .expect("must first initialise heap before attempting to deallocate memory")
-         .deallocate(NonNull::new_unchecked(pointer), layout);
+         .expect("must first initialise heap before allocating memory")
+         .allocate_or_extend(layout)
+         .map_or(ptr::null_mut(), |allocation| allocation.as_ptr())
    }
 }
 
-/// Reallocates a chunk of memory.
+unsafe fn heap_deallocate(pointer: *mut u8, layout: Layout) {
+   HEAP
+      .lock()
+      .as_mut()
+      .expect("must first initialise heap before attempting to deallocate memory")
+      .deallocate(NonNull::new_unchecked(pointer), layout);
+}
+
+/// Reallocates a chunk of memory, in place if the heap can resize the block where it is.
+/// Reallocating a null pointer allocates afresh.
+#[cfg_attr(feature = "alloc-tracing", link_section = "alloc_text")]
 #[no_mangle]
 pub extern "C" fn __rust_reallocate(
    pointer: *mut u8,
@@ -37,6 +85,14 @@ pub extern "C" fn __rust_reallocate(
    newSize: usize,
    align: usize,
 ) -> *mut u8 {
+   if pointer.is_null() {
+      return __rust_allocate(newSize, align);
+   }
+
+   if __rust_reallocate_inplace(pointer, oldSize, newSize, align) == newSize {
+      return pointer;
+   }
+
    let newPointer = __rust_allocate(newSize, align);
    return if newPointer.is_null() {
       newPointer
@@ -50,23 +106,59 @@ pub extern "C" fn __rust_reallocate(
    };
 }
 
-/// We do not support in-place reallocation, so just return `oldSize`.
+/// Resizes a chunk of memory without moving it.
+///
+/// Returns `size` if the block now holds `size` bytes, or `oldSize` if it could not be resized
+/// and was left alone.
 #[no_mangle]
 pub extern "C" fn __rust_reallocate_inplace(
-   _pointer: *mut u8,
+   pointer: *mut u8,
    oldSize: usize,
-   _size: usize,
-   _align: usize,
+   size: usize,
+   align: usize,
 ) -> usize {
-   oldSize
+   // Growing in place would move the upper redzone into memory the block does not own, so with
+   // redzones blocks always move.
+   if cfg!(feature = "redzones") {
+      return oldSize;
+   }
+
+   let pointer = match NonNull::new(pointer) {
+      Some(pointer) => pointer,
+      None => return oldSize,
+   };
+
+   unsafe {
+      let old = Layout::from_size_align_unchecked(oldSize, align);
+      let resized = HEAP
+         .lock()
+         .as_mut()
+         .expect("must first initialise heap before reallocating memory")
+         .reallocate_in_place(pointer, old, size);
+
+      #[cfg(feature = "alloc-tracing")]
+      if resized {
+         trace::record_resize(pointer.as_ptr(), size);
+      }
+
+      return if resized { size } else { oldSize };
+   }
 }
 
-/// I have no idea what this actually does, but we're supposed to have one,
-/// and the other backends to implement it as something equivalent to the
-/// following.
+/// Returns how many bytes an allocation of `size` bytes aligned to `align` really gets. The
+/// buddy heap rounds every block up to a power of two, and the rest of the block can be claimed
+/// with [`__rust_reallocate_inplace`].
 #[no_mangle]
-pub extern "C" fn __rust_usable_size(size: usize, _align: usize) -> usize {
-   size
+pub extern "C" fn __rust_usable_size(size: usize, align: usize) -> usize {
+   // The slack in a guarded block is redzone.
+   if cfg!(feature = "redzones") {
+      return size;
+   }
+
+   return match Layout::from_size_align(size, align) {
+      Ok(layout) => block_size(layout),
+      Err(_) => size,
+   };
 }
 
 /// Performs a single heap allocation, like `malloc`.
@@ -97,7 +189,7 @@ pub unsafe fn allocate<T>(allocator: &mut dyn Allocator) -> Option<NonNull<T>> {
 /// where the call is inherently unsafe because we aren't certain the allocation will succeed.
 pub unsafe fn allocate_array<T>(allocator: &mut dyn Allocator, size: usize) -> Option<NonNull<T>> {
    allocator
-      .allocate_aligned(Layout::from_type_array::<T>(size))
+      .allocate_aligned(Layout::from_type_array::<T>(size).ok()?)
       .map(|ptr| ptr.cast::<T>())
 }
 
@@ -110,6 +202,43 @@ impl Display for AllocationError {
    }
 }
 
+/// How an allocator's free memory is split up, for comparing allocators on the same workload.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Fragmentation {
+   /// Free bytes.
+   pub free: usize,
+
+   /// Size of the largest free block.
+   pub largest_free: usize,
+
+   /// Number of free blocks.
+   pub free_blocks: usize,
+}
+
+impl Fragmentation {
+   /// Share of free memory, in percent, that lies outside the largest free block: 0 when all free
+   /// memory is one block, approaching 100 as it splinters.
+   pub fn percent(&self) -> usize {
+      return match self.free {
+         0 => 0,
+         free => (free - self.largest_free) * 100 / free,
+      };
+   }
+}
+
+impl Display for Fragmentation {
+   fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+      write!(
+         f,
+         "{} bytes free in {} blocks, largest {} bytes ({}% fragmented)",
+         self.free,
+         self.free_blocks,
+         self.largest_free,
+         self.percent(),
+      )
+   }
+}
+
 /// A global allocator for our program.
 #[derive(Copy, Clone)]
 pub struct GlobalAllocator;
@@ -140,10 +269,20 @@ pub unsafe trait Allocator {
       layout: Layout,
    ) -> Option<NonNull<u8>>;
 
-   unsafe fn allocate_aligned(&self, layout: Layout) -> Option<NonNull<u8>> {
-      let actualSize = layout.size + layout.align - 1 + size_of::<usize>();
+   /// Number of bytes actually available in an allocation made for `layout`. An allocation can be
+   /// [`reallocate_in_place`](Allocator::reallocate_in_place)d up to this size.
+   fn usable_size(&self, layout: Layout) -> usize {
+      return layout.size;
+   }
 
-      let pointer = match self.allocate(Layout::from_size(actualSize)) {
+   /// Tries to resize the allocation at `pointer` from `oldSize` to `layout.size` bytes without
+   /// moving it. Returns `false`, leaving the allocation alone, if it cannot.
+   unsafe fn reallocate_in_place(&self, _pointer: *mut u8, _oldSize: usize, _layout: Layout) -> bool {
+      return false;
+   }
+
+   unsafe fn allocate_aligned(&self, layout: Layout) -> Option<NonNull<u8>> {
+      let pointer = match self.allocate(padded_layout(layout)?) {
          Some(p) => p.as_ptr() as usize,
          None => return None,
       };
@@ -161,10 +300,50 @@ pub unsafe trait Allocator {
       let actualP2P = alignedPointer - size_of::<usize>();
       let actualPointer = ptr::read_unaligned(actualP2P as *const usize);
 
-      self.deallocate(actualPointer as *mut u8, layout);
+      self.deallocate(
+         actualPointer as *mut u8,
+         padded_layout(layout).expect("layout was valid when allocated"),
+      );
+   }
+
+   /// [`usable_size`](Allocator::usable_size) for an allocation made with
+   /// [`allocate_aligned`](Allocator::allocate_aligned), counted from the aligned pointer.
+   fn usable_size_aligned(&self, layout: Layout) -> usize {
+      return match padded_layout(layout) {
+         Some(padded) => self.usable_size(padded) - (padded.size - layout.size),
+         None => layout.size,
+      };
+   }
+
+   /// [`reallocate_in_place`](Allocator::reallocate_in_place) for an allocation made with
+   /// [`allocate_aligned`](Allocator::allocate_aligned).
+   unsafe fn reallocate_aligned_in_place(&self, pointer: *mut u8, layout: Layout, size: usize) -> bool {
+      let actualP2P = pointer as usize - size_of::<usize>();
+      let actualPointer = ptr::read_unaligned(actualP2P as *const usize);
+
+      let (old, new) = match (padded_layout(layout), Layout::from_size_align(size, layout.align)) {
+         (Some(old), Ok(new)) => (old, padded_layout(new)),
+         _ => return false,
+      };
+
+      // The aligned pointer keeps its offset into the block, so only the padded size changes.
+      return match new {
+         Some(new) => self.reallocate_in_place(actualPointer as *mut u8, old.size, new),
+         None => false,
+      };
    }
 }
 
+/// The layout [`Allocator::allocate_aligned`] asks the allocator for: room to align `layout` by
+/// hand, plus the original pointer stored just before the aligned one.
+fn padded_layout(layout: Layout) -> Option<Layout> {
+   let size = layout.size
+      .checked_add(layout.align - 1)?
+      .checked_add(size_of::<usize>())?;
+
+   return Layout::from_size_align(size, align_of::<usize>()).ok();
+}
+
 unsafe impl<A: Allocator> Allocator for Mutex<A> {
    fn allocate(&self, layout: Layout) -> Option<NonNull<u8>> {
       return self.lock().allocate(layout);
@@ -182,6 +361,14 @@ unsafe impl<A: Allocator> Allocator for Mutex<A> {
    ) -> Option<NonNull<u8>> {
       return self.lock().reallocate(pointer, oldSize, layout);
    }
+
+   fn usable_size(&self, layout: Layout) -> usize {
+      return self.lock().usable_size(layout);
+   }
+
+   unsafe fn reallocate_in_place(&self, pointer: *mut u8, oldSize: usize, layout: Layout) -> bool {
+      return self.lock().reallocate_in_place(pointer, oldSize, layout);
+   }
 }
 
 unsafe impl<A: Allocator> Allocator for LockedAllocator<A> {
@@ -201,6 +388,14 @@ unsafe impl<A: Allocator> Allocator for LockedAllocator<A> {
    ) -> Option<NonNull<u8>> {
       return self.lock().reallocate(pointer, oldSize, layout);
    }
+
+   fn usable_size(&self, layout: Layout) -> usize {
+      return self.lock().usable_size(layout);
+   }
+
+   unsafe fn reallocate_in_place(&self, pointer: *mut u8, oldSize: usize, layout: Layout) -> bool {
+      return self.lock().reallocate_in_place(pointer, oldSize, layout);
+   }
 }
 
 unsafe impl<A: Allocator> Allocator for &RefCell<A> {
@@ -220,36 +415,52 @@ unsafe impl<A: Allocator> Allocator for &RefCell<A> {
    ) -> Option<NonNull<u8>> {
       return self.borrow_mut().reallocate(pointer, oldSize, layout);
    }
+
+   fn usable_size(&self, layout: Layout) -> usize {
+      return self.borrow_mut().usable_size(layout);
+   }
+
+   unsafe fn reallocate_in_place(&self, pointer: *mut u8, oldSize: usize, layout: Layout) -> bool {
+      return self.borrow_mut().reallocate_in_place(pointer, oldSize, layout);
+   }
 }
 
 unsafe impl Allocator for GlobalAllocator {
+   #[cfg_attr(feature = "alloc-tracing", link_section = "alloc_text")]
    fn allocate(&self, layout: Layout) -> Option<NonNull<u8>> {
-      return Some(NonNull::new(__rust_allocate(layout.size, layout.align)).unwrap());
+      return NonNull::new(__rust_allocate(layout.size, layout.align));
    }
 
    unsafe fn deallocate(&self, pointer: *mut u8, layout: Layout) {
       __rust_deallocate(pointer, layout.size, layout.align);
    }
 
+   #[cfg_attr(feature = "alloc-tracing", link_section = "alloc_text")]
    unsafe fn reallocate(
       &self,
       pointer: *mut u8,
       oldSize: usize,
       layout: Layout,
    ) -> Option<NonNull<u8>> {
-      return Some(
-         NonNull::new(__rust_reallocate(
-            pointer,
-            oldSize,
-            layout.size,
-            layout.align,
-         ))
-         .unwrap(),
-      );
+      return NonNull::new(__rust_reallocate(
+         pointer,
+         oldSize,
+         layout.size,
+         layout.align,
+      ));
+   }
+
+   fn usable_size(&self, layout: Layout) -> usize {
+      return __rust_usable_size(layout.size, layout.align);
+   }
+
+   unsafe fn reallocate_in_place(&self, pointer: *mut u8, oldSize: usize, layout: Layout) -> bool {
+      return __rust_reallocate_inplace(pointer, oldSize, layout.size, layout.align) == layout.size;
    }
 }
 
 unsafe impl GlobalAlloc for GlobalAllocator {
+   #[cfg_attr(feature = "alloc-tracing", link_section = "alloc_text")]
    unsafe fn alloc(&self, layout: StdLayout) -> *mut u8 {
       let layout = Layout::from(layout);
 
@@ -261,6 +472,11 @@ unsafe impl GlobalAlloc for GlobalAllocator {
       let layout = Layout::from(layout);
       self.deallocate(ptr, layout);
    }
+
+   #[cfg_attr(feature = "alloc-tracing", link_section = "alloc_text")]
+   unsafe fn realloc(&self, ptr: *mut u8, layout: StdLayout, new_size: usize) -> *mut u8 {
+      return __rust_reallocate(ptr, layout.size(), new_size, layout.align());
+   }
 }
 
 // MODULES //
@@ -288,6 +504,35 @@ pub mod layout;
 /// and [`FIT_ALLOCATOR`][crate::alloc::paging::FIT_ALLOCATOR].
 pub mod paging;
 
+/// Opt-in checking of [`GlobalAllocator`] allocations for overruns and use after free, behind the
+/// `redzones` feature.
+///
+/// Every allocation gets a redzone on each side, freed memory is filled with poison and held in
+/// a quarantine for a while before it goes back to the heap, and both are checked when the
+/// allocation is freed or leaves quarantine, and at [`check`][crate::alloc::redzone::check]
+/// points. Corruption is reported with the sequence number, address, size and alignment of the
+/// allocation it hit.
+#[cfg(feature = "redzones")]
+pub mod redzone;
+
+/// Slab caches for small objects, carved out of pages taken from the buddy heap.
+///
+/// [`SLAB`][crate::alloc::slab::SLAB] serves a fixed set of size classes, so a 24-byte object
+/// costs 24 bytes instead of the 32 the buddy heap would round it up to;
+/// [`ObjectCache`][crate::alloc::slab::ObjectCache] gives a single kernel object type a cache of
+/// its own.
+pub mod slab;
+
+/// Records every allocation made through [`GlobalAllocator`] and
+/// [`LockedHeap`][crate::alloc::heap::LockedHeap] with its size, alignment, callers and time
+/// stamp, for finding out who holds the heap and what leaked.
+///
+/// [`dump_live_allocations`][crate::alloc::trace::dump_live_allocations] reports over serial, and
+/// [`checkpoint`][crate::alloc::trace::checkpoint] and [`diff`][crate::alloc::trace::diff]
+/// compare the heap before and after a test.
+#[cfg(feature = "alloc-tracing")]
+pub mod trace;
+
 // EXPORTS //
 
 pub use self::layout::Layout;
@@ -295,13 +540,13 @@ pub use self::layout::Layout;
 // IMPORTS //
 
 use {
-   self::heap::HEAP,
+   self::heap::{block_size, HEAP},
    std_alloc::alloc::{GlobalAlloc, Layout as StdLayout},
    core::{
       cell::RefCell,
       cmp::min,
       fmt::{Display, Formatter},
-      mem::size_of,
+      mem::{align_of, size_of},
       ptr::{self, NonNull},
    },
    spin::{Mutex, MutexGuard},
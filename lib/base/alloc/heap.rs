@@ -3,13 +3,115 @@ pub static HEAP: Mutex<Option<Heap<32>>> = Mutex::new(None);
 pub const HEAP_START: usize = 0x4444_4444_0000;
 pub const HEAP_SIZE: usize = 8 * 1024 * 1024;
 
+/// Size of the virtual window reserved for the heap. Only [`HEAP_SIZE`] bytes of it are mapped up
+/// front; the rest is mapped on demand.
+pub const HEAP_RESERVE: usize = 1024 * 1024 * 1024;
+
+/// Smallest amount the heap grows by at a time.
+pub const HEAP_GROWTH: usize = 2 * 1024 * 1024;
+
+/// Maps more memory onto the end of the heap.
+///
+/// Given the current end of the heap and the minimum number of bytes needed, returns the new end,
+/// or `None` if nothing more could be mapped. It is called with the heap locked, so it must not
+/// allocate.
+pub type HeapExtender = fn(end: usize, size: usize) -> Option<usize>;
+
+/// Smallest block the heap hands out. A free block holds the links of its doubly linked free
+/// list.
+pub const MIN_BLOCK: usize = 2 * size_of::<usize>();
+
+/// Size class of [`MIN_BLOCK`].
+const MIN_CLASS: usize = MIN_BLOCK.trailing_zeros() as usize;
+
+/// Smallest size [`Heap::allocated`] counts an allocation as, the smallest block from before free
+/// blocks needed room for two links.
+const MIN_ACCOUNTED: usize = size_of::<usize>();
+
+/// Number of separately added memory ranges a [`Heap`] can track.
+pub const MAX_HEAP_REGIONS: usize = 32;
+
+/// Size of the block the buddy heap hands out for `layout`: the size rounded up to a power of two,
+/// and at least the alignment and [`MIN_BLOCK`].
+#[inline]
+pub fn block_size(layout: Layout) -> usize {
+   return max(
+      layout.size.nextPowerOf2(),
+      max(layout.align, MIN_BLOCK),
+   );
+}
+
+/// Bytes an allocation for `layout` adds to [`Heap::allocated`]: [`block_size`], but with a
+/// minimum of one `usize` rather than [`MIN_BLOCK`], so the accounting does not depend on how
+/// large a free block's links are.
+#[inline]
+fn accounted_size(layout: Layout) -> usize {
+   return max(
+      layout.size.nextPowerOf2(),
+      max(layout.align, MIN_ACCOUNTED),
+   );
+}
+
+/// A range added with [`Heap::add_to_heap`], with a free bitmap per size class carved off its
+/// front. Bit `(block - start) >> class` of bitmap `class` is set while that block is on the free
+/// list for `class`.
+#[derive(Copy, Clone)]
+struct HeapRegion<const ORDER: usize> {
+   start: usize,
+   end: usize,
+   bitmap: *mut u64,
+   offsets: [usize; ORDER],
+}
+
+impl<const ORDER: usize> HeapRegion<ORDER> {
+   const fn empty() -> Self {
+      return HeapRegion{
+         start: 0,
+         end: 0,
+         bitmap: ptr::null_mut(),
+         offsets: [0; ORDER],
+      };
+   }
+
+   /// Number of bitmap words a region of `length` bytes needs.
+   fn bitmap_words(length: usize) -> usize {
+      return (MIN_CLASS..ORDER).map(|class| (length >> class) / 64 + 1).sum();
+   }
+
+   fn holds(&self, block: usize, class: usize) -> bool {
+      return self.start <= block && block + (1 << class) <= self.end;
+   }
+
+   unsafe fn bit(&self, block: usize, class: usize) -> (*mut u64, u64) {
+      let index = (block - self.start) >> class;
+      return (self.bitmap.add(self.offsets[class] + index / 64), 1 << (index % 64));
+   }
+}
+
 pub struct Heap<const ORDER: usize> {
+   /// Bytes handed out, each allocation rounded up to a power of two of at least its alignment
+   /// and one `usize`.
    pub allocated: usize,
    pub freeList: [LinkedList; ORDER],
+
+   /// Bytes added to the heap, the free bitmaps at the front of each range included.
    pub total: usize,
+
+   /// Bytes requested by callers.
    pub user: usize,
+
+   /// End of the most recently added region, where the heap grows from.
+   pub end: usize,
+
+   /// Called when an allocation cannot be satisfied.
+   pub extender: Option<HeapExtender>,
+
+   regions: [HeapRegion<ORDER>; MAX_HEAP_REGIONS],
+   regionCount: usize,
 }
 
+unsafe impl<const ORDER: usize> Send for Heap<ORDER> {}
+
 impl<const ORDER: usize> Heap<ORDER> {
    /// Create an empty heap.
    pub const fn new() -> Self {
@@ -18,116 +120,301 @@ impl<const ORDER: usize> Heap<ORDER> {
          freeList: [LinkedList::new(); ORDER],
          total: 0,
          user: 0,
+         end: 0,
+         extender: None,
+         regions: [HeapRegion::empty(); MAX_HEAP_REGIONS],
+         regionCount: 0,
       };
    }
 
+   /// Lets the heap grow past its current end through `extender`.
+   pub fn set_extender(&mut self, extender: HeapExtender) {
+      self.extender = Some(extender);
+   }
+
+   /// Asks the extender for enough memory to satisfy `layout` and adds it to the heap.
+   ///
+   /// The heap asks to double in size first, so that a growing heap only needs a handful of
+   /// regions, and settles for just enough if that is refused.
+   ///
+   /// Returns `false` if there is no extender or it could not map any more memory.
+   pub unsafe fn extend(&mut self, layout: Layout) -> bool {
+      let extender = match self.extender {
+         Some(extender) => extender,
+         None => return false,
+      };
+
+      if self.regionCount == MAX_HEAP_REGIONS {
+         return false;
+      }
+
+      let size = block_size(layout);
+
+      // Twice the block size guarantees an aligned block of the right size in the new range,
+      // once the region's bitmaps are carved off.
+      let needed = size * 2 + HeapRegion::<ORDER>::bitmap_words(size * 4) * size_of::<u64>() + MIN_BLOCK;
+
+      for request in [max(needed, self.total), needed] {
+         if let Some(end) = extender(self.end, request) {
+            if end > self.end {
+               let total = self.total;
+               self.add_to_heap(self.end, end);
+               return self.total > total;
+            }
+         }
+      }
+
+      return false;
+   }
+
+   /// Allocates a block for `layout`, growing the heap once if it is exhausted.
+   pub unsafe fn allocate_or_extend(&mut self, layout: Layout) -> Result<NonNull<u8>, AllocationError> {
+      return match self.allocate(layout) {
+         Ok(pointer) => Ok(pointer),
+         Err(_) if self.extend(layout) => self.allocate(layout),
+         Err(error) => Err(error),
+      };
+   }
+
+   /// Adds the memory in `start..end` to the heap.
+   ///
+   /// The front of the range holds the region's free bitmaps, about one sixty-fourth of its size,
+   /// which count towards [`total`](Heap::total) like the rest. Blocks never merge across ranges. If [`MAX_HEAP_REGIONS`] ranges have already been added,
+   /// the range is ignored.
    pub unsafe fn add_to_heap(&mut self, mut start: usize, mut end: usize) {
       // Avoid unaligned access.
-      start = (start + size_of::<usize>() - 1) & (!size_of::<usize>() + 1);
-      end &= !size_of::<usize>() + 1;
+      start = (start + MIN_BLOCK - 1) & !(MIN_BLOCK - 1);
+      end &= !(MIN_BLOCK - 1);
       assert!(start <= end);
 
-      let mut total = 0;
-      let mut current_start = start;
+      if self.regionCount == MAX_HEAP_REGIONS {
+         return;
+      }
+
+      // Carve the free bitmaps off the front of the range.
+      let mut offsets = [0; ORDER];
+      let mut words = 0;
+      for class in MIN_CLASS..ORDER {
+         offsets[class] = words;
+         words += ((end - start) >> class) / 64 + 1;
+      }
 
-      while current_start + size_of::<usize>() <= end {
+      let bitmap = start as *mut u64;
+      let base = (start + words * size_of::<u64>() + MIN_BLOCK - 1) & !(MIN_BLOCK - 1);
+      if base >= end {
+         return;
+      }
+
+      bitmap.write_bytes(0, words);
+
+      let region = self.regionCount;
+      self.regions[region] = HeapRegion{ start: base, end, bitmap, offsets };
+      self.regionCount += 1;
+
+      let mut current_start = base;
+
+      while current_start + MIN_BLOCK <= end {
          let lowbit = current_start & (!current_start + 1);
          let size = min(lowbit, previous_po2(end - current_start));
-         total += size;
 
-         self.freeList[size.trailing_zeros() as usize].push(current_start as *mut usize);
+         self.push_free(region, current_start, size.trailing_zeros() as usize);
          current_start += size;
       }
 
-      self.total += total;
+      self.total += end - start;
+      self.end = max(self.end, end);
    }
 
    /// Allocate a block of memory large enough to contain `size` bytes,
-   /// and aligned on `align`.  This will return NULL if the `align` is
-   /// greater than `MIN_HEAP_ALIGN`, if `align` is not a power of 2, or
-   /// if we can't find enough memory.
+   /// and aligned on `align`.  Blocks are aligned to their own size, so
+   /// the block is at least `align` bytes.  This will return an error if
+   /// we can't find enough memory.
    ///
    /// All allocated memory must be passed to `deallocate` with the same
    /// `size` and `align` parameter, or else horrible things will happen.
    pub unsafe fn allocate(&mut self, layout: Layout) -> Result<NonNull<u8>, AllocationError> {
-      let size = max(
-         layout.size.nextPowerOf2(),
-         max(layout.align, size_of::<usize>()),
-      );
+      let size = block_size(layout);
 
       let class = size.trailing_zeros() as usize;
       for i in class..self.freeList.len() {
          // Find the first non-empty size class
-         if !self.freeList[i].empty() {
-            // Split the buffers.
+         if let Some(block) = self.pop_free(i) {
+            let region = self.region_of(block);
+
+            // Split the block, keeping the lower half each time.
             for j in (class + 1..i + 1).rev() {
-               if let Some(block) = self.freeList[j].pop() {
-                  unsafe {
-                     self.freeList[j - 1].push((block as usize + (1 << (j - 1))) as *mut usize);
-                     self.freeList[j - 1].push(block);
-                  }
-               } else {
-                  return Err(AllocationError);
-               }
+               self.push_free(region, block + (1 << (j - 1)), j - 1);
             }
 
-            let result = NonNull::new(
-               self.freeList[class].pop()
-                  .expect("current block should have free space") as *mut u8
-            );
-
-            return if let Some(result) = result {
-               self.user += layout.size;
-               self.allocated += size;
-               Ok(result)
-            } else {
-               Err(AllocationError)
-            };
+            self.user += layout.size;
+            self.allocated += accounted_size(layout);
+            return Ok(NonNull::new_unchecked(block as *mut u8));
          }
       }
 
       return Err(AllocationError);
    }
 
+   /// Returns a block to the heap, merging it with its buddy for as long as the buddy is free.
+   ///
+   /// Each merge step is a bitmap test and an unlink from a doubly linked list, so a free costs
+   /// O(1) per size class instead of a scan of the free list.
    pub unsafe fn deallocate(&mut self, ptr: NonNull<u8>, layout: Layout) {
-      let size = max(
-         layout.size.nextPowerOf2(),
-         max(layout.align, size_of::<usize>()),
-      );
+      let size = block_size(layout);
 
       let class = size.trailing_zeros() as usize;
-
-      // Place the block back into the free list.
-      self.freeList[class].push(ptr.as_ptr() as *mut usize);
+      let region = self.region_of(ptr.as_ptr() as usize);
 
       // Merge buddy free lists.
       let mut currentPointer = ptr.as_ptr() as usize;
       let mut currentClass = class;
 
-      while currentClass < self.freeList.len() {
+      while currentClass + 1 < self.freeList.len() {
          let buddy = currentPointer ^ (1 << currentClass);
-         let mut flag = false;
 
-         for block in self.freeList[currentClass].iterator_mut() {
-            if block.value() as usize == buddy {
-               block.pop();
-               flag = true;
-               break;
+         if !self.regions[region].holds(buddy, currentClass) || !self.is_free(region, buddy, currentClass) {
+            break;
+         }
+
+         self.remove_free(region, buddy, currentClass);
+         currentPointer = min(currentPointer, buddy);
+         currentClass += 1;
+      }
+
+      // Place the block back into the free list.
+      self.push_free(region, currentPointer, currentClass);
+
+      self.user -= layout.size;
+      self.allocated -= accounted_size(layout);
+   }
+
+   /// Resizes the allocation at `ptr` from `old.size` to `size` bytes without moving it.
+   ///
+   /// A size that rounds to the same block always fits. Shrinking hands the upper halves back to
+   /// the free lists; growing takes them, which only works if the block is the lower buddy at every
+   /// level up to the new size and each of those upper buddies is free. Returns `false`, leaving
+   /// the allocation alone, if the block cannot grow in place or `size` is too large for any
+   /// layout.
+   pub unsafe fn reallocate_in_place(&mut self, ptr: NonNull<u8>, old: Layout, size: usize) -> bool {
+      let address = ptr.as_ptr() as usize;
+      // The size comes straight from callers of `__rust_reallocate_inplace`.
+      let new = match Layout::from_size_align(size, old.align) {
+         Ok(new) => new,
+         Err(_) => return false,
+      };
+      let oldBlock = block_size(old);
+      let newBlock = block_size(new);
+
+      let oldClass = oldBlock.trailing_zeros() as usize;
+      let newClass = newBlock.trailing_zeros() as usize;
+
+      if newClass > oldClass {
+         if newClass >= self.freeList.len() || address & (newBlock - 1) != 0 {
+            return false;
+         }
+
+         let region = self.region_of(address);
+         for class in oldClass..newClass {
+            let buddy = address + (1 << class);
+            if !self.regions[region].holds(buddy, class) || !self.is_free(region, buddy, class) {
+               return false;
             }
          }
 
-         if flag {
-            self.freeList[currentClass].pop();
-            currentPointer = min(currentPointer, buddy);
-            currentClass += 1;
-            self.freeList[currentClass].push(currentPointer as *mut usize);
-         } else {
-            break;
+         for class in oldClass..newClass {
+            self.remove_free(region, address + (1 << class), class);
+         }
+      } else if newClass < oldClass {
+         // The upper halves' buddies are the part we keep, so none of them can merge.
+         let region = self.region_of(address);
+         for class in newClass..oldClass {
+            self.push_free(region, address + (1 << class), class);
          }
       }
 
-      self.user -= layout.size;
-      self.allocated -= size;
+      self.allocated = self.allocated - accounted_size(old) + accounted_size(new);
+      self.user = self.user - old.size + size;
+      return true;
+   }
+
+   /// The index of the region holding `block`.
+   fn region_of(&self, block: usize) -> usize {
+      return self.regions[..self.regionCount]
+         .iter()
+         .position(|region| region.start <= block && block < region.end)
+         .expect("block does not belong to this heap");
+   }
+
+   unsafe fn is_free(&self, region: usize, block: usize, class: usize) -> bool {
+      let (word, mask) = self.regions[region].bit(block, class);
+      return *word & mask != 0;
+   }
+
+   /// Pushes `block` onto the free list for `class`. A free block holds the address of the next
+   /// free block, as [`LinkedList`] expects, followed by the address of the previous one.
+   unsafe fn push_free(&mut self, region: usize, block: usize, class: usize) {
+      let node = block as *mut usize;
+      let head = self.freeList[class].head;
+
+      *node = head as usize;
+      *node.add(1) = 0;
+
+      if !head.is_null() {
+         *head.add(1) = block;
+      }
+
+      self.freeList[class].head = node;
+
+      let (word, mask) = self.regions[region].bit(block, class);
+      *word |= mask;
+   }
+
+   unsafe fn pop_free(&mut self, class: usize) -> Option<usize> {
+      let block = self.freeList[class].head as usize;
+      if block == 0 {
+         return None;
+      }
+
+      self.remove_free(self.region_of(block), block, class);
+      return Some(block);
+   }
+
+   /// Takes `block` off the free list for `class`, wherever it is in the list.
+   unsafe fn remove_free(&mut self, region: usize, block: usize, class: usize) {
+      let node = block as *mut usize;
+      let next = *node as *mut usize;
+      let previous = *node.add(1) as *mut usize;
+
+      if previous.is_null() {
+         self.freeList[class].head = next;
+      } else {
+         *previous = next as usize;
+      }
+
+      if !next.is_null() {
+         *next.add(1) = previous as usize;
+      }
+
+      let (word, mask) = self.regions[region].bit(block, class);
+      *word &= !mask;
+   }
+}
+
+impl<const ORDER: usize> Heap<ORDER> {
+   /// Describes how the free memory is split up.
+   pub fn fragmentation(&self) -> Fragmentation {
+      let mut report = Fragmentation::default();
+
+      for (class, list) in self.freeList.iter().enumerate() {
+         let blocks = list.iterator().count();
+         if blocks > 0 {
+            report.free += blocks << class;
+            report.largest_free = 1 << class;
+            report.free_blocks += blocks;
+         }
+      }
+
+      return report;
    }
 }
 
@@ -166,29 +453,111 @@ impl<const ORDER: usize> LockedHeap<ORDER> {
    pub const fn new() -> Self {
       return LockedHeap(Mutex::new(Heap::<ORDER>::new()));
    }
+
+   /// Locks the heap.
+   pub fn lock(&self) -> MutexGuard<Heap<ORDER>> {
+      return self.0.lock();
+   }
+}
+
+unsafe impl<const ORDER: usize> Allocator for LockedHeap<ORDER> {
+   #[cfg_attr(feature = "alloc-tracing", link_section = "alloc_text")]
+   fn allocate(&self, layout: Layout) -> Option<NonNull<u8>> {
+      let allocation = unsafe{ self.lock().allocate_or_extend(layout).ok() };
+
+      #[cfg(feature = "alloc-tracing")]
+      if let Some(allocation) = allocation {
+         trace::record_allocation(allocation.as_ptr(), layout);
+      }
+
+      return allocation;
+   }
+
+   unsafe fn deallocate(&self, pointer: *mut u8, layout: Layout) {
+      if let Some(pointer) = NonNull::new(pointer) {
+         // Before the block is back in the heap, where another CPU could take it and record it.
+         #[cfg(feature = "alloc-tracing")]
+         trace::record_free(pointer.as_ptr());
+
+         self.lock().deallocate(pointer, layout);
+      }
+   }
+
+   #[cfg_attr(feature = "alloc-tracing", link_section = "alloc_text")]
+   unsafe fn reallocate(
+      &self,
+      pointer: *mut u8,
+      oldSize: usize,
+      layout: Layout,
+   ) -> Option<NonNull<u8>> {
+      if self.reallocate_in_place(pointer, oldSize, layout) {
+         return NonNull::new(pointer);
+      }
+
+      let newPointer = self.allocate(layout)?;
+      ptr::copy_nonoverlapping(pointer, newPointer.as_ptr(), min(oldSize, layout.size));
+      self.deallocate(pointer, Layout::from_size_align_unchecked(oldSize, layout.align));
+
+      return Some(newPointer);
+   }
+
+   fn usable_size(&self, layout: Layout) -> usize {
+      return block_size(layout);
+   }
+
+   unsafe fn reallocate_in_place(&self, pointer: *mut u8, oldSize: usize, layout: Layout) -> bool {
+      return match NonNull::new(pointer) {
+         Some(pointer) => {
+            let old = Layout::from_size_align_unchecked(oldSize, layout.align);
+            let resized = self.lock().reallocate_in_place(pointer, old, layout.size);
+
+            #[cfg(feature = "alloc-tracing")]
+            if resized {
+               trace::record_resize(pointer.as_ptr(), layout.size);
+            }
+
+            resized
+         }
+         None => false,
+      };
+   }
 }
 
 unsafe impl<const ORDER: usize> GlobalAlloc for LockedHeap<ORDER> {
+   #[cfg_attr(feature = "alloc-tracing", link_section = "alloc_text")]
    unsafe fn alloc(&self, layout: StdLayout) -> *mut u8 {
       let layout = Layout::from(layout);
 
-      self.0.lock()
-         .allocate(layout)
+      let pointer = self.0.lock()
+         .allocate_or_extend(layout)
          .ok()
-         .map_or(0 as *mut u8, |allocation| allocation.as_ptr())
+         .map_or(0 as *mut u8, |allocation| allocation.as_ptr());
+
+      #[cfg(feature = "alloc-tracing")]
+      trace::record_allocation(pointer, layout);
+
+      pointer
    }
 
    unsafe fn dealloc(&self, ptr: *mut u8, layout: StdLayout) {
       let layout = Layout::from(layout);
+
+      // Before the block is back in the heap, where another CPU could take it and record it.
+      #[cfg(feature = "alloc-tracing")]
+      trace::record_free(ptr);
+
       self.0.lock().deallocate(NonNull::new_unchecked(ptr), layout);
    }
 }
 
 // IMPORTS //
 
+#[cfg(feature = "alloc-tracing")]
+use crate::alloc::trace;
+
 use {
    crate::{
-      alloc::{AllocationError, Layout},
+      alloc::{AllocationError, Allocator, Fragmentation, Layout},
       array::linked_list::LinkedList,
       math::{previous_po2, PowersOf2},
    },
@@ -197,7 +566,7 @@ use {
       cmp::{min, max},
       fmt::{Debug, Formatter, Result as FmtResult},
       mem::size_of,
-      ptr::NonNull,
+      ptr::{self, NonNull},
    },
-   spin::Mutex,
+   spin::{Mutex, MutexGuard},
 };
@@ -17,23 +17,50 @@ impl Layout {
       };
    }
 
-   /// Creates a new instance of a Layout with the given size.
+   /// Creates a new instance of a Layout with the given size and the default alignment of 4.
    #[inline]
    pub fn from_size(size: usize) -> Self {
       return Layout { size, align: 4 };
    }
 
+   /// Creates a new instance of a Layout with the given size and alignment.
+   ///
+   /// Fails if `align` is not a power of two, or if `size` rounded up to `align` would overflow
+   /// an `isize`.
    #[inline]
-   pub fn from_size_align(size: usize, align: usize) -> Self {
+   pub fn from_size_align(size: usize, align: usize) -> Result<Self, LayoutError> {
+      if !align.is_power_of_two() || size > isize::MAX as usize - (align - 1) {
+         return Err(LayoutError);
+      }
+
+      return Ok(Layout{ size, align });
+   }
+
+   /// Creates a new instance of a Layout with the given size and alignment, without checking them.
+   ///
+   /// ## Safety
+   ///
+   /// The pair must satisfy the conditions checked by [`from_size_align`](Layout::from_size_align).
+   #[inline]
+   pub const unsafe fn from_size_align_unchecked(size: usize, align: usize) -> Self {
       return Layout{ size, align };
    }
 
    /// Create a new instance of Layout from the given array-length and type parameter.
+   ///
+   /// Fails if the size of the array overflows.
+   #[inline]
+   pub fn from_type_array<T>(length: usize) -> Result<Self, LayoutError> {
+      let size = size_of::<T>().checked_mul(length).ok_or(LayoutError)?;
+      return Layout::from_size_align(size, align_of::<T>());
+   }
+
+   /// Creates a new instance of a Layout describing the memory behind `value`.
    #[inline]
-   pub fn from_type_array<T>(length: usize) -> Self {
+   pub fn for_value<T: ?Sized>(value: &T) -> Self {
       return Layout {
-         size: size_of::<T>() * length,
-         align: align_of::<T>(),
+         size: size_of_val(value),
+         align: align_of_val(value),
       };
    }
 
@@ -53,7 +80,10 @@ impl Into<StdLayout> for Layout {
 
 impl From<StdLayout> for Layout {
    fn from(value: StdLayout) -> Self {
-      return Layout::from_size(value.size());
+      return Layout {
+         size: value.size(),
+         align: value.align(),
+      };
    }
 }
 
@@ -72,6 +102,6 @@ use {
    std_alloc::alloc::Layout as StdLayout,
    core::{
       fmt::{self, Display},
-      mem::{align_of, size_of},
+      mem::{align_of, align_of_val, size_of, size_of_val},
    },
 };
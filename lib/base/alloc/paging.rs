@@ -77,7 +77,304 @@ impl Table {
 
 // BUDDY ALLOCATOR //
 
+/// A buddy allocator with its own memory, separate from the kernel [`HEAP`](crate::alloc::heap::HEAP).
+///
+/// Give it memory with `BUDDY_ALLOCATOR.lock().add_to_heap(start, end)` before allocating.
+pub static BUDDY_ALLOCATOR: LockedHeap<32> = LockedHeap::new();
+
 // BEST-FIT ALLOCATOR //
-// TODO: implement best-fit allocator.
+
+/// A best-fit allocator, an alternative to [`BUDDY_ALLOCATOR`] for workloads the buddy system
+/// fragments badly.
+///
+/// Give it memory with `FIT_ALLOCATOR.lock().add_to_heap(start, end)` before allocating.
+pub static FIT_ALLOCATOR: LockedFitAllocator = LockedFitAllocator::new();
+
+/// Granularity of the best-fit allocator. Every block is a multiple of this and starts on it.
+pub const FIT_GRANULE: usize = 2 * size_of::<usize>();
+
+/// A free block, stored in the block itself.
+struct FreeBlock {
+   size: usize,
+   next: *mut FreeBlock,
+}
+
+/// A best-fit allocator over an address-ordered free list.
+///
+/// An allocation takes the free block that leaves the least over once the request (and any
+/// alignment padding in front of it) is cut out, and the padding and the remainder go back on the
+/// list. Freed blocks are inserted in address order and merged with free neighbours on either
+/// side, so adjacent free space never stays split. Unlike the buddy [`Heap`](crate::alloc::heap::Heap), sizes are only
+/// rounded up to [`FIT_GRANULE`], not to a power of two.
+pub struct FitAllocator {
+   head: *mut FreeBlock,
+
+   /// Bytes handed out, including rounding.
+   pub allocated: usize,
+
+   /// Bytes managed by the allocator.
+   pub total: usize,
+
+   /// Bytes requested by callers.
+   pub user: usize,
+}
+
+unsafe impl Send for FitAllocator {}
+
+impl FitAllocator {
+   /// Create an empty allocator.
+   pub const fn new() -> Self {
+      return FitAllocator{
+         head: ptr::null_mut(),
+         allocated: 0,
+         total: 0,
+         user: 0,
+      };
+   }
+
+   /// Adds the memory in `start..end` to the allocator.
+   ///
+   /// ## Safety
+   ///
+   /// The range must be valid, writable and not used by anything else.
+   pub unsafe fn add_to_heap(&mut self, start: usize, end: usize) {
+      let start = (start + FIT_GRANULE - 1) & !(FIT_GRANULE - 1);
+      let end = end & !(FIT_GRANULE - 1);
+
+      if start >= end {
+         return;
+      }
+
+      self.total += end - start;
+      self.insert(start, end - start);
+   }
+
+   /// Allocates a block for `layout` from the best-fitting free block.
+   pub unsafe fn allocate(&mut self, layout: Layout) -> Result<NonNull<u8>, AllocationError> {
+      let size = FitAllocator::block_size(layout);
+      let align = max(layout.align, FIT_GRANULE);
+
+      // Find the block that leaves the smallest remainder.
+      let mut best: Option<(*mut FreeBlock, *mut FreeBlock, usize, usize)> = None;
+      let mut previous: *mut FreeBlock = ptr::null_mut();
+      let mut current = self.head;
+
+      while !current.is_null() {
+         let start = current as usize;
+         let blockSize = (*current).size;
+         let aligned = (start + align - 1) & !(align - 1);
+
+         if aligned + size <= start + blockSize {
+            let left = blockSize - size;
+            if best.map_or(true, |(_, _, _, bestLeft)| left < bestLeft) {
+               best = Some((previous, current, aligned, left));
+               if left == 0 {
+                  break;
+               }
+            }
+         }
+
+         previous = current;
+         current = (*current).next;
+      }
+
+      let (previous, block, aligned, _) = best.ok_or(AllocationError)?;
+      let start = block as usize;
+      let end = start + (*block).size;
+
+      // Take the block off the list, then give back whatever lies around the allocation.
+      self.unlink(previous, block);
+
+      if aligned > start {
+         self.insert(start, aligned - start);
+      }
+
+      if aligned + size < end {
+         self.insert(aligned + size, end - aligned - size);
+      }
+
+      self.allocated += size;
+      self.user += layout.size;
+
+      return Ok(NonNull::new_unchecked(aligned as *mut u8));
+   }
+
+   /// Returns a block to the allocator, merging it with free neighbours.
+   ///
+   /// ## Safety
+   ///
+   /// `pointer` must have come from [`allocate`](FitAllocator::allocate) with the same `layout`.
+   pub unsafe fn deallocate(&mut self, pointer: NonNull<u8>, layout: Layout) {
+      let size = FitAllocator::block_size(layout);
+      self.insert(pointer.as_ptr() as usize, size);
+
+      self.allocated -= size;
+      self.user -= layout.size;
+   }
+
+   /// Describes how the free memory is split up.
+   pub fn fragmentation(&self) -> Fragmentation {
+      let mut report = Fragmentation::default();
+      let mut current = self.head;
+
+      while !current.is_null() {
+         unsafe {
+            report.free += (*current).size;
+            report.largest_free = max(report.largest_free, (*current).size);
+            report.free_blocks += 1;
+            current = (*current).next;
+         }
+      }
+
+      return report;
+   }
+
+   fn block_size(layout: Layout) -> usize {
+      return (max(layout.size, FIT_GRANULE) + FIT_GRANULE - 1) & !(FIT_GRANULE - 1);
+   }
+
+   unsafe fn unlink(&mut self, previous: *mut FreeBlock, block: *mut FreeBlock) {
+      if previous.is_null() {
+         self.head = (*block).next;
+      } else {
+         (*previous).next = (*block).next;
+      }
+   }
+
+   /// Puts `start..start + size` on the free list in address order, merging it with the blocks
+   /// directly before and after it.
+   unsafe fn insert(&mut self, start: usize, size: usize) {
+      let mut previous: *mut FreeBlock = ptr::null_mut();
+      let mut next = self.head;
+
+      while !next.is_null() && (next as usize) < start {
+         previous = next;
+         next = (*next).next;
+      }
+
+      let mut block = start as *mut FreeBlock;
+      block.write(FreeBlock{ size, next });
+
+      if !next.is_null() && start + size == next as usize {
+         (*block).size += (*next).size;
+         (*block).next = (*next).next;
+      }
+
+      if previous.is_null() {
+         self.head = block;
+      } else if previous as usize + (*previous).size == start {
+         (*previous).size += (*block).size;
+         (*previous).next = (*block).next;
+         block = previous;
+      } else {
+         (*previous).next = block;
+      }
+
+      debug_assert!((*block).next.is_null() || block as usize + (*block).size <= (*block).next as usize);
+   }
+}
+
+impl Debug for FitAllocator {
+   fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+      f.debug_struct("FitAllocator")
+         .field("user", &self.user)
+         .field("allocated", &self.allocated)
+         .field("total", &self.total)
+         .field("fragmentation", &self.fragmentation())
+         .finish()
+   }
+}
+
+/// A locked version of [`FitAllocator`].
+pub struct LockedFitAllocator(Mutex<FitAllocator>);
+
+impl LockedFitAllocator {
+   /// Create an empty, locked allocator.
+   pub const fn new() -> Self {
+      return LockedFitAllocator(Mutex::new(FitAllocator::new()));
+   }
+
+   /// Locks the allocator.
+   pub fn lock(&self) -> MutexGuard<FitAllocator> {
+      return self.0.lock();
+   }
+}
+
+unsafe impl Allocator for LockedFitAllocator {
+   fn allocate(&self, layout: Layout) -> Option<NonNull<u8>> {
+      return unsafe{ self.lock().allocate(layout).ok() };
+   }
+
+   unsafe fn deallocate(&self, pointer: *mut u8, layout: Layout) {
+      if let Some(pointer) = NonNull::new(pointer) {
+         self.lock().deallocate(pointer, layout);
+      }
+   }
+
+   unsafe fn reallocate(
+      &self,
+      pointer: *mut u8,
+      oldSize: usize,
+      layout: Layout,
+   ) -> Option<NonNull<u8>> {
+      if self.reallocate_in_place(pointer, oldSize, layout) {
+         return NonNull::new(pointer);
+      }
+
+      let newPointer = self.allocate(layout)?;
+      ptr::copy_nonoverlapping(pointer, newPointer.as_ptr(), min(oldSize, layout.size));
+      self.deallocate(pointer, Layout::from_size_align_unchecked(oldSize, layout.align));
+
+      return Some(newPointer);
+   }
+
+   fn usable_size(&self, layout: Layout) -> usize {
+      return FitAllocator::block_size(layout);
+   }
+
+   unsafe fn reallocate_in_place(&self, _pointer: *mut u8, oldSize: usize, layout: Layout) -> bool {
+      let old = Layout::from_size_align_unchecked(oldSize, layout.align);
+
+      // Only a size that rounds to the same block fits without touching the free list.
+      if FitAllocator::block_size(old) != FitAllocator::block_size(layout) {
+         return false;
+      }
+
+      let mut allocator = self.lock();
+      allocator.user = allocator.user - oldSize + layout.size;
+      return true;
+   }
+}
+
+unsafe impl GlobalAlloc for LockedFitAllocator {
+   unsafe fn alloc(&self, layout: StdLayout) -> *mut u8 {
+      let layout = Layout::from(layout);
+
+      self.lock()
+         .allocate(layout)
+         .ok()
+         .map_or(0 as *mut u8, |allocation| allocation.as_ptr())
+   }
+
+   unsafe fn dealloc(&self, ptr: *mut u8, layout: StdLayout) {
+      let layout = Layout::from(layout);
+      self.lock().deallocate(NonNull::new_unchecked(ptr), layout);
+   }
+}
 
 // IMPORTS //
+
+use {
+   crate::alloc::{
+      heap::LockedHeap,
+      AllocationError, Allocator, Fragmentation, Layout,
+   },
+   std_alloc::alloc::{GlobalAlloc, Layout as StdLayout},
+   core::{
+      cmp::{max, min},
+      fmt::{Debug, Formatter, Result as FmtResult},
+      mem::size_of,
+      ptr::{self, NonNull},
+   },
+   spin::{Mutex, MutexGuard},
+};
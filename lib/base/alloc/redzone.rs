@@ -0,0 +1,420 @@
+/// Bytes of redzone on each side of an allocation.
+pub const REDZONE_SIZE: usize = 16;
+
+/// Pattern the redzones are filled with.
+pub const REDZONE_BYTE: u8 = 0xFB;
+
+/// Pattern freed memory is filled with while it sits in quarantine.
+pub const POISON_BYTE: u8 = 0x6B;
+
+/// Most blocks held in quarantine before the oldest goes back to the heap.
+pub const QUARANTINE_LENGTH: usize = 256;
+
+/// Most bytes held in quarantine before the oldest block goes back to the heap.
+pub const QUARANTINE_BYTES: usize = 1024 * 1024;
+
+const LIVE_MAGIC: usize = 0x5AFE_B10C_5AFE_B10C;
+const FREED_MAGIC: usize = 0xDEAD_B10C_DEAD_B10C;
+
+/// Smallest alignment of the blocks behind guarded allocations, which keeps the header aligned.
+const MIN_ALIGN: usize = 16;
+
+static REDZONES: Mutex<Redzones> = Mutex::new(Redzones::new());
+
+/// What a check found wrong with an allocation.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum CorruptionKind {
+   /// The header in front of the allocation was overwritten, or the pointer never came from the
+   /// heap.
+   Header,
+
+   /// The allocation was freed twice.
+   DoubleFree,
+
+   /// The allocation was freed with a different size or alignment than it was made with.
+   LayoutMismatch,
+
+   /// Something wrote to the redzone below the allocation.
+   Underflow,
+
+   /// Something wrote to the redzone above the allocation.
+   Overflow,
+
+   /// Something wrote to the allocation after it was freed.
+   UseAfterFree,
+}
+
+impl Display for CorruptionKind {
+   fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+      return f.write_str(match self {
+         CorruptionKind::Header => "corrupt allocation header",
+         CorruptionKind::DoubleFree => "double free",
+         CorruptionKind::LayoutMismatch => "free with the wrong layout",
+         CorruptionKind::Underflow => "heap buffer underflow",
+         CorruptionKind::Overflow => "heap buffer overflow",
+         CorruptionKind::UseAfterFree => "write after free",
+      });
+   }
+}
+
+/// A corrupted allocation.
+#[derive(Copy, Clone, Debug)]
+pub struct Corruption {
+   /// What went wrong.
+   pub kind: CorruptionKind,
+
+   /// Address of the allocation as handed out.
+   pub address: usize,
+
+   /// Size of the allocation as made.
+   pub size: usize,
+
+   /// Alignment of the allocation as made.
+   pub align: usize,
+
+   /// Position of the allocation among all guarded allocations, starting at 1, or 0 if the
+   /// header was too damaged to tell; the other fields but `kind` and `address` are then 0 too.
+   pub sequence: u64,
+
+   /// Offset of the first bad byte from the start of the allocation.
+   pub offset: isize,
+}
+
+impl Display for Corruption {
+   fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+      if self.sequence == 0 {
+         return write!(f, "{} at {:#x}", self.kind, self.address);
+      }
+
+      write!(f, "{} in allocation #{} at {:#x} ({} bytes, align {})", self.kind, self.sequence, self.address, self.size, self.align)?;
+
+      return match self.kind {
+         CorruptionKind::Underflow | CorruptionKind::Overflow | CorruptionKind::UseAfterFree => {
+            write!(f, ", first bad byte at offset {}", self.offset)
+         }
+         _ => Ok(()),
+      };
+   }
+}
+
+/// Sits at the start of every guarded block, in front of the lower redzone.
+///
+/// Live blocks are linked into one list and quarantined blocks into another, so check points can
+/// find every block without a side table.
+#[repr(C)]
+struct Header {
+   next: *mut Header,
+   previous: *mut Header,
+   size: usize,
+   align: usize,
+   sequence: u64,
+   magic: usize,
+}
+
+impl Header {
+   fn front(align: usize) -> usize {
+      let align = max(align, MIN_ALIGN);
+      return (size_of::<Header>() + REDZONE_SIZE + align - 1) & !(align - 1);
+   }
+
+   fn user(&self) -> *mut u8 {
+      return unsafe{ (self as *const Header as *mut u8).add(Header::front(self.align)) };
+   }
+
+   fn outer_layout(&self) -> Layout {
+      return guarded_layout(unsafe{ Layout::from_size_align_unchecked(self.size, self.align) })
+         .expect("layout was valid when allocated");
+   }
+
+   fn corruption(&self, kind: CorruptionKind, offset: isize) -> Corruption {
+      return Corruption{
+         kind,
+         address: self.user() as usize,
+         size: self.size,
+         align: self.align,
+         sequence: self.sequence,
+         offset,
+      };
+   }
+
+   /// Checks both redzones, and the poison if the block is in quarantine.
+   unsafe fn check(&self) -> Result<(), Corruption> {
+      let user = self.user();
+      let base = self as *const Header as *const u8;
+
+      let front = base.add(size_of::<Header>());
+      if let Some(bad) = find_not(front, user as usize - front as usize, REDZONE_BYTE) {
+         return Err(self.corruption(CorruptionKind::Underflow, (front as usize + bad) as isize - user as isize));
+      }
+
+      if let Some(bad) = find_not(user.add(self.size), REDZONE_SIZE, REDZONE_BYTE) {
+         return Err(self.corruption(CorruptionKind::Overflow, (self.size + bad) as isize));
+      }
+
+      if self.magic == FREED_MAGIC {
+         if let Some(bad) = find_not(user, self.size, POISON_BYTE) {
+            return Err(self.corruption(CorruptionKind::UseAfterFree, bad as isize));
+         }
+      }
+
+      return Ok(());
+   }
+}
+
+/// A list of headers, linked through `next` and `previous`.
+struct HeaderList {
+   head: *mut Header,
+   tail: *mut Header,
+   length: usize,
+   bytes: usize,
+}
+
+impl HeaderList {
+   const fn new() -> Self {
+      return HeaderList{
+         head: ptr::null_mut(),
+         tail: ptr::null_mut(),
+         length: 0,
+         bytes: 0,
+      };
+   }
+
+   unsafe fn push_back(&mut self, header: *mut Header) {
+      (*header).next = ptr::null_mut();
+      (*header).previous = self.tail;
+
+      if self.tail.is_null() {
+         self.head = header;
+      } else {
+         (*self.tail).next = header;
+      }
+
+      self.tail = header;
+      self.length += 1;
+      self.bytes += (*header).size;
+   }
+
+   unsafe fn remove(&mut self, header: *mut Header) {
+      let (next, previous) = ((*header).next, (*header).previous);
+
+      if previous.is_null() {
+         self.head = next;
+      } else {
+         (*previous).next = next;
+      }
+
+      if next.is_null() {
+         self.tail = previous;
+      } else {
+         (*next).previous = previous;
+      }
+
+      self.length -= 1;
+      self.bytes -= (*header).size;
+   }
+
+   unsafe fn check(&self, magic: usize) -> Result<(), Corruption> {
+      let mut previous = ptr::null_mut();
+      let mut header = self.head;
+
+      while !header.is_null() {
+         if (*header).magic != magic || (*header).previous != previous {
+            return Err(unknown(header as usize, CorruptionKind::Header));
+         }
+
+         (*header).check()?;
+
+         previous = header;
+         header = (*header).next;
+      }
+
+      return Ok(());
+   }
+}
+
+struct Redzones {
+   live: HeaderList,
+   quarantine: HeaderList,
+   sequence: u64,
+}
+
+unsafe impl Send for Redzones {}
+
+impl Redzones {
+   const fn new() -> Self {
+      return Redzones{
+         live: HeaderList::new(),
+         quarantine: HeaderList::new(),
+         sequence: 0,
+      };
+   }
+}
+
+/// The layout of the block behind an allocation of `layout`: a header and the lower redzone in
+/// front, the upper redzone behind.
+pub(crate) fn guarded_layout(layout: Layout) -> Option<Layout> {
+   let size = Header::front(layout.align)
+      .checked_add(layout.size)?
+      .checked_add(REDZONE_SIZE)?;
+
+   return Layout::from_size_align(size, max(layout.align, MIN_ALIGN)).ok();
+}
+
+/// Lays out a freshly allocated block of [`guarded_layout(layout)`](guarded_layout) and returns
+/// the pointer to hand out. A null block is passed through.
+pub(crate) unsafe fn arm(block: *mut u8, layout: Layout) -> *mut u8 {
+   if block.is_null() {
+      return block;
+   }
+
+   let header = block as *mut Header;
+   let user = block.add(Header::front(layout.align));
+
+   ptr::write_bytes(block.add(size_of::<Header>()), REDZONE_BYTE, user as usize - block as usize - size_of::<Header>());
+   ptr::write_bytes(user.add(layout.size), REDZONE_BYTE, REDZONE_SIZE);
+
+   let mut redzones = REDZONES.lock();
+   redzones.sequence += 1;
+   header.write(Header{
+      next: ptr::null_mut(),
+      previous: ptr::null_mut(),
+      size: layout.size,
+      align: layout.align,
+      sequence: redzones.sequence,
+      magic: LIVE_MAGIC,
+   });
+   redzones.live.push_back(header);
+
+   return user;
+}
+
+/// Checks the allocation at `pointer` and moves it into quarantine, poisoned. The block stays
+/// out of the heap until [`evict`] hands it back.
+///
+/// ## Panics
+///
+/// Panics with a report naming the allocation if it is corrupted or `layout` does not match it.
+pub(crate) unsafe fn quarantine(pointer: *mut u8, layout: Layout) {
+   let header = pointer.sub(Header::front(layout.align)) as *mut Header;
+
+   let mut redzones = REDZONES.lock();
+   let report = match (*header).magic {
+      LIVE_MAGIC if (*header).size != layout.size || (*header).align != layout.align => {
+         Err((*header).corruption(CorruptionKind::LayoutMismatch, 0))
+      }
+      LIVE_MAGIC => (*header).check(),
+      FREED_MAGIC => Err((*header).corruption(CorruptionKind::DoubleFree, 0)),
+      _ => Err(unknown(pointer as usize, CorruptionKind::Header)),
+   };
+
+   if let Err(corruption) = report {
+      drop(redzones);
+      panic!("{}", corruption);
+   }
+
+   redzones.live.remove(header);
+   ptr::write_bytes(pointer, POISON_BYTE, layout.size);
+   (*header).magic = FREED_MAGIC;
+   redzones.quarantine.push_back(header);
+}
+
+/// Takes the oldest block out of quarantine if the quarantine is over its limits, checking that
+/// nothing wrote to it while it was freed. Returns the block and its layout, ready to go back to
+/// the heap.
+///
+/// ## Panics
+///
+/// Panics with a report naming the allocation if the block was written to after it was freed.
+pub(crate) fn evict() -> Option<(*mut u8, Layout)> {
+   let mut redzones = REDZONES.lock();
+   let over = redzones.quarantine.length > QUARANTINE_LENGTH || redzones.quarantine.bytes > QUARANTINE_BYTES;
+   return match over {
+      true => pop_quarantine(&mut redzones),
+      false => None,
+   };
+}
+
+fn pop_quarantine(redzones: &mut Redzones) -> Option<(*mut u8, Layout)> {
+   let header = redzones.quarantine.head;
+   if header.is_null() {
+      return None;
+   }
+
+   unsafe {
+      if let Err(corruption) = (*header).check() {
+         panic!("{}", corruption);
+      }
+
+      redzones.quarantine.remove(header);
+      (*header).magic = 0;
+      return Some((header as *mut u8, (*header).outer_layout()));
+   }
+}
+
+/// Checks every live and quarantined allocation for redzone overruns, writes after free and
+/// broken headers.
+///
+/// Returns the first corruption found. Call this at points where the heap is expected to be
+/// consistent, e.g. before and after a test.
+pub fn check() -> Result<(), Corruption> {
+   let redzones = REDZONES.lock();
+   unsafe {
+      redzones.live.check(LIVE_MAGIC)?;
+      redzones.quarantine.check(FREED_MAGIC)?;
+   }
+
+   return Ok(());
+}
+
+/// Checks the quarantine and returns every block in it to the heap.
+///
+/// ## Panics
+///
+/// Panics with a report naming the allocation if a quarantined block was written to.
+pub fn drain_quarantine() {
+   loop {
+      let block = pop_quarantine(&mut REDZONES.lock());
+      match block {
+         Some((block, layout)) => unsafe{ heap_deallocate(block, layout) },
+         None => return,
+      }
+   }
+}
+
+/// Number of blocks and bytes currently held in quarantine.
+pub fn quarantined() -> (usize, usize) {
+   let redzones = REDZONES.lock();
+   return (redzones.quarantine.length, redzones.quarantine.bytes);
+}
+
+/// A corruption report for a block whose header cannot be trusted, so all that is known about it
+/// is where it is.
+fn unknown(address: usize, kind: CorruptionKind) -> Corruption {
+   return Corruption{
+      kind,
+      address,
+      size: 0,
+      align: 0,
+      sequence: 0,
+      offset: 0,
+   };
+}
+
+/// Offset of the first of the `length` bytes at `start` that is not `byte`.
+unsafe fn find_not(start: *const u8, length: usize, byte: u8) -> Option<usize> {
+   return slice::from_raw_parts(start, length).iter().position(|b| *b != byte);
+}
+
+// IMPORTS //
+
+use {
+   super::{heap_deallocate, Layout},
+   core::{
+      cmp::max,
+      fmt::{Display, Formatter, Result as FmtResult},
+      mem::size_of,
+      ptr,
+      slice,
+   },
+   spin::Mutex,
+};
@@ -0,0 +1,505 @@
+/// The general-purpose slab allocator, serving every size class in [`SIZE_CLASSES`].
+pub static SLAB: SlabAllocator = SlabAllocator::new();
+
+/// Object sizes the [`SlabAllocator`] keeps a cache for. Larger requests go straight to the heap.
+pub const SIZE_CLASSES: [usize; 16] = [
+   8, 16, 24, 32, 48, 64, 96, 128, 192, 256, 384, 512, 768, 1024, 1536, 2048,
+];
+
+/// Names of the caches behind [`SIZE_CLASSES`].
+const CLASS_NAMES: [&str; 16] = [
+   "slab-8", "slab-16", "slab-24", "slab-32", "slab-48", "slab-64", "slab-96", "slab-128",
+   "slab-192", "slab-256", "slab-384", "slab-512", "slab-768", "slab-1024", "slab-1536", "slab-2048",
+];
+
+/// Largest alignment a slab object can have.
+pub const MAX_SLAB_ALIGN: usize = 64;
+
+/// Bytes reserved for the header at the start of every slab. Objects start right after it, so
+/// they stay aligned up to [`MAX_SLAB_ALIGN`].
+const SLAB_HEADER: usize = 64;
+
+/// Smallest slab, one page.
+const MIN_SLAB_SIZE: usize = 4096;
+
+/// Slabs hold at least this many objects, which keeps the per-slab waste small for large sizes.
+const MIN_SLAB_OBJECTS: usize = 16;
+
+/// The header at the start of every slab.
+///
+/// Slabs are aligned to their own size, so the header of the slab holding an object is found by
+/// masking the object's address.
+struct Slab {
+   next: *mut Slab,
+   previous: *mut Slab,
+   free: LinkedList,
+   active: usize,
+}
+
+/// An intrusive doubly linked list of slabs.
+#[derive(Clone, Copy)]
+struct SlabList {
+   head: *mut Slab,
+}
+
+impl SlabList {
+   const fn new() -> Self {
+      return SlabList{ head: ptr::null_mut() };
+   }
+
+   fn empty(&self) -> bool {
+      return self.head.is_null();
+   }
+
+   unsafe fn push(&mut self, slab: *mut Slab) {
+      (*slab).previous = ptr::null_mut();
+      (*slab).next = self.head;
+
+      if !self.head.is_null() {
+         (*self.head).previous = slab;
+      }
+
+      self.head = slab;
+   }
+
+   unsafe fn remove(&mut self, slab: *mut Slab) {
+      if (*slab).previous.is_null() {
+         self.head = (*slab).next;
+      } else {
+         (*(*slab).previous).next = (*slab).next;
+      }
+
+      if !(*slab).next.is_null() {
+         (*(*slab).next).previous = (*slab).previous;
+      }
+
+      (*slab).next = ptr::null_mut();
+      (*slab).previous = ptr::null_mut();
+   }
+}
+
+/// A cache of equally sized objects, carved out of slabs taken from the buddy [`HEAP`].
+///
+/// Slabs move between three lists: partial slabs serve allocations, full slabs wait for a free,
+/// and a single empty slab is kept back so that a cache hovering around a slab boundary does not
+/// keep returning memory to the heap and asking for it again.
+pub struct SlabCache {
+   /// The name shown in the statistics.
+   pub name: &'static str,
+
+   /// Size of each object slot, rounded up to the object alignment.
+   pub object_size: usize,
+
+   /// Size of each slab, a power of two.
+   pub slab_size: usize,
+
+   /// Number of slabs currently owned by the cache.
+   pub slabs: usize,
+
+   /// Number of objects currently handed out.
+   pub active: usize,
+
+   /// Number of successful allocations since the cache was created.
+   pub allocations: usize,
+
+   /// Number of frees since the cache was created.
+   pub frees: usize,
+
+   partial: SlabList,
+   full: SlabList,
+   empty: SlabList,
+}
+
+unsafe impl Send for SlabCache {}
+
+impl SlabCache {
+   /// Creates an empty cache for objects of `size` bytes aligned to `align`.
+   pub const fn new(name: &'static str, size: usize, align: usize) -> Self {
+      assert!(align.is_power_of_two() && align <= MAX_SLAB_ALIGN, "unsupported slab alignment");
+
+      let size = if size < size_of::<usize>() { size_of::<usize>() } else { size };
+      let object_size = (size + align - 1) / align * align;
+
+      let slab_size = (SLAB_HEADER + object_size * MIN_SLAB_OBJECTS).next_power_of_two();
+      let slab_size = if slab_size < MIN_SLAB_SIZE { MIN_SLAB_SIZE } else { slab_size };
+
+      return SlabCache{
+         name,
+         object_size,
+         slab_size,
+         slabs: 0,
+         active: 0,
+         allocations: 0,
+         frees: 0,
+         partial: SlabList::new(),
+         full: SlabList::new(),
+         empty: SlabList::new(),
+      };
+   }
+
+   /// Number of objects that fit in one slab.
+   pub const fn objects_per_slab(&self) -> usize {
+      return (self.slab_size - SLAB_HEADER) / self.object_size;
+   }
+
+   /// Hands out one object, taking a new slab from the heap if every slab is full.
+   pub fn allocate(&mut self) -> Option<NonNull<u8>> {
+      unsafe {
+         if self.partial.empty() {
+            if !self.empty.empty() {
+               let slab = self.empty.head;
+               self.empty.remove(slab);
+               self.partial.push(slab);
+            } else if !self.grow() {
+               return None;
+            }
+         }
+
+         let slab = self.partial.head;
+         let object = (*slab).free.pop().expect("partial slab should have a free object");
+         (*slab).active += 1;
+
+         if (*slab).free.empty() {
+            self.partial.remove(slab);
+            self.full.push(slab);
+         }
+
+         self.active += 1;
+         self.allocations += 1;
+
+         return NonNull::new(object as *mut u8);
+      }
+   }
+
+   /// Returns an object to the cache.
+   ///
+   /// ## Safety
+   ///
+   /// `pointer` must have come from [`allocate`](SlabCache::allocate) on this cache and must not
+   /// be used again.
+   pub unsafe fn deallocate(&mut self, pointer: NonNull<u8>) {
+      let slab = (pointer.as_ptr() as usize & !(self.slab_size - 1)) as *mut Slab;
+      let wasFull = (*slab).free.empty();
+
+      (*slab).free.push(pointer.as_ptr() as *mut usize);
+      (*slab).active -= 1;
+
+      if wasFull {
+         self.full.remove(slab);
+         self.partial.push(slab);
+      }
+
+      if (*slab).active == 0 {
+         self.partial.remove(slab);
+
+         if self.empty.empty() {
+            self.empty.push(slab);
+         } else {
+            self.release(slab);
+         }
+      }
+
+      self.active -= 1;
+      self.frees += 1;
+   }
+
+   /// Gives every empty slab back to the heap.
+   pub fn shrink(&mut self) {
+      while !self.empty.empty() {
+         unsafe {
+            let slab = self.empty.head;
+            self.empty.remove(slab);
+            self.release(slab);
+         }
+      }
+   }
+
+   /// A snapshot of the cache's counters.
+   pub fn statistics(&self) -> CacheStatistics {
+      return CacheStatistics{
+         name: self.name,
+         object_size: self.object_size,
+         slab_size: self.slab_size,
+         slabs: self.slabs,
+         active: self.active,
+         capacity: self.slabs * self.objects_per_slab(),
+         allocations: self.allocations,
+         frees: self.frees,
+      };
+   }
+
+   /// Takes a slab from the heap and threads its objects onto a free list.
+   unsafe fn grow(&mut self) -> bool {
+      let layout = Layout::from_size_align_unchecked(self.slab_size, self.slab_size);
+      let memory = match HEAP.lock().as_mut().map(|heap| heap.allocate_or_extend(layout)) {
+         Some(Ok(memory)) => memory.as_ptr() as usize,
+         _ => return false,
+      };
+
+      let slab = memory as *mut Slab;
+      slab.write(Slab{
+         next: ptr::null_mut(),
+         previous: ptr::null_mut(),
+         free: LinkedList::new(),
+         active: 0,
+      });
+
+      // Push in reverse so objects are handed out in address order.
+      for index in (0..self.objects_per_slab()).rev() {
+         (*slab).free.push((memory + SLAB_HEADER + index * self.object_size) as *mut usize);
+      }
+
+      self.partial.push(slab);
+      self.slabs += 1;
+
+      return true;
+   }
+
+   unsafe fn release(&mut self, slab: *mut Slab) {
+      let layout = Layout::from_size_align_unchecked(self.slab_size, self.slab_size);
+      HEAP.lock()
+         .as_mut()
+         .expect("slabs are only created once the heap exists")
+         .deallocate(NonNull::new_unchecked(slab as *mut u8), layout);
+
+      self.slabs -= 1;
+   }
+}
+
+/// Counters for one [`SlabCache`].
+#[derive(Copy, Clone, Debug)]
+pub struct CacheStatistics {
+   /// The cache's name.
+   pub name: &'static str,
+
+   /// Size of each object slot.
+   pub object_size: usize,
+
+   /// Size of each slab.
+   pub slab_size: usize,
+
+   /// Slabs owned by the cache.
+   pub slabs: usize,
+
+   /// Objects handed out.
+   pub active: usize,
+
+   /// Objects the cache's slabs can hold.
+   pub capacity: usize,
+
+   /// Allocations since the cache was created.
+   pub allocations: usize,
+
+   /// Frees since the cache was created.
+   pub frees: usize,
+}
+
+impl Display for CacheStatistics {
+   fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+      write!(
+         f,
+         "{:<12} {:>5} B {:>6}/{:<6} objects {:>4} slabs of {:>5} B ({} allocations, {} frees)",
+         self.name,
+         self.object_size,
+         self.active,
+         self.capacity,
+         self.slabs,
+         self.slab_size,
+         self.allocations,
+         self.frees,
+      )
+   }
+}
+
+/// A slab allocator with one [`SlabCache`] per size class.
+///
+/// A request is served by the smallest class that fits its size and whose slots keep its
+/// alignment; anything larger than the largest class, or aligned beyond [`MAX_SLAB_ALIGN`], is
+/// passed through to the buddy [`HEAP`].
+pub struct SlabAllocator {
+   caches: Mutex<[SlabCache; SIZE_CLASSES.len()]>,
+}
+
+impl SlabAllocator {
+   /// Creates an allocator with empty caches.
+   pub const fn new() -> Self {
+      const EMPTY: SlabCache = SlabCache::new("", 0, 1);
+
+      let mut caches = [EMPTY; SIZE_CLASSES.len()];
+      let mut index = 0;
+
+      while index < SIZE_CLASSES.len() {
+         caches[index] = SlabCache::new(CLASS_NAMES[index], SIZE_CLASSES[index], 1);
+         index += 1;
+      }
+
+      return SlabAllocator{ caches: Mutex::new(caches) };
+   }
+
+   /// Per-cache statistics, in size-class order.
+   pub fn statistics(&self) -> [CacheStatistics; SIZE_CLASSES.len()] {
+      let caches = self.caches.lock();
+      return core::array::from_fn(|index| caches[index].statistics());
+   }
+
+   /// Gives every empty slab in every cache back to the heap.
+   pub fn shrink(&self) {
+      for cache in self.caches.lock().iter_mut() {
+         cache.shrink();
+      }
+   }
+
+   /// The index of the size class serving `layout`, if any.
+   fn class_of(layout: Layout) -> Option<usize> {
+      if layout.align > MAX_SLAB_ALIGN {
+         return None;
+      }
+
+      return SIZE_CLASSES
+         .iter()
+         .position(|&size| size >= layout.size && size % layout.align == 0);
+   }
+}
+
+unsafe impl Allocator for SlabAllocator {
+   fn allocate(&self, layout: Layout) -> Option<NonNull<u8>> {
+      return match SlabAllocator::class_of(layout) {
+         Some(class) => self.caches.lock()[class].allocate(),
+         None => unsafe {
+            HEAP.lock().as_mut()?.allocate_or_extend(layout).ok()
+         },
+      };
+   }
+
+   unsafe fn deallocate(&self, pointer: *mut u8, layout: Layout) {
+      let pointer = match NonNull::new(pointer) {
+         Some(pointer) => pointer,
+         None => return,
+      };
+
+      match SlabAllocator::class_of(layout) {
+         Some(class) => self.caches.lock()[class].deallocate(pointer),
+         None => HEAP
+            .lock()
+            .as_mut()
+            .expect("must first initialise heap before attempting to deallocate memory")
+            .deallocate(pointer, layout),
+      }
+   }
+
+   unsafe fn reallocate(
+      &self,
+      pointer: *mut u8,
+      oldSize: usize,
+      layout: Layout,
+   ) -> Option<NonNull<u8>> {
+      if self.reallocate_in_place(pointer, oldSize, layout) {
+         return NonNull::new(pointer);
+      }
+
+      let newPointer = self.allocate(layout)?;
+      ptr::copy_nonoverlapping(pointer, newPointer.as_ptr(), min(oldSize, layout.size));
+      self.deallocate(pointer, Layout::from_size_align_unchecked(oldSize, layout.align));
+
+      return Some(newPointer);
+   }
+
+   fn usable_size(&self, layout: Layout) -> usize {
+      return match SlabAllocator::class_of(layout) {
+         Some(class) => SIZE_CLASSES[class],
+         None => block_size(layout),
+      };
+   }
+
+   unsafe fn reallocate_in_place(&self, pointer: *mut u8, oldSize: usize, layout: Layout) -> bool {
+      let oldLayout = Layout::from_size_align_unchecked(oldSize, layout.align);
+
+      return match (SlabAllocator::class_of(oldLayout), SlabAllocator::class_of(layout)) {
+         // Same slot size: the object already fits.
+         (Some(old), Some(new)) => old == new,
+         (None, None) => match (NonNull::new(pointer), HEAP.lock().as_mut()) {
+            (Some(pointer), Some(heap)) => heap.reallocate_in_place(pointer, oldLayout, layout.size),
+            _ => false,
+         },
+         _ => false,
+      };
+   }
+}
+
+/// A typed cache for one kind of kernel object, such as tasks or process control blocks.
+///
+/// # Usage
+///
+/// ```no_run
+/// use base::alloc::slab::ObjectCache;
+///
+/// struct Control { id: usize, state: u8 }
+///
+/// static CONTROLS: ObjectCache<Control> = ObjectCache::new("controls");
+///
+/// let control = CONTROLS.allocate(Control{ id: 1, state: 0 }).unwrap();
+/// unsafe{ CONTROLS.free(control) };
+/// ```
+pub struct ObjectCache<T> {
+   cache: Mutex<SlabCache>,
+   marker: PhantomData<*const T>,
+}
+
+unsafe impl<T: Send> Send for ObjectCache<T> {}
+unsafe impl<T: Send> Sync for ObjectCache<T> {}
+
+impl<T> ObjectCache<T> {
+   /// Creates an empty cache for `T`.
+   pub const fn new(name: &'static str) -> Self {
+      return ObjectCache{
+         cache: Mutex::new(SlabCache::new(name, size_of::<T>(), align_of::<T>())),
+         marker: PhantomData,
+      };
+   }
+
+   /// Moves `value` into a new object from the cache.
+   pub fn allocate(&self, value: T) -> Option<NonNull<T>> {
+      let object = self.cache.lock().allocate()?.cast::<T>();
+      unsafe{ object.as_ptr().write(value) };
+
+      return Some(object);
+   }
+
+   /// Drops the object and returns its slot to the cache.
+   ///
+   /// ## Safety
+   ///
+   /// `object` must have come from [`allocate`](ObjectCache::allocate) on this cache and must not
+   /// be used again.
+   pub unsafe fn free(&self, object: NonNull<T>) {
+      ptr::drop_in_place(object.as_ptr());
+      self.cache.lock().deallocate(object.cast());
+   }
+
+   /// Gives every empty slab back to the heap.
+   pub fn shrink(&self) {
+      self.cache.lock().shrink();
+   }
+
+   /// A snapshot of the cache's counters.
+   pub fn statistics(&self) -> CacheStatistics {
+      return self.cache.lock().statistics();
+   }
+}
+
+// IMPORTS //
+
+use {
+   crate::{
+      alloc::{heap::{block_size, HEAP}, Allocator, Layout},
+      array::linked_list::LinkedList,
+   },
+   core::{
+      cmp::min,
+      fmt::{self, Display, Formatter},
+      marker::PhantomData,
+      mem::{align_of, size_of},
+      ptr::{self, NonNull},
+   },
+   spin::Mutex,
+};
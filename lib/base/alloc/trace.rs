@@ -0,0 +1,438 @@
+/// Most live allocations the tracer can follow at once. Allocations made while the table is full
+/// are counted in [`TraceStatistics::dropped`] but otherwise go untraced.
+pub const MAX_TRACKED: usize = 4096;
+
+/// Return addresses kept for each allocation, innermost first.
+pub const BACKTRACE_DEPTH: usize = 6;
+
+/// Furthest above the current stack pointer a frame pointer may lie and still be followed.
+const STACK_WINDOW: usize = 64 * 1024;
+
+static TRACER: Mutex<Tracer> = Mutex::new(Tracer::new());
+
+/// A live allocation as the tracer saw it.
+#[derive(Copy, Clone, Debug)]
+pub struct AllocationRecord {
+   /// Address of the allocation.
+   pub address: usize,
+
+   /// Size requested, in bytes. Follows in-place resizes.
+   pub size: usize,
+
+   /// Alignment requested.
+   pub align: usize,
+
+   /// Return addresses leading up to the allocation, starting at the allocator's caller. Unused
+   /// entries are zero.
+   pub callers: [usize; BACKTRACE_DEPTH],
+
+   /// Time stamp counter when the allocation was made.
+   pub timestamp: u64,
+
+   /// Position of the allocation among all traced allocations, starting at 1.
+   pub sequence: u64,
+}
+
+impl AllocationRecord {
+   /// The innermost known return address, or zero if the stack could not be walked.
+   pub fn caller(&self) -> usize {
+      return self.callers[0];
+   }
+}
+
+impl Display for AllocationRecord {
+   fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+      write!(
+         f,
+         "#{} {:#x}: {} bytes, align {}, tsc {}, callers",
+         self.sequence,
+         self.address,
+         self.size,
+         self.align,
+         self.timestamp,
+      )?;
+
+      for caller in self.callers.iter().take_while(|caller| **caller != 0) {
+         write!(f, " {:#x}", caller)?;
+      }
+
+      return Ok(());
+   }
+}
+
+/// A point in the allocation history that later allocations can be compared against.
+#[derive(Copy, Clone, Debug)]
+pub struct Checkpoint {
+   /// Sequence number of the last allocation made before the checkpoint.
+   pub sequence: u64,
+
+   /// Frees seen before the checkpoint.
+   pub frees: u64,
+
+   /// Allocations live at the checkpoint.
+   pub live: usize,
+
+   /// Bytes live at the checkpoint.
+   pub bytes: usize,
+}
+
+/// What changed between a [`Checkpoint`] and now.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct AllocationDiff {
+   /// Allocations made since the checkpoint.
+   pub allocations: u64,
+
+   /// Frees since the checkpoint, including frees of older allocations.
+   pub frees: u64,
+
+   /// Allocations made since the checkpoint that are still live.
+   pub leaked: usize,
+
+   /// Bytes held by the leaked allocations.
+   pub leaked_bytes: usize,
+
+   /// Change in live bytes since the checkpoint.
+   pub net_bytes: isize,
+}
+
+impl Display for AllocationDiff {
+   fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+      return write!(
+         f,
+         "{} allocations, {} frees, {} still live ({} bytes), net {:+} bytes",
+         self.allocations,
+         self.frees,
+         self.leaked,
+         self.leaked_bytes,
+         self.net_bytes,
+      );
+   }
+}
+
+/// Running totals kept by the tracer.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct TraceStatistics {
+   /// Allocations currently traced.
+   pub live: usize,
+
+   /// Bytes held by the traced allocations.
+   pub bytes: usize,
+
+   /// Allocations traced since boot.
+   pub allocations: u64,
+
+   /// Frees of traced allocations since boot.
+   pub frees: u64,
+
+   /// Allocations that went untraced because the table was full.
+   pub dropped: u64,
+}
+
+impl Display for TraceStatistics {
+   fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+      return write!(
+         f,
+         "{} live allocations ({} bytes), {} allocations, {} frees, {} untraced",
+         self.live,
+         self.bytes,
+         self.allocations,
+         self.frees,
+         self.dropped,
+      );
+   }
+}
+
+/// Open-addressed table of live allocations keyed by address.
+///
+/// The table lives in static memory: the tracer runs inside the allocator and must never allocate.
+struct Tracer {
+   slots: [Option<AllocationRecord>; MAX_TRACKED],
+   statistics: TraceStatistics,
+}
+
+impl Tracer {
+   const fn new() -> Self {
+      return Tracer{
+         slots: [None; MAX_TRACKED],
+         statistics: TraceStatistics{
+            live: 0,
+            bytes: 0,
+            allocations: 0,
+            frees: 0,
+            dropped: 0,
+         },
+      };
+   }
+
+   fn home(address: usize) -> usize {
+      // Fibonacci hashing; the low bits of a heap address are mostly zero.
+      return (address.wrapping_mul(0x9E37_79B9_7F4A_7C15) >> 32) % MAX_TRACKED;
+   }
+
+   fn find(&self, address: usize) -> Option<usize> {
+      let mut index = Tracer::home(address);
+      for _ in 0..MAX_TRACKED {
+         match self.slots[index] {
+            Some(record) if record.address == address => return Some(index),
+            Some(_) => index = (index + 1) % MAX_TRACKED,
+            None => return None,
+         }
+      }
+
+      return None;
+   }
+
+   fn insert(&mut self, mut record: AllocationRecord) {
+      if self.statistics.live == MAX_TRACKED {
+         self.statistics.dropped += 1;
+         return;
+      }
+
+      self.statistics.allocations += 1;
+      self.statistics.live += 1;
+      self.statistics.bytes += record.size;
+      record.sequence = self.statistics.allocations;
+
+      let mut index = Tracer::home(record.address);
+      while self.slots[index].is_some() {
+         index = (index + 1) % MAX_TRACKED;
+      }
+      self.slots[index] = Some(record);
+   }
+
+   fn remove(&mut self, address: usize) {
+      let mut hole = match self.find(address) {
+         Some(index) => index,
+         None => return,
+      };
+
+      let record = self.slots[hole].take().unwrap();
+      self.statistics.frees += 1;
+      self.statistics.live -= 1;
+      self.statistics.bytes -= record.size;
+
+      // Backward-shift deletion: pull later entries of the probe run into the hole so lookups
+      // never stop early at it.
+      let mut index = hole;
+      loop {
+         index = (index + 1) % MAX_TRACKED;
+         let home = match self.slots[index] {
+            Some(record) => Tracer::home(record.address),
+            None => break,
+         };
+
+         let distance = (index + MAX_TRACKED - home) % MAX_TRACKED;
+         let gap = (index + MAX_TRACKED - hole) % MAX_TRACKED;
+         if distance >= gap {
+            self.slots[hole] = self.slots[index].take();
+            hole = index;
+         }
+      }
+   }
+
+   fn resize(&mut self, address: usize, size: usize) {
+      if let Some(index) = self.find(address) {
+         let record = self.slots[index].as_mut().unwrap();
+         self.statistics.bytes = self.statistics.bytes - record.size + size;
+         record.size = size;
+      }
+   }
+
+   fn records(&self) -> impl Iterator<Item = &AllocationRecord> {
+      return self.slots.iter().filter_map(|slot| slot.as_ref());
+   }
+}
+
+/// Records an allocation of `layout` at `pointer`. Null pointers are ignored.
+#[inline(never)]
+pub fn record_allocation(pointer: *const u8, layout: Layout) {
+   if pointer.is_null() {
+      return;
+   }
+
+   let record = AllocationRecord{
+      address: pointer as usize,
+      size: layout.size,
+      align: layout.align,
+      callers: backtrace(),
+      timestamp: timestamp(),
+      sequence: 0,
+   };
+
+   without_interrupts(|| TRACER.lock().insert(record));
+}
+
+/// Forgets the allocation at `pointer`. Pointers the tracer never saw are ignored.
+pub fn record_free(pointer: *const u8) {
+   without_interrupts(|| TRACER.lock().remove(pointer as usize));
+}
+
+/// Notes that the allocation at `pointer` was resized in place to `size` bytes.
+pub fn record_resize(pointer: *const u8, size: usize) {
+   without_interrupts(|| TRACER.lock().resize(pointer as usize, size));
+}
+
+/// Current totals.
+pub fn statistics() -> TraceStatistics {
+   return without_interrupts(|| TRACER.lock().statistics);
+}
+
+/// Calls `f` on every live allocation, in table order.
+///
+/// The tracer is locked while `f` runs, so `f` must not allocate.
+pub fn for_each_live(mut f: impl FnMut(&AllocationRecord)) {
+   without_interrupts(|| TRACER.lock().records().for_each(|record| f(record)));
+}
+
+/// Marks the current point in the allocation history.
+pub fn checkpoint() -> Checkpoint {
+   let statistics = statistics();
+   return Checkpoint{
+      sequence: statistics.allocations,
+      frees: statistics.frees,
+      live: statistics.live,
+      bytes: statistics.bytes,
+   };
+}
+
+/// Compares the allocation history against `since`.
+pub fn diff(since: &Checkpoint) -> AllocationDiff {
+   return without_interrupts(|| {
+      let tracer = TRACER.lock();
+      let mut diff = AllocationDiff{
+         allocations: tracer.statistics.allocations - since.sequence,
+         frees: tracer.statistics.frees - since.frees,
+         net_bytes: tracer.statistics.bytes as isize - since.bytes as isize,
+         ..AllocationDiff::default()
+      };
+
+      for record in tracer.records().filter(|record| record.sequence > since.sequence) {
+         diff.leaked += 1;
+         diff.leaked_bytes += record.size;
+      }
+
+      diff
+   });
+}
+
+/// Writes every live allocation to the serial port, oldest first.
+pub fn dump_live_allocations() {
+   // Handlers log through the same port, so none may run on this CPU while it is locked.
+   without_interrupts(|| {
+      let _ = write_allocations(&mut *COM2.lock(), 0);
+   });
+}
+
+/// Writes the allocations made since `since` that are still live to the serial port, followed by
+/// a summary of the [`diff`].
+pub fn dump_leaks(since: &Checkpoint) {
+   without_interrupts(|| {
+      let mut serial = COM2.lock();
+      let _ = write_allocations(&mut *serial, since.sequence)
+         .and_then(|_| writeln!(serial, "alloc-trace: since checkpoint: {}", diff(since)));
+   });
+}
+
+/// Writes the live allocations newer than sequence number `after` to `out`, oldest first.
+///
+/// Walks the table once per allocation instead of sorting it, since there is nowhere to sort into.
+pub fn write_allocations(out: &mut dyn Write, after: u64) -> FmtResult {
+   return without_interrupts(|| {
+      let tracer = TRACER.lock();
+      writeln!(out, "alloc-trace: {}", tracer.statistics)?;
+
+      let mut last = after;
+      loop {
+         let next = tracer.records()
+            .filter(|record| record.sequence > last)
+            .min_by_key(|record| record.sequence);
+
+         match next {
+            Some(record) => {
+               writeln!(out, "   {}", record)?;
+               last = record.sequence;
+            }
+            None => return Ok(()),
+         }
+      }
+   });
+}
+
+/// Walks the frame pointer chain from the caller of this function, leaving out the allocator's
+/// own frames.
+///
+/// The kernel target is built with `-C force-frame-pointers=yes` (see `.cargo/config.toml`) for
+/// this walk. Frames are only followed upwards and within [`STACK_WINDOW`] of the stack pointer,
+/// and a zero frame pointer, which the kernel stack entry point sets up, ends the chain. Return
+/// addresses inside the allocator's entry points are skipped until the first one outside it; the
+/// `__rg_alloc` shim the compiler generates for the global allocator may still show up first.
+#[inline(always)]
+fn backtrace() -> [usize; BACKTRACE_DEPTH] {
+   let mut callers = [0; BACKTRACE_DEPTH];
+
+   #[cfg(target_arch = "x86_64")]
+   unsafe {
+      let (mut frame, stack): (usize, usize);
+      asm!("mov {}, rbp", "mov {}, rsp", out(reg) frame, out(reg) stack, options(nomem, nostack));
+
+      let limit = stack.saturating_add(STACK_WINDOW);
+      let mut count = 0;
+
+      while count < BACKTRACE_DEPTH {
+         if frame < stack || frame >= limit - 16 || frame % 8 != 0 {
+            break;
+         }
+
+         let next = *(frame as *const usize);
+         let caller = *((frame + 8) as *const usize);
+
+         if count > 0 || !in_allocator(caller) {
+            callers[count] = caller;
+            count += 1;
+         }
+
+         if next <= frame {
+            break;
+         }
+         frame = next;
+      }
+   }
+
+   return callers;
+}
+
+/// Returns `true` if `address` lies in the code of the allocator's entry points, which tracing
+/// builds place in the `alloc_text` section.
+#[cfg(target_arch = "x86_64")]
+fn in_allocator(address: usize) -> bool {
+   extern "C" {
+      static __start_alloc_text: u8;
+      static __stop_alloc_text: u8;
+   }
+
+   // The linker defines both for any section whose name is a C identifier.
+   let (start, end) = unsafe{ (addr_of!(__start_alloc_text) as usize, addr_of!(__stop_alloc_text) as usize) };
+   return (start..end).contains(&address);
+}
+
+fn timestamp() -> u64 {
+   #[cfg(target_arch = "x86_64")]
+   return unsafe{ rdtsc() };
+
+   #[cfg(not(target_arch = "x86_64"))]
+   return 0;
+}
+
+// IMPORTS //
+
+#[cfg(target_arch = "x86_64")]
+use {
+   core::{arch::asm, ptr::addr_of},
+   x86::time::rdtsc,
+};
+
+use {
+   crate::{alloc::Layout, arch::without_interrupts, uart::COM2},
+   core::fmt::{Display, Formatter, Result as FmtResult, Write},
+   spin::Mutex,
+};
@@ -0,0 +1,12 @@
+/// Runs `f` with interrupts disabled on this CPU, restoring the previous state afterwards.
+///
+/// Anything an interrupt handler may lock must be locked through this, or the handler can spin
+/// forever on a lock held by the code it interrupted.
+#[inline]
+pub fn without_interrupts<R>(f: impl FnOnce() -> R) -> R {
+   #[cfg(target_arch = "x86_64")]
+   return x86_64::instructions::interrupts::without_interrupts(f);
+
+   #[cfg(not(target_arch = "x86_64"))]
+   return f();
+}
@@ -253,6 +253,20 @@ impl<T, A: Allocator> RawArray<T, A> {
          return;
       }
 
+      // Grow the block we already have if the allocator can.
+      if self.capacity > 0 {
+         if let (Ok(layout), Some(size)) = (
+            Layout::from_type_array::<T>(self.capacity),
+            new_capacity.checked_mul(size_of::<T>()),
+         ) {
+            if unsafe{ self.allocator.reallocate_aligned_in_place(self.pointer.cast::<u8>().as_ptr(), layout, size) } {
+               self.capacity = new_capacity;
+               self.claim_slack();
+               return;
+            }
+         }
+      }
+
       let mut pointer = unsafe {
          allocate_array::<T>(&mut self.allocator, new_capacity)
             .expect("Allocation error")
@@ -266,22 +280,42 @@ impl<T, A: Allocator> RawArray<T, A> {
                .allocator
                .deallocate_aligned(
                   self.pointer.cast::<u8>().as_ptr(),
-                  Layout::from_size(self.capacity)
+                  Layout::from_type_array::<T>(self.capacity).expect("array layout was valid when allocated"),
                );
          }
       }
 
       self.pointer = pointer.cast::<T>();
       self.capacity = new_capacity;
+      self.claim_slack();
+   }
+
+   /// Grows the capacity into whatever the allocator rounded the allocation up to, so the
+   /// rounding is not paid for twice.
+   fn claim_slack(&mut self) {
+      let layout = match Layout::from_type_array::<T>(self.capacity) {
+         Ok(layout) if layout.size > 0 => layout,
+         _ => return,
+      };
+
+      let usable = self.allocator.usable_size_aligned(layout) / size_of::<T>();
+      if usable > self.capacity && unsafe {
+         self.allocator.reallocate_aligned_in_place(self.pointer.cast::<u8>().as_ptr(), layout, usable * size_of::<T>())
+      } {
+         self.capacity = usable;
+      }
    }
 }
 
 impl<T, A: Allocator> Drop for RawArray<T, A> {
    fn drop(&mut self) {
-      if !self.pointer.as_ptr().is_null() {
+      // Nothing was allocated for an empty or zero-sized array; the pointer is dangling.
+      if self.capacity > 0 && size_of::<T>() > 0 {
          unsafe {
-            self.allocator
-               .deallocate_aligned(self.pointer.cast::<u8>().as_ptr(), Layout::from_size(self.capacity));
+            self.allocator.deallocate_aligned(
+               self.pointer.cast::<u8>().as_ptr(),
+               Layout::from_type_array::<T>(self.capacity).expect("array layout was valid when allocated"),
+            );
          }
       }
    }
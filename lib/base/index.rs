@@ -50,6 +50,10 @@ pub mod memory;
 /// [`Option`][core::option::Option] in Standard Rust.
 pub mod optional;
 
+/// Per-CPU data, reached through the GS base.
+#[cfg(target_arch = "x86_64")]
+pub mod percpu;
+
 /// Various smart pointer implementations.
 ///
 /// [`Unique`][crate::pointer::unique::Unique] is similar to `std::unique_ptr` in C++,
@@ -76,6 +80,9 @@ pub mod terminal;
 /// Testing utilities.
 pub mod test;
 
+/// The monotonic clock, run from the calibrated TSC, and the delays built on it.
+pub mod time;
+
 /// A pair of UART (universal asynchronous receiver-transmitter) implementations, one memory-mapped,
 /// and the other mapped to serial hardware.
 ///
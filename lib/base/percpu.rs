@@ -0,0 +1,145 @@
+/// One CPU's private data, found through its GS base.
+///
+/// While a CPU runs kernel code `IA32_GS_BASE` points at its area and `IA32_KERNEL_GS_BASE`
+/// holds the user GS base; entry paths from user mode swap the two with `swapgs`. The area starts
+/// with a pointer to itself, so `gs:[0]` turns into an ordinary reference.
+#[repr(C)]
+pub struct PerCpu {
+   /// Where this area lives, read through `gs:[0]`. Must stay the first field.
+   this: *const PerCpu,
+
+   /// The kernel's number for this CPU; the boot processor is 0.
+   pub id: usize,
+
+   /// The CPU's local APIC ID.
+   pub apic_id: u32,
+
+   /// The task this CPU is running, as recorded by the scheduler, or null while it runs no task.
+   pub current_task: AtomicPtr<()>,
+
+   /// The CPU's task state segment, which holds its interrupt stacks.
+   pub tss: &'static TaskStateSegment,
+
+   /// Tasks waiting to run on this CPU.
+   pub run_queue: Spinlock<Executor>,
+
+   /// Interrupt and exception handlers this CPU is inside of.
+   pub interrupt_depth: AtomicUsize,
+}
+
+// Only the owning CPU uses the area once it is installed, and every shared field is atomic or
+// locked.
+unsafe impl Send for PerCpu {}
+unsafe impl Sync for PerCpu {}
+
+impl PerCpu {
+   /// An area for CPU `id`, to be handed to [`install`] on that CPU.
+   pub const fn new(id: usize, apic_id: u32, tss: &'static TaskStateSegment) -> Self {
+      return PerCpu{
+         this: null(),
+         id,
+         apic_id,
+         current_task: AtomicPtr::new(null_mut()),
+         tss,
+         run_queue: Spinlock::new(Executor::new()),
+         interrupt_depth: AtomicUsize::new(0),
+      };
+   }
+}
+
+/// Counts an interrupt handler on the current CPU until dropped. See [`enter_interrupt`].
+pub struct InterruptGuard {
+   cpu: Option<&'static PerCpu>,
+}
+
+impl Drop for InterruptGuard {
+   fn drop(&mut self) {
+      if let Some(cpu) = self.cpu {
+         cpu.interrupt_depth.fetch_sub(1, Ordering::Relaxed);
+      }
+   }
+}
+
+/// Makes `area` the calling CPU's per-CPU area and returns it. The area is never freed.
+///
+/// ## Safety
+///
+/// Must be called once on each CPU, on that CPU, and with interrupts disabled. Loading the GS
+/// segment register afterwards clears the base on some CPUs, so it must not be reloaded.
+pub unsafe fn install(area: Box<PerCpu>) -> &'static PerCpu {
+   let area = Box::leak(area);
+   area.this = area;
+
+   wrmsr(IA32_GS_BASE, area as *const PerCpu as u64);
+   wrmsr(IA32_KERNEL_GSBASE, 0);
+
+   return area;
+}
+
+/// The calling CPU's area.
+///
+/// ## Panics
+///
+/// Panics before the calling CPU has installed its area.
+pub fn current() -> &'static PerCpu {
+   return try_current().expect("per-CPU data used before it was installed");
+}
+
+/// The calling CPU's area, or `None` before the calling CPU has installed its area.
+pub fn try_current() -> Option<&'static PerCpu> {
+   // Every CPU comes out of reset with a zero GS base, and a flag shared between CPUs would say
+   // nothing about this one.
+   if unsafe{ rdmsr(IA32_GS_BASE) } == 0 {
+      return None;
+   }
+
+   let area: *const PerCpu;
+   unsafe{ asm!("mov {}, gs:[0]", out(reg) area, options(nostack, preserves_flags, readonly)) };
+   return unsafe{ area.as_ref() };
+}
+
+/// Counts the caller as an interrupt handler on this CPU until the returned guard is dropped.
+/// Does nothing before per-CPU data is installed.
+pub fn enter_interrupt() -> InterruptGuard {
+   let cpu = try_current();
+   if let Some(cpu) = cpu {
+      cpu.interrupt_depth.fetch_add(1, Ordering::Relaxed);
+   }
+
+   return InterruptGuard{ cpu };
+}
+
+/// Returns `true` if the calling CPU is handling an interrupt or exception.
+pub fn in_interrupt() -> bool {
+   return try_current().map_or(false, |cpu| cpu.interrupt_depth.load(Ordering::Relaxed) > 0);
+}
+
+/// Gives the calling CPU's [`PerCpu`], or a reference to one of its fields.
+///
+/// ```ignore
+/// let cpu = per_cpu!(id);
+/// per_cpu!(interrupt_depth).load(Ordering::Relaxed);
+/// ```
+pub macro per_cpu {
+   () => {
+      $crate::percpu::current()
+   },
+   ($field:ident) => {
+      &$crate::percpu::current().$field
+   },
+}
+
+// IMPORTS //
+
+use {
+   crate::tasks::Executor,
+   core::{
+      arch::asm,
+      ptr::{null, null_mut},
+      sync::atomic::{AtomicPtr, AtomicUsize, Ordering},
+   },
+   spinning_top::Spinlock,
+   std_alloc::boxed::Box,
+   x86::msr::{rdmsr, wrmsr, IA32_GS_BASE, IA32_KERNEL_GSBASE},
+   x86_64::structures::tss::TaskStateSegment,
+};
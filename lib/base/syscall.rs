@@ -328,16 +328,62 @@ pub fn pause() {
    }
 }
 
+/// How the kernel turns the machine off and restarts it.
+#[derive(Copy, Clone)]
+pub struct PowerHandlers {
+   /// Turns the machine off.
+   pub poweroff: fn() -> !,
+
+   /// Restarts the machine.
+   pub reboot: fn() -> !,
+}
+
+static POWER_HANDLERS: Mutex<Option<PowerHandlers>> = Mutex::new(None);
+
+/// Installs the handlers [`shutdown`] and [`reboot`] hand over to.
+pub fn set_power_handlers(handlers: PowerHandlers) {
+   *POWER_HANDLERS.lock() = Some(handlers);
+}
+
+/// Turns the machine off, or halts this CPU for good if no handler is installed.
 #[no_mangle]
 pub extern "C" fn shutdown() -> ! {
-   // TODO: implement a proper shutdown sequence.
    crate::println!("Shutting down...");
-   loop{}
+
+   let handlers = *POWER_HANDLERS.lock();
+   if let Some(handlers) = handlers {
+      (handlers.poweroff)();
+   }
+
+   halt_forever();
+}
+
+/// Restarts the machine, or halts this CPU for good if no handler is installed.
+#[no_mangle]
+pub extern "C" fn reboot() -> ! {
+   crate::println!("Rebooting...");
+
+   let handlers = *POWER_HANDLERS.lock();
+   if let Some(handlers) = handlers {
+      (handlers.reboot)();
+   }
+
+   halt_forever();
+}
+
+fn halt_forever() -> ! {
+   unsafe{ asm!("cli", options(nomem, nostack)) };
+   loop {
+      halt();
+   }
 }
 
 // IMPORTS //
 
-use core::arch::asm;
+use {
+   core::arch::asm,
+   spin::Mutex,
+};
 
 // MODULES //
 
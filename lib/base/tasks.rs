@@ -1,18 +1,36 @@
-/// Polls all pending tasks on global executor and remove completed tasks.
+/// The calling CPU's run queue, or the global executor before per-CPU data is installed.
+fn executor() -> &'static Spinlock<Executor> {
+   #[cfg(target_arch = "x86_64")]
+   if let Some(cpu) = crate::percpu::try_current() {
+      return &cpu.run_queue;
+   }
+
+   return &executor::DEFAULT_EXECUTOR;
+}
+
+/// Polls all pending tasks on this CPU's executor and remove completed tasks.
 pub fn run_tasks() {
-   executor::DEFAULT_EXECUTOR.lock().run();
+   executor().lock().run();
 }
 
-/// Adds task for a future to the global executor queue.
+/// Adds task for a future to this CPU's executor queue.
 pub fn add_future<T>(future: impl Future<Output = T> + 'static + Send)
 where
    T: Send + 'static, {
-   executor::DEFAULT_EXECUTOR.lock().add_async(Box::pin(future));
+   executor().lock().add_async(Box::pin(future));
+}
+
+/// Checks whether no task on this CPU's executor has been woken since it was last polled.
+///
+/// Call with interrupts disabled to halt on the answer, or a wakeup from an interrupt handler
+/// could land between the check and the halt.
+pub fn idle() -> bool {
+   return executor().lock().is_idle();
 }
 
 /// Drops completed tasks and checks if any uncompleted tasks remain.
 pub fn completed() -> bool {
-   let mut executor = executor::DEFAULT_EXECUTOR.lock();
+   let mut executor = executor().lock();
    executor.collect();
    return executor.tasks.is_empty();
 }
@@ -21,7 +39,7 @@ pub fn completed() -> bool {
 pub fn poll_now<T>(future: impl Future<Output = T> + 'static + Send)
 where
    T: Send + 'static, {
-   executor::DEFAULT_EXECUTOR.lock().poll_now(Box::pin(future));
+   executor().lock().poll_now(Box::pin(future));
 }
 
 pub type TaskList = VecDeque<Box<dyn Pendable + core::marker::Send + core::marker::Sync>>;
@@ -32,6 +50,19 @@ pub type TaskList = VecDeque<Box<dyn Pendable + core::marker::Send + core::marke
 pub struct Task<T> {
    pub future: Spinlock<Pin<Box<dyn Future<Output = T> + Send + 'static>>>,
    pub completed: AtomicBool,
+   /// Set by the task's waker and cleared when the executor polls it.
+   pub woken: AtomicBool,
+}
+
+impl<T> Task<T> {
+   /// Wraps `future` in a task that is ready for its first poll.
+   pub fn new(future: Pin<Box<dyn Future<Output = T> + Send + 'static>>) -> Self {
+      return Task{
+         future: Spinlock::new(future),
+         completed: AtomicBool::new(false),
+         woken: AtomicBool::new(true),
+      };
+   }
 }
 
 pub trait Pendable {
@@ -44,30 +75,38 @@ pub trait Pendable {
    ///
    /// Needed to determine, which task we shall drop.
    fn is_done(&self) -> bool;
+
+   /// Returns `true` if the task has been woken since it was last polled.
+   fn is_woken(&self) -> bool;
 }
 
+// Waking only marks the task, since wakers are called from interrupt handlers and from inside
+// the task's own poll; the executor polls it on its next run.
 impl<T> Wake for Task<T> {
    fn wake(self: Arc<Self>) {
-      self.update();
+      self.woken.store(true, Ordering::Release);
    }
 
    fn wake_by_ref(self: &Arc<Self>) {
-      self.update();
+      self.woken.store(true, Ordering::Release);
    }
 }
 
 impl<T> ArcWake for Task<T> {
    fn wake(self: Arc<Self>) {
-      self.update();
+      self.woken.store(true, Ordering::Release);
    }
 
    fn wake_by_ref(arc_self: &Arc<Self>) {
-      arc_self.update();
+      arc_self.woken.store(true, Ordering::Release);
    }
 }
 
 impl<T> Pendable for Arc<Task<T>> {
    fn update(&self) {
+      // Cleared before polling, so a wakeup during the poll gets the task polled again.
+      self.woken.store(false, Ordering::Release);
+
       let mut future = self.future.lock();
       let waker = waker_ref(self);
       let context = &mut Context::from_waker(&waker);
@@ -80,6 +119,10 @@ impl<T> Pendable for Arc<Task<T>> {
    fn is_done(&self) -> bool {
       return self.completed.load(Ordering::Relaxed);
    }
+
+   fn is_woken(&self) -> bool {
+      return self.woken.load(Ordering::Acquire);
+   }
 }
 
 // MODULES //
@@ -1,4 +1,4 @@
-/// The default global task executor.
+/// The global task executor, used until the CPU has per-CPU data with a run queue of its own.
 pub static DEFAULT_EXECUTOR: Spinlock<Executor> = Spinlock::new(Executor::new());
 
 /// Our executor type.
@@ -24,10 +24,7 @@ impl Executor {
    pub fn poll_now<T>(&mut self, future: Pin<Box<dyn Future<Output = T> + 'static + Send>>)
    where
       T: Send + 'static {
-      let task = Arc::new(Task{
-         future: Spinlock::new(future),
-         completed: AtomicBool::new(false),
-      });
+      let task = Arc::new(Task::new(future));
 
       task.update();
       self.add_task(task);
@@ -37,15 +34,12 @@ impl Executor {
    pub fn add_async<T>(&mut self, future: Pin<Box<dyn Future<Output = T> + 'static + Send>>)
    where
       T: Send + 'static {
-      let task = Arc::new(Task{
-         future: Spinlock::new(future),
-         completed: AtomicBool::new(false),
-      });
+      let task = Arc::new(Task::new(future));
 
       self.add_task(task);
    }
 
-   /// Polls all pending tasks on global executor and remove completed tasks.
+   /// Polls all woken tasks on global executor and remove completed tasks.
    ///
    /// You may notice, that when all tasks will done, we keep them, although this is objectively
    /// useless. I think, finding our each completed task from [`Wake::wake_by_ref()`] at [`Executor::tasks`]
@@ -56,12 +50,19 @@ impl Executor {
       for _ in 0..self.tasks.len() {
          let task = self.tasks.pop_front().unwrap();
          if !task.is_done() {
-            task.update();
+            if task.is_woken() {
+               task.update();
+            }
             self.tasks.push_back(task);
          }
       }
    }
 
+   /// Checks that no uncompleted task is waiting to be polled.
+   pub fn is_idle(&self) -> bool {
+      return !self.tasks.iter().any(|task| !task.is_done() && task.is_woken());
+   }
+
    /// Removes completed task from [`Executor::tasks`].
    ///
    /// As you may also notice, same as [`Executor::run()`], but don't poll tasks.
@@ -83,7 +84,6 @@ use {
    core::{
       future::Future,
       pin::Pin,
-      sync::atomic::AtomicBool,
    },
    spinning_top::Spinlock,
    std_alloc::{
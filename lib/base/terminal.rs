@@ -76,15 +76,18 @@ impl log::Log for LockedWriter {
    }
 
    fn log(&self, record: &log::Record) {
-      if let Some(writer) = &self.writer {
-         let mut writer = writer.lock();
-         writeln!(writer, "{:5}: {}", record.level(), record.args()).unwrap();
-      }
-
-      if let Some(serial) = &self.serial {
-         let mut serial = serial.lock();
-         writeln!(serial, "{:5}: {}", record.level(), record.args()).unwrap();
-      }
+      // Interrupt handlers log too.
+      without_interrupts(|| {
+         if let Some(writer) = &self.writer {
+            let mut writer = writer.lock();
+            writeln!(writer, "{:5}: {}", record.level(), record.args()).unwrap();
+         }
+
+         if let Some(serial) = &self.serial {
+            let mut serial = serial.lock();
+            writeln!(serial, "{:5}: {}", record.level(), record.args()).unwrap();
+         }
+      });
    }
 
    fn flush(&self) {}
@@ -98,15 +101,17 @@ macro_rules! print {
    ($($args:tt)+) => ({
       use core::fmt::Write;
 
-      if let Some(writer) = &$crate::terminal::GLOBAL_WRITER.get().unwrap().writer {
-         let mut writer = writer.lock();
-         let _ = write!(writer, $($args)+).unwrap();
-      }
-
-      if let Some(serial) = &$crate::terminal::GLOBAL_WRITER.get().unwrap().serial {
-         let mut serial = serial.lock();
-         let _ = write!(serial, $($args)+).unwrap();
-      }
+      $crate::arch::without_interrupts(|| {
+         if let Some(writer) = &$crate::terminal::GLOBAL_WRITER.get().unwrap().writer {
+            let mut writer = writer.lock();
+            let _ = write!(writer, $($args)+).unwrap();
+         }
+
+         if let Some(serial) = &$crate::terminal::GLOBAL_WRITER.get().unwrap().serial {
+            let mut serial = serial.lock();
+            let _ = write!(serial, $($args)+).unwrap();
+         }
+      });
    });
 }
 
@@ -149,6 +154,7 @@ pub mod framebuffer;
 
 use {
    crate::{
+      arch::without_interrupts,
       uart::SerialPort,
       syscall::pio::Pio,
    },
@@ -0,0 +1,290 @@
+/// TSC frequency assumed until the kernel calibrates it, in kHz.
+const UNCALIBRATED_TSC_KHZ: u64 = 1_000_000;
+
+/// Measured TSC frequency in kHz, or zero before calibration.
+static TSC_KHZ: AtomicU64 = AtomicU64::new(0);
+
+/// TSC reading that [`Instant`]s count from.
+static TSC_ORIGIN: AtomicU64 = AtomicU64::new(0);
+
+/// Periodic timer interrupts taken since interrupts were enabled.
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Nanoseconds since the Unix epoch at the start of the monotonic clock, or zero if the wall clock
+/// has not been set.
+static UNIX_ORIGIN: AtomicU64 = AtomicU64::new(0);
+
+/// A point on the monotonic clock, counted in nanoseconds from when the clock was calibrated.
+///
+/// Only comparable with other `Instant`s taken on the same boot.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant {
+   nanos: u64,
+}
+
+impl Instant {
+   /// The current time.
+   pub fn now() -> Self {
+      let khz = match TSC_KHZ.load(Ordering::Relaxed) {
+         0 => UNCALIBRATED_TSC_KHZ,
+         khz => khz,
+      };
+
+      let cycles = counter().wrapping_sub(TSC_ORIGIN.load(Ordering::Relaxed));
+      return Instant{ nanos: (cycles as u128 * 1_000_000 / khz as u128) as u64 };
+   }
+
+   /// Time elapsed from `earlier` to `self`, or zero if `earlier` is later.
+   pub fn duration_since(&self, earlier: Instant) -> Duration {
+      return Duration::from_nanos(self.nanos.saturating_sub(earlier.nanos));
+   }
+
+   /// Time elapsed since `self`.
+   pub fn elapsed(&self) -> Duration {
+      return Instant::now().duration_since(*self);
+   }
+
+   /// `self` moved forward by `duration`, or `None` on overflow.
+   pub fn checked_add(&self, duration: Duration) -> Option<Instant> {
+      let nanos = u64::try_from(duration.as_nanos()).ok()?;
+      return Some(Instant{ nanos: self.nanos.checked_add(nanos)? });
+   }
+
+   /// `self` moved back by `duration`, or `None` if that is before the clock started.
+   pub fn checked_sub(&self, duration: Duration) -> Option<Instant> {
+      let nanos = u64::try_from(duration.as_nanos()).ok()?;
+      return Some(Instant{ nanos: self.nanos.checked_sub(nanos)? });
+   }
+
+   /// Time from the start of the clock to `self`.
+   pub fn since_start(&self) -> Duration {
+      return Duration::from_nanos(self.nanos);
+   }
+}
+
+impl Add<Duration> for Instant {
+   type Output = Instant;
+
+   fn add(self, duration: Duration) -> Instant {
+      return self.checked_add(duration).expect("overflow adding a duration to an instant");
+   }
+}
+
+impl AddAssign<Duration> for Instant {
+   fn add_assign(&mut self, duration: Duration) {
+      *self = *self + duration;
+   }
+}
+
+impl Sub<Duration> for Instant {
+   type Output = Instant;
+
+   fn sub(self, duration: Duration) -> Instant {
+      return self.checked_sub(duration).expect("overflow subtracting a duration from an instant");
+   }
+}
+
+impl Sub<Instant> for Instant {
+   type Output = Duration;
+
+   fn sub(self, earlier: Instant) -> Duration {
+      return self.duration_since(earlier);
+   }
+}
+
+/// A calendar date and time of day in UTC, to the second.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DateTime {
+   /// Full year, such as 2024.
+   pub year: u16,
+
+   /// Month, from 1 to 12.
+   pub month: u8,
+
+   /// Day of the month, from 1.
+   pub day: u8,
+
+   /// Hour, from 0 to 23.
+   pub hour: u8,
+
+   /// Minute, from 0 to 59.
+   pub minute: u8,
+
+   /// Second, from 0 to 59.
+   pub second: u8,
+}
+
+impl DateTime {
+   /// The date and time `seconds` after the Unix epoch.
+   pub fn from_unix(seconds: u64) -> Self {
+      let (days, time) = (seconds / 86400, seconds % 86400);
+
+      // Civil-from-days, counting in 400-year eras that start on 1 March 0000.
+      let days = days + 719468;
+      let era = days / 146097;
+      let day_of_era = days % 146097;
+      let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+      let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+      let month_from_march = (5 * day_of_year + 2) / 153;
+      let month = if month_from_march < 10 { month_from_march + 3 } else { month_from_march - 9 };
+      let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+      return DateTime{
+         year: year as u16,
+         month: month as u8,
+         day: (day_of_year - (153 * month_from_march + 2) / 5 + 1) as u8,
+         hour: (time / 3600) as u8,
+         minute: (time / 60 % 60) as u8,
+         second: (time % 60) as u8,
+      };
+   }
+
+   /// Seconds from the Unix epoch to `self`, which must not be earlier.
+   pub fn to_unix(&self) -> u64 {
+      // Days-from-civil, the inverse of the calculation in `from_unix`.
+      let month = self.month as u64;
+      let year = self.year as u64 - if month <= 2 { 1 } else { 0 };
+      let era = year / 400;
+      let year_of_era = year % 400;
+      let day_of_year = (153 * if month > 2 { month - 3 } else { month + 9 } + 2) / 5 + self.day as u64 - 1;
+      let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+      let days = era * 146097 + day_of_era - 719468;
+
+      return days * 86400 + self.hour as u64 * 3600 + self.minute as u64 * 60 + self.second as u64;
+   }
+
+   /// Returns `true` if every field is in range, leap days included, and the date is not before
+   /// the Unix epoch.
+   pub fn is_valid(&self) -> bool {
+      let leap = self.year % 4 == 0 && (self.year % 100 != 0 || self.year % 400 == 0);
+      let days = match self.month {
+         1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+         4 | 6 | 9 | 11 => 30,
+         2 if leap => 29,
+         2 => 28,
+         _ => return false,
+      };
+
+      return self.year >= 1970
+         && (1..=days).contains(&self.day)
+         && self.hour < 24
+         && self.minute < 60
+         && self.second < 60;
+   }
+}
+
+impl Display for DateTime {
+   /// Formats as ISO 8601, such as `2024-02-29T13:05:00Z`.
+   fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+      return write!(
+         f,
+         "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+         self.year,
+         self.month,
+         self.day,
+         self.hour,
+         self.minute,
+         self.second,
+      );
+   }
+}
+
+/// Sets the wall clock to `now`, which from then on advances with the monotonic clock.
+pub fn set_wall_clock(now: DateTime) {
+   let since_start = Instant::now().since_start().as_nanos() as u64;
+   UNIX_ORIGIN.store((now.to_unix() * 1_000_000_000).saturating_sub(since_start), Ordering::Relaxed);
+}
+
+/// The current date and time in UTC, or `None` before the wall clock has been set.
+pub fn wall_clock() -> Option<DateTime> {
+   return unix_time().map(|time| DateTime::from_unix(time.as_secs()));
+}
+
+/// Time since the Unix epoch, or `None` before the wall clock has been set.
+pub fn unix_time() -> Option<Duration> {
+   return match UNIX_ORIGIN.load(Ordering::Relaxed) {
+      0 => None,
+      origin => Some(Duration::from_nanos(origin) + uptime()),
+   };
+}
+
+/// Sets the TSC frequency the clock runs from, and starts the clock at zero.
+///
+/// Called once by the kernel after calibrating the TSC. Until then the TSC is assumed to run at
+/// 1 GHz, so early timings are only rough.
+pub fn set_tsc_frequency(khz: u64) {
+   TSC_ORIGIN.store(counter(), Ordering::Relaxed);
+   TSC_KHZ.store(khz, Ordering::Relaxed);
+}
+
+/// The calibrated TSC frequency in kHz, or `None` before calibration.
+pub fn tsc_frequency() -> Option<u64> {
+   return match TSC_KHZ.load(Ordering::Relaxed) {
+      0 => None,
+      khz => Some(khz),
+   };
+}
+
+/// Time since the clock started.
+pub fn uptime() -> Duration {
+   return Instant::now().since_start();
+}
+
+/// Spins for at least `duration`, without giving up the CPU.
+pub fn busy_wait(duration: Duration) {
+   let start = Instant::now();
+   while start.elapsed() < duration {
+      spin_loop();
+   }
+}
+
+/// Waits for at least `duration`, halting between timer interrupts.
+///
+/// With interrupts disabled nothing would wake a halted CPU, so this falls back to
+/// [`busy_wait`].
+pub fn sleep(duration: Duration) {
+   #[cfg(target_arch = "x86_64")]
+   if x86_64::instructions::interrupts::are_enabled() {
+      let start = Instant::now();
+      while start.elapsed() < duration {
+         x86_64::instructions::hlt();
+      }
+      return;
+   }
+
+   busy_wait(duration);
+}
+
+/// Counts a periodic timer interrupt. Called from the timer interrupt handler.
+pub fn tick() {
+   TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Periodic timer interrupts taken so far.
+pub fn ticks() -> u64 {
+   return TICKS.load(Ordering::Relaxed);
+}
+
+fn counter() -> u64 {
+   #[cfg(target_arch = "x86_64")]
+   return unsafe{ rdtsc() };
+
+   #[cfg(not(target_arch = "x86_64"))]
+   return 0;
+}
+
+// IMPORTS //
+
+#[cfg(target_arch = "x86_64")]
+use x86::time::rdtsc;
+
+use core::{
+   fmt::{Display, Formatter, Result as FmtResult},
+   hint::spin_loop,
+   ops::{Add, AddAssign, Sub},
+   sync::atomic::{AtomicU64, Ordering},
+};
+
+// EXPORTS //
+
+pub use core::time::Duration;
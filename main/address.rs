@@ -14,3 +14,14 @@ pub const USER_SPACE_START: usize = 0x20000000000usize;
 
 /// Initial value of the stack pointer.
 pub const USER_STACK: usize = USER_SPACE_START + 0x800000000usize;
+
+/// Fixed virtual address the bootloader maps the framebuffer at.
+pub const FRAMEBUFFER_START: usize = 0x8000000;
+
+// MODULES //
+
+/// Kernel virtual address space manager.
+///
+/// Subsystems ask [`KERNEL_SPACE`](space::KERNEL_SPACE) for named ranges of address space instead
+/// of picking fixed addresses.
+pub mod space;
@@ -0,0 +1,681 @@
+/// The kernel's virtual address space.
+///
+/// Lock order: `KERNEL_SPACE`, then [`MAPPER`](crate::memory::MAPPER), then
+/// [`FRAME_ALLOCATOR`](crate::memory::FRAME_ALLOCATOR). Nothing in here allocates from the heap,
+/// so the heap itself may ask for address space while its own lock is held.
+pub static KERNEL_SPACE: Mutex<AddressSpace> = Mutex::new(AddressSpace::new());
+
+/// Maximum number of regions an address space can track.
+pub const MAX_REGIONS: usize = 64;
+
+/// Finds an unused higher-half level 4 entry and hands its 512 GiB to the kernel for dynamic
+/// allocations, then records the regions the bootloader and the kernel already occupy.
+pub fn initialise(physical_offset: VirtAddr, physical_size: u64, framebuffer_size: u64) {
+   let mut space = KERNEL_SPACE.lock();
+
+   memory::with_kernel_memory(|mapper, _| {
+      let l4table = mapper.level_4_table();
+      let index = (256..511)
+         .find(|&index| l4table[index].is_unused())
+         .expect("no free level 4 entry left for the kernel address space");
+
+      let start = VirtAddr::new_truncate((index as u64) << 39);
+      space.window = (start, start + (1u64 << 39));
+   });
+
+   space.reserve_at("physical-memory", physical_offset, physical_size, Backing::Fixed)
+      .expect("physical memory window overlaps an existing region");
+
+   space.reserve_at("framebuffer", VirtAddr::new(FRAMEBUFFER_START as u64), framebuffer_size, Backing::Fixed)
+      .expect("framebuffer overlaps an existing region");
+
+   log::info!("Kernel address space window: {:?}..{:?}", space.window.0, space.window.1);
+}
+
+/// Reserves `size` bytes of kernel address space and backs them with fresh frames.
+pub fn allocate(name: &'static str, size: u64, flags: PageTableFlags) -> Result<VirtAddr, RegionError> {
+   let mut space = KERNEL_SPACE.lock();
+   let start = space.reserve(name, size, Size4KiB::SIZE)?;
+
+   return match memory::with_kernel_memory(|mapper, frames| space.map(start, flags, mapper, frames)) {
+      Ok(()) => Ok(start),
+      Err(error) => {
+         space.release(start)?;
+         Err(error)
+      }
+   };
+}
+
+/// Reserves `size` bytes of kernel address space whose pages are only backed when first touched.
+pub fn allocate_lazy(name: &'static str, size: u64, flags: PageTableFlags) -> Result<VirtAddr, RegionError> {
+   let mut space = KERNEL_SPACE.lock();
+   let start = space.reserve(name, size, Size4KiB::SIZE)?;
+   space.map_lazy(start, flags)?;
+
+   return Ok(start);
+}
+
+/// Returns the name of the stack whose guard page contains `address`, if any.
+///
+/// Used by the fault handlers, so like [`resolve_fault`] it never waits on the lock.
+pub fn guard_owner(address: VirtAddr) -> Option<&'static str> {
+   let space = KERNEL_SPACE.try_lock()?;
+
+   return match space.find(address) {
+      Some(region) if region.backing == Backing::Guard => Some(region.name),
+      _ => None,
+   };
+}
+
+/// Resolves a not-present fault at `address` by backing the page if it lies in a lazily-backed
+/// region.
+///
+/// This runs from the page-fault handler, so it never waits on a lock: if the address space, the
+/// mapper or the frame allocator are already held, the fault cannot be resolved.
+pub fn resolve_fault(address: VirtAddr) -> Result<(), FaultError> {
+   let space = KERNEL_SPACE.try_lock().ok_or(FaultError::Busy)?;
+   let region = *space.find(address).ok_or(FaultError::Unmapped)?;
+
+   match region.backing {
+      Backing::Lazy => {}
+      Backing::Guard => return Err(FaultError::Guard(region.name)),
+      _ => return Err(FaultError::NotLazy(region)),
+   }
+
+   let mut mapper = memory::MAPPER.try_lock().ok_or(FaultError::Busy)?;
+   let mut frames = memory::FRAME_ALLOCATOR.try_lock().ok_or(FaultError::Busy)?;
+   let mapper = mapper.as_mut().ok_or(FaultError::Busy)?;
+   let frames = frames.as_mut().ok_or(FaultError::Busy)?;
+
+   let page = Page::<Size4KiB>::containing_address(address);
+   let frame = frames.allocate_frame().ok_or(FaultError::OutOfMemory)?;
+
+   unsafe {
+      // Hand out zeroed memory, never whatever the frame held before.
+      let contents: *mut u8 = (mapper.phys_offset() + frame.start_address().as_u64()).as_mut_ptr();
+      contents.write_bytes(0, Size4KiB::SIZE as usize);
+
+      match mapper.map_to(page, frame, region.flags, frames) {
+         Ok(flush) => flush.flush(),
+         Err(error) => {
+            frames.deallocate_frame(frame);
+            return Err(FaultError::Map(error));
+         }
+      }
+   }
+
+   return Ok(());
+}
+
+/// Reserves kernel address space for `size` bytes of physical memory starting at `physical` and
+/// maps it with `flags`.
+pub fn map_physical(
+   name: &'static str,
+   physical: PhysAddr,
+   size: u64,
+   flags: PageTableFlags,
+) -> Result<VirtAddr, RegionError> {
+   let base = physical.align_down(Size4KiB::SIZE);
+   let size = align_up(size + (physical - base), Size4KiB::SIZE);
+
+   let mut space = KERNEL_SPACE.lock();
+   let start = space.reserve(name, size, Size4KiB::SIZE)?;
+
+   return match memory::with_kernel_memory(|mapper, frames| {
+      space.map_physical(start, base, flags, mapper, frames)
+   }) {
+      Ok(()) => Ok(start + (physical - base)),
+      Err(error) => {
+         space.release(start)?;
+         Err(error)
+      }
+   };
+}
+
+/// Unmaps and releases the kernel region starting at `start`.
+pub fn free(start: VirtAddr) -> Result<(), RegionError> {
+   let mut space = KERNEL_SPACE.lock();
+   memory::with_kernel_memory(|mapper, frames| space.unmap(start, mapper, frames))?;
+   space.release(start)?;
+
+   return Ok(());
+}
+
+/// What a region is backed by once it is mapped.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Backing {
+   /// Address space only; nothing is mapped.
+   Reserved,
+
+   /// Frames owned by the region, returned to the frame allocator on unmap.
+   Anonymous,
+
+   /// A fixed physical range, such as MMIO. Frames are never freed.
+   Physical(PhysAddr),
+
+   /// Mapped by the bootloader before the kernel took over. Never unmapped.
+   Fixed,
+
+   /// Frames owned by the region, allocated by the page-fault handler when a page is first
+   /// touched. Parts of the region may also be committed up front.
+   Lazy,
+
+   /// Deliberately left unmapped so that running off the end of the region above it faults.
+   Guard,
+}
+
+/// A named range of virtual address space.
+#[derive(Copy, Clone, Debug)]
+pub struct Region {
+   /// A short name for the layout listing.
+   pub name: &'static str,
+
+   /// First byte of the region.
+   pub start: VirtAddr,
+
+   /// Size of the region in bytes.
+   pub size: u64,
+
+   /// The flags the region is mapped with.
+   pub flags: PageTableFlags,
+
+   /// What the region is backed by.
+   pub backing: Backing,
+}
+
+impl Region {
+   /// One past the last byte of the region.
+   pub fn end(&self) -> VirtAddr {
+      return self.start + self.size;
+   }
+
+   /// Returns `true` if `address` lies within the region.
+   pub fn contains(&self, address: VirtAddr) -> bool {
+      return self.start <= address && address < self.end();
+   }
+
+   fn overlaps(&self, start: VirtAddr, size: u64) -> bool {
+      return self.start < start + size && start < self.end();
+   }
+}
+
+/// An error returned by [`AddressSpace`] operations.
+#[derive(Debug)]
+pub enum RegionError {
+   /// The range is empty or not page-aligned.
+   Unaligned,
+
+   /// The range overlaps the named region.
+   Overlap(&'static str),
+
+   /// No gap in the dynamic window is large enough.
+   OutOfSpace,
+
+   /// The region table is full.
+   TooManyRegions,
+
+   /// No region starts at the given address.
+   NotFound,
+
+   /// The region is already mapped.
+   AlreadyMapped,
+
+   /// The page tables could not be updated.
+   Map(MapToError<Size4KiB>),
+}
+
+impl Display for RegionError {
+   fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+      match self {
+         RegionError::Unaligned => write!(f, "region is empty or not page-aligned"),
+         RegionError::Overlap(name) => write!(f, "region overlaps `{}`", name),
+         RegionError::OutOfSpace => write!(f, "no free address space left"),
+         RegionError::TooManyRegions => write!(f, "too many regions"),
+         RegionError::NotFound => write!(f, "no such region"),
+         RegionError::AlreadyMapped => write!(f, "region is already mapped"),
+         RegionError::Map(error) => write!(f, "failed to map region: {:?}", error),
+      }
+   }
+}
+
+/// Why a page fault could not be resolved.
+#[derive(Debug)]
+pub enum FaultError {
+   /// The address is not in any region.
+   Unmapped,
+
+   /// The address is in a region that is not lazily backed.
+   NotLazy(Region),
+
+   /// The address is in the guard page below the named stack: the stack overflowed.
+   Guard(&'static str),
+
+   /// The address space, mapper or frame allocator was locked when the fault happened.
+   Busy,
+
+   /// No frame was left to back the page.
+   OutOfMemory,
+
+   /// The page tables could not be updated.
+   Map(MapToError<Size4KiB>),
+}
+
+impl Display for FaultError {
+   fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+      match self {
+         FaultError::Unmapped => write!(f, "address is outside every region"),
+         FaultError::NotLazy(region) => {
+            write!(f, "address is in `{}` ({:?}), which is not lazily backed", region.name, region.backing)
+         }
+         FaultError::Guard(name) => write!(f, "address is in the guard page of `{}`: stack overflow", name),
+         FaultError::Busy => write!(f, "memory manager was locked at the time of the fault"),
+         FaultError::OutOfMemory => write!(f, "out of physical frames"),
+         FaultError::Map(error) => write!(f, "failed to map page: {:?}", error),
+      }
+   }
+}
+
+impl From<MapToError<Size4KiB>> for RegionError {
+   fn from(value: MapToError<Size4KiB>) -> Self {
+      return RegionError::Map(value);
+   }
+}
+
+/// A set of non-overlapping named regions, kept sorted by start address.
+///
+/// Dynamic reservations are placed first-fit inside `window`; fixed reservations may lie anywhere.
+#[derive(Clone)]
+pub struct AddressSpace {
+   /// The range dynamic reservations are carved from.
+   pub window: (VirtAddr, VirtAddr),
+
+   regions: [Option<Region>; MAX_REGIONS],
+   count: usize,
+}
+
+impl AddressSpace {
+   /// Creates an empty address space with no dynamic window.
+   pub const fn new() -> Self {
+      return AddressSpace{
+         window: (VirtAddr::zero(), VirtAddr::zero()),
+         regions: [None; MAX_REGIONS],
+         count: 0,
+      };
+   }
+
+   /// Iterates over the regions in address order.
+   pub fn regions(&self) -> impl Iterator<Item = &Region> {
+      return self.regions[..self.count].iter().flatten();
+   }
+
+   /// Finds the region containing `address`.
+   pub fn find(&self, address: VirtAddr) -> Option<&Region> {
+      return self.regions().find(|region| region.contains(address));
+   }
+
+   /// Reserves `size` bytes aligned to `align` somewhere in the dynamic window.
+   pub fn reserve(&mut self, name: &'static str, size: u64, align: u64) -> Result<VirtAddr, RegionError> {
+      if size == 0 || size % Size4KiB::SIZE != 0 {
+         return Err(RegionError::Unaligned);
+      }
+
+      let (windowStart, windowEnd) = self.window;
+      let mut candidate = windowStart.align_up(max(align, Size4KiB::SIZE));
+
+      for region in self.regions() {
+         if region.end() <= candidate {
+            continue;
+         }
+
+         if region.start >= candidate + size {
+            break;
+         }
+
+         candidate = region.end().align_up(max(align, Size4KiB::SIZE));
+      }
+
+      if candidate + size > windowEnd {
+         return Err(RegionError::OutOfSpace);
+      }
+
+      self.insert(Region{
+         name,
+         start: candidate,
+         size,
+         flags: PageTableFlags::empty(),
+         backing: Backing::Reserved,
+      })?;
+
+      return Ok(candidate);
+   }
+
+   /// Reserves `size` bytes in the dynamic window with an unmapped guard page directly below
+   /// them, returning the start of the usable part. The guard shares the region's name, so a
+   /// fault in it can be traced back to its owner.
+   pub fn reserve_guarded(&mut self, name: &'static str, size: u64) -> Result<VirtAddr, RegionError> {
+      if self.count + 2 > MAX_REGIONS {
+         return Err(RegionError::TooManyRegions);
+      }
+
+      let guard = self.reserve(name, size + Size4KiB::SIZE, Size4KiB::SIZE)?;
+      let index = self.index_of(guard)?;
+
+      let region = self.regions[index].as_mut().unwrap();
+      region.size = Size4KiB::SIZE;
+      region.backing = Backing::Guard;
+
+      let start = guard + Size4KiB::SIZE;
+      self.insert(Region{
+         name,
+         start,
+         size,
+         flags: PageTableFlags::empty(),
+         backing: Backing::Reserved,
+      })?;
+
+      return Ok(start);
+   }
+
+   /// Records a region at a fixed address, rejecting it if it overlaps an existing one.
+   pub fn reserve_at(
+      &mut self,
+      name: &'static str,
+      start: VirtAddr,
+      size: u64,
+      backing: Backing,
+   ) -> Result<VirtAddr, RegionError> {
+      if size == 0 || !start.is_aligned(Size4KiB::SIZE) {
+         return Err(RegionError::Unaligned);
+      }
+
+      self.insert(Region{
+         name,
+         start,
+         size: align_up(size, Size4KiB::SIZE),
+         flags: PageTableFlags::empty(),
+         backing,
+      })?;
+
+      return Ok(start);
+   }
+
+   /// Forgets the region starting at `start`. It must already be unmapped.
+   pub fn release(&mut self, start: VirtAddr) -> Result<Region, RegionError> {
+      let index = self.index_of(start)?;
+      let region = self.regions[index].take().unwrap();
+
+      self.regions[index..self.count].rotate_left(1);
+      self.count -= 1;
+
+      return Ok(region);
+   }
+
+   /// Backs the reserved region starting at `start` with freshly allocated frames.
+   pub fn map(
+      &mut self,
+      start: VirtAddr,
+      flags: PageTableFlags,
+      mapper: &mut OffsetPageTable<'static>,
+      frames: &mut SystemFrameAllocator,
+   ) -> Result<(), RegionError> {
+      let region = self.reserved(start)?;
+      memory::map_anonymous(mapper, region.start, region.size, flags, frames)?;
+
+      self.update(start, flags, Backing::Anonymous);
+      return Ok(());
+   }
+
+   /// Backs `size` bytes at `offset` into the region starting at `start` with fresh frames,
+   /// leaving the rest of the region as it is. Used by regions that are mapped piecemeal, such
+   /// as the heap.
+   pub fn commit(
+      &mut self,
+      start: VirtAddr,
+      offset: u64,
+      size: u64,
+      flags: PageTableFlags,
+      mapper: &mut OffsetPageTable<'static>,
+      frames: &mut SystemFrameAllocator,
+   ) -> Result<(), RegionError> {
+      let region = self.regions[self.index_of(start)?].unwrap();
+
+      let backing = match region.backing {
+         Backing::Reserved | Backing::Anonymous => Backing::Anonymous,
+         Backing::Lazy => Backing::Lazy,
+         _ => return Err(RegionError::AlreadyMapped),
+      };
+
+      if offset % Size4KiB::SIZE != 0 || size == 0 || offset + size > region.size {
+         return Err(RegionError::Unaligned);
+      }
+
+      memory::map_anonymous(mapper, region.start + offset, size, flags, frames)?;
+
+      self.update(start, flags, backing);
+      return Ok(());
+   }
+
+   /// Marks the reserved region starting at `start` to be backed page by page on first touch.
+   pub fn map_lazy(&mut self, start: VirtAddr, flags: PageTableFlags) -> Result<(), RegionError> {
+      self.reserved(start)?;
+      self.update(start, flags, Backing::Lazy);
+
+      return Ok(());
+   }
+
+   /// Maps the reserved region starting at `start` onto the physical range at `physical`.
+   pub fn map_physical(
+      &mut self,
+      start: VirtAddr,
+      physical: PhysAddr,
+      flags: PageTableFlags,
+      mapper: &mut OffsetPageTable<'static>,
+      frames: &mut SystemFrameAllocator,
+   ) -> Result<(), RegionError> {
+      let region = self.reserved(start)?;
+      unsafe {
+         memory::map_physical_range(mapper, region.start, physical, region.size, flags, frames)?;
+      }
+
+      self.update(start, flags, Backing::Physical(physical));
+      return Ok(());
+   }
+
+   /// Unmaps the region starting at `start`, returning anonymous frames to the allocator. The
+   /// address space stays reserved until [`release`](AddressSpace::release) is called.
+   pub fn unmap(
+      &mut self,
+      start: VirtAddr,
+      mapper: &mut OffsetPageTable<'static>,
+      frames: &mut SystemFrameAllocator,
+   ) -> Result<(), RegionError> {
+      let region = self.regions[self.index_of(start)?].unwrap();
+      let owned = matches!(region.backing, Backing::Anonymous | Backing::Lazy);
+
+      if region.backing == Backing::Fixed {
+         return Err(RegionError::AlreadyMapped);
+      }
+
+      let mut address = region.start;
+      while address < region.end() {
+         address += match mapper.translate(address) {
+            TranslateResult::Mapped{ frame: MappedFrame::Size4KiB(frame), .. } => {
+               if let Ok((_, flush)) = mapper.unmap(Page::<Size4KiB>::containing_address(address)) {
+                  flush.flush();
+                  if owned {
+                     unsafe{ frames.deallocate_frame(frame) };
+                  }
+               }
+
+               Size4KiB::SIZE
+            }
+
+            TranslateResult::Mapped{ frame: MappedFrame::Size2MiB(frame), .. } => {
+               if let Ok((_, flush)) = mapper.unmap(Page::<Size2MiB>::containing_address(address)) {
+                  flush.flush();
+                  if owned {
+                     let first = PhysFrame::containing_address(frame.start_address());
+                     unsafe{ frames.deallocate_contiguous(first, 512) };
+                  }
+               }
+
+               Size2MiB::SIZE
+            }
+
+            TranslateResult::Mapped{ frame: MappedFrame::Size1GiB(_), .. } => {
+               // Gigantic pages only ever back physical windows, which own no frames.
+               if let Ok((_, flush)) = mapper.unmap(Page::<Size1GiB>::containing_address(address)) {
+                  flush.flush();
+               }
+
+               Size1GiB::SIZE
+            }
+
+            _ => Size4KiB::SIZE,
+         };
+      }
+
+      self.update(start, PageTableFlags::empty(), Backing::Reserved);
+      return Ok(());
+   }
+
+   /// Changes the page flags of every mapped page in the region starting at `start`.
+   pub fn protect(
+      &mut self,
+      start: VirtAddr,
+      flags: PageTableFlags,
+      mapper: &mut OffsetPageTable<'static>,
+   ) -> Result<(), RegionError> {
+      let region = self.regions[self.index_of(start)?].unwrap();
+
+      let mut address = region.start;
+      while address < region.end() {
+         address += match mapper.translate(address) {
+            TranslateResult::Mapped{ frame: MappedFrame::Size4KiB(_), .. } => {
+               let page = Page::<Size4KiB>::containing_address(address);
+               if let Ok(flush) = unsafe{ mapper.update_flags(page, flags) } {
+                  flush.flush();
+               }
+
+               Size4KiB::SIZE
+            }
+
+            TranslateResult::Mapped{ frame: MappedFrame::Size2MiB(_), .. } => {
+               let page = Page::<Size2MiB>::containing_address(address);
+               if let Ok(flush) = unsafe{ mapper.update_flags(page, flags | PageTableFlags::HUGE_PAGE) } {
+                  flush.flush();
+               }
+
+               Size2MiB::SIZE
+            }
+
+            TranslateResult::Mapped{ frame: MappedFrame::Size1GiB(_), .. } => {
+               let page = Page::<Size1GiB>::containing_address(address);
+               if let Ok(flush) = unsafe{ mapper.update_flags(page, flags | PageTableFlags::HUGE_PAGE) } {
+                  flush.flush();
+               }
+
+               Size1GiB::SIZE
+            }
+
+            _ => Size4KiB::SIZE,
+         };
+      }
+
+      let backing = region.backing;
+      self.update(start, flags, backing);
+      return Ok(());
+   }
+
+   fn reserved(&self, start: VirtAddr) -> Result<Region, RegionError> {
+      let region = self.regions[self.index_of(start)?].unwrap();
+
+      return match region.backing {
+         Backing::Reserved => Ok(region),
+         _ => Err(RegionError::AlreadyMapped),
+      };
+   }
+
+   fn update(&mut self, start: VirtAddr, flags: PageTableFlags, backing: Backing) {
+      if let Ok(index) = self.index_of(start) {
+         let region = self.regions[index].as_mut().unwrap();
+         region.flags = flags;
+         region.backing = backing;
+      }
+   }
+
+   fn index_of(&self, start: VirtAddr) -> Result<usize, RegionError> {
+      return self.regions[..self.count]
+         .iter()
+         .position(|region| region.map_or(false, |r| r.start == start))
+         .ok_or(RegionError::NotFound);
+   }
+
+   fn insert(&mut self, region: Region) -> Result<(), RegionError> {
+      if let Some(other) = self.regions().find(|r| r.overlaps(region.start, region.size)) {
+         return Err(RegionError::Overlap(other.name));
+      }
+
+      if self.count == MAX_REGIONS {
+         return Err(RegionError::TooManyRegions);
+      }
+
+      let index = self.regions().take_while(|r| r.start < region.start).count();
+
+      self.regions[index..self.count + 1].rotate_right(1);
+      self.regions[index] = Some(region);
+      self.count += 1;
+
+      return Ok(());
+   }
+}
+
+impl Display for AddressSpace {
+   fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+      for region in self.regions() {
+         writeln!(
+            f,
+            "{:#018x}-{:#018x} {:>10} KiB {:<16} {:?} {:?}",
+            region.start.as_u64(),
+            region.end().as_u64(),
+            region.size / 1024,
+            region.name,
+            region.backing,
+            region.flags,
+         )?;
+      }
+
+      return Ok(());
+   }
+}
+
+// IMPORTS //
+
+use {
+   super::FRAMEBUFFER_START,
+   crate::memory::{self, align_up, SystemFrameAllocator},
+   base::log,
+   core::{
+      cmp::max,
+      fmt::{self, Display, Formatter},
+   },
+   spin::Mutex,
+   x86_64::{
+      structures::paging::{
+         FrameAllocator,
+         FrameDeallocator,
+         OffsetPageTable,
+         Page,
+         PageSize,
+         PageTableFlags,
+         PhysFrame,
+         Size1GiB,
+         Size2MiB,
+         Size4KiB,
+         Mapper,
+         Translate,
+         mapper::{MapToError, MappedFrame, TranslateResult},
+      },
+      PhysAddr, VirtAddr,
+   },
+};
@@ -1,4 +1,437 @@
-pub fn initialise(rsdp: u64) {
+/// Vector the local APIC delivers spurious interrupts on. The low four bits must be set on older
+/// APICs, and nothing needs acknowledging.
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+/// Vector of the local APIC timer. It stands in for the PIT, so it takes over IRQ 0's vector and
+/// handler.
+pub const TIMER_VECTOR: u8 = Irq::Timer.vector();
+
+/// Most IO-APICs the kernel will drive.
+pub const MAX_IO_APICS: usize = 8;
+
+/// Flags the APIC register windows are mapped with: uncached, since every access has side effects.
+const MMIO_FLAGS: PageTableFlags = PageTableFlags::PRESENT
+   .union(PageTableFlags::WRITABLE)
+   .union(PageTableFlags::NO_EXECUTE)
+   .union(PageTableFlags::NO_CACHE)
+   .union(PageTableFlags::WRITE_THROUGH);
+
+// Local APIC registers, as offsets into its register window.
+const LAPIC_ID: usize = 0x20;
+const LAPIC_TASK_PRIORITY: usize = 0x80;
+const LAPIC_EOI: usize = 0xB0;
+const LAPIC_SPURIOUS: usize = 0xF0;
+const LAPIC_ERROR_STATUS: usize = 0x280;
+const LAPIC_ICR_LOW: usize = 0x300;
+const LAPIC_ICR_HIGH: usize = 0x310;
+const LAPIC_LVT_TIMER: usize = 0x320;
+const LAPIC_LVT_LINT0: usize = 0x350;
+const LAPIC_LVT_LINT1: usize = 0x360;
+const LAPIC_LVT_ERROR: usize = 0x370;
+const LAPIC_TIMER_INITIAL: usize = 0x380;
+const LAPIC_TIMER_CURRENT: usize = 0x390;
+const LAPIC_TIMER_DIVIDE: usize = 0x3E0;
+
+const LAPIC_SOFTWARE_ENABLE: u32 = 1 << 8;
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_NMI: u32 = 0b100 << 8;
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+const TIMER_DIVIDE_BY_16: u32 = 0b0011;
+
+const ICR_INIT: u32 = 0b101 << 8;
+const ICR_STARTUP: u32 = 0b110 << 8;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+const ICR_ASSERT: u32 = 1 << 14;
+
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+// IO-APIC registers, selected through the index register.
+const IOAPIC_VERSION: u32 = 0x01;
+const IOAPIC_REDIRECTION: u32 = 0x10;
+
+const REDIRECTION_ACTIVE_LOW: u64 = 1 << 13;
+const REDIRECTION_LEVEL: u64 = 1 << 15;
+const REDIRECTION_MASKED: u64 = 1 << 16;
+
+/// Virtual address of this CPU's local APIC registers, or zero while the legacy PICs are in use.
+static LOCAL_APIC: AtomicU64 = AtomicU64::new(0);
+
+/// Local APIC timer ticks per millisecond with the divider at 16.
+static TIMER_TICKS_PER_MS: AtomicU32 = AtomicU32::new(0);
+
+static ROUTING: Mutex<Routing> = Mutex::new(Routing::new());
+
+/// Why the APICs could not be brought up.
+#[derive(Debug)]
+pub enum ApicError {
+   /// The MADT or the CPU describes no APIC, or the ACPI tables were never read.
+   NoApic,
+
+   /// A register window could not be mapped.
+   Region(RegionError),
+
+   /// The MADT lists more IO-APICs than [`MAX_IO_APICS`].
+   TooManyIoApics,
+
+   /// No IO-APIC handles the global system interrupt.
+   NoIoApic(u32),
+}
+
+impl Display for ApicError {
+   fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+      match self {
+         ApicError::NoApic => write!(f, "no APIC described by the MADT or the CPU"),
+         ApicError::Region(error) => write!(f, "failed to map APIC registers: {}", error),
+         ApicError::TooManyIoApics => write!(f, "more than {} IO-APICs", MAX_IO_APICS),
+         ApicError::NoIoApic(gsi) => write!(f, "no IO-APIC handles GSI {}", gsi),
+      }
+   }
+}
+
+impl From<RegionError> for ApicError {
+   fn from(value: RegionError) -> Self {
+      return ApicError::Region(value);
+   }
+}
+
+/// How an ISA IRQ reaches an IO-APIC, after any interrupt source override.
+#[derive(Copy, Clone, Debug)]
+struct IsaRoute {
+   gsi: u32,
+   active_low: bool,
+   level: bool,
+}
+
+/// An IO-APIC and the global system interrupts it handles.
+#[derive(Copy, Clone, Debug)]
+struct IoApic {
+   id: u8,
+   base: VirtAddr,
+   gsi_base: u32,
+   entries: u32,
+}
+
+impl IoApic {
+   unsafe fn read(&self, register: u32) -> u32 {
+      let select: *mut u32 = self.base.as_mut_ptr();
+      select.write_volatile(register);
+      return select.add(4).read_volatile();
+   }
+
+   unsafe fn write(&self, register: u32, value: u32) {
+      let select: *mut u32 = self.base.as_mut_ptr();
+      select.write_volatile(register);
+      select.add(4).write_volatile(value);
+   }
+
+   fn handles(&self, gsi: u32) -> bool {
+      return self.gsi_base <= gsi && gsi < self.gsi_base + self.entries;
+   }
+
+   /// Writes the redirection entry for `gsi`, high half first so that an entry being unmasked
+   /// never fires at a stale destination.
+   unsafe fn set_entry(&self, gsi: u32, entry: u64) {
+      let register = IOAPIC_REDIRECTION + (gsi - self.gsi_base) * 2;
+      self.write(register + 1, (entry >> 32) as u32);
+      self.write(register, entry as u32);
+   }
+}
+
+/// The IO-APICs and the ISA IRQ routing read from the MADT.
+struct Routing {
+   io_apics: [Option<IoApic>; MAX_IO_APICS],
+   isa: [IsaRoute; 16],
+}
+
+impl Routing {
+   const fn new() -> Self {
+      let mut isa = [IsaRoute{ gsi: 0, active_low: false, level: false }; 16];
+      let mut irq = 0;
+      while irq < 16 {
+         isa[irq].gsi = irq as u32;
+         irq += 1;
+      }
+
+      return Routing{
+         io_apics: [None; MAX_IO_APICS],
+         isa,
+      };
+   }
+
+   fn io_apic(&self, gsi: u32) -> Result<&IoApic, ApicError> {
+      return self.io_apics.iter()
+         .flatten()
+         .find(|io_apic| io_apic.handles(gsi))
+         .ok_or(ApicError::NoIoApic(gsi));
+   }
+}
+
+/// Switches interrupt delivery from the legacy PICs to the APICs described by the MADT.
+///
+/// Masks the PICs, enables this CPU's local APIC, routes the keyboard through the IO-APIC with any
+/// interrupt source override applied, and replaces the PIT with the local APIC timer, calibrated
+/// against PIT channel 2. Must run with interrupts disabled, after [`crate::platform::initialise`]; on
+/// error the PICs stay in charge.
+pub fn initialise() -> Result<(), ApicError> {
+   let platform = PLATFORM.lock();
+   let platform = platform.as_ref().ok_or(ApicError::NoApic)?;
+   let apic = platform.interrupts.as_ref().ok_or(ApicError::NoApic)?;
+
+   let features = CpuId::new().get_feature_info();
+   let bsp = match features {
+      Some(features) if features.has_apic() && !apic.io_apics.is_empty() => features.initial_local_apic_id() as u32,
+      _ => return Err(ApicError::NoApic),
+   };
+
+   if apic.io_apics.len() > MAX_IO_APICS {
+      return Err(ApicError::TooManyIoApics);
+   }
+
+   let mut routing = ROUTING.lock();
+   for (slot, io_apic) in routing.io_apics.iter_mut().zip(apic.io_apics.iter()) {
+      let base = space::map_physical("io-apic", PhysAddr::new(io_apic.address as u64), Size4KiB::SIZE, MMIO_FLAGS)?;
+      let mut entry = IoApic{
+         id: io_apic.id,
+         base,
+         gsi_base: io_apic.global_system_interrupt_base,
+         entries: 0,
+      };
+
+      unsafe {
+         entry.entries = ((entry.read(IOAPIC_VERSION) >> 16) & 0xFF) + 1;
+         for gsi in entry.gsi_base..entry.gsi_base + entry.entries {
+            entry.set_entry(gsi, REDIRECTION_MASKED);
+         }
+      }
+
+      *slot = Some(entry);
+   }
+
+   for source in apic.overrides.iter() {
+      if let Some(route) = routing.isa.get_mut(source.isa_source as usize) {
+         // ISA interrupts are edge-triggered and active high unless overridden.
+         *route = IsaRoute{
+            gsi: source.global_system_interrupt,
+            active_low: source.polarity == Polarity::ActiveLow,
+            level: source.trigger_mode == TriggerMode::Level,
+         };
+      }
+   }
+   drop(routing);
+
+   let base = space::map_physical("local-apic", PhysAddr::new(apic.local_apic_address), Size4KiB::SIZE, MMIO_FLAGS)?;
+
+   // The last fallible step: from here on the PICs are masked and the APICs take over.
+   route_isa_irq(Irq::Keyboard as u8, Irq::Keyboard.vector(), bsp)?;
+
+   // The PICs stay remapped, so a stray interrupt from them still lands on a known vector.
+   PICS.lock().disable();
+
+   LOCAL_APIC.store(base.as_u64(), Ordering::Release);
+   initialise_local();
+
+   let boot = platform.boot_cpu().map(|cpu| cpu.uid);
+   for nmi in apic.nmi_lines.iter() {
+      let applies = match nmi.processor {
+         NmiProcessor::All => true,
+         NmiProcessor::ProcessorUid(uid) => Some(uid) == boot,
+      };
+
+      if applies {
+         let register = match nmi.line {
+            LocalInterruptLine::Lint0 => LAPIC_LVT_LINT0,
+            LocalInterruptLine::Lint1 => LAPIC_LVT_LINT1,
+         };
+         unsafe{ write(register, LVT_NMI) };
+      }
+   }
+
+   let ticks = calibrate_timer();
+   TIMER_TICKS_PER_MS.store(ticks, Ordering::Relaxed);
+   start_timer();
+
+   let routing = ROUTING.lock();
+   for io_apic in routing.io_apics.iter().flatten() {
+      log::info!("IO-APIC {} at {:?}: GSIs {}..{}", io_apic.id, io_apic.base, io_apic.gsi_base, io_apic.gsi_base + io_apic.entries);
+   }
+   log::info!(
+      "Local APIC {} enabled; timer at {} ticks/ms; {} interrupt source overrides",
+      id(),
+      ticks,
+      apic.overrides.len(),
+   );
+
+   return Ok(());
+}
+
+/// Enables the calling CPU's local APIC and masks all of its local interrupts.
+///
+/// The register window is shared: every CPU sees its own local APIC at the same address.
+pub fn initialise_local() {
+   unsafe {
+      let base = rdmsr(IA32_APIC_BASE);
+      wrmsr(IA32_APIC_BASE, base | APIC_BASE_ENABLE);
+
+      write(LAPIC_LVT_TIMER, LVT_MASKED);
+      write(LAPIC_LVT_LINT0, LVT_MASKED);
+      write(LAPIC_LVT_LINT1, LVT_MASKED);
+      write(LAPIC_LVT_ERROR, LVT_MASKED);
+
+      // The error status register must be written before it is read.
+      write(LAPIC_ERROR_STATUS, 0);
+      write(LAPIC_ERROR_STATUS, 0);
+
+      write(LAPIC_TASK_PRIORITY, 0);
+      write(LAPIC_SPURIOUS, LAPIC_SOFTWARE_ENABLE | SPURIOUS_VECTOR as u32);
+   }
+}
+
+/// Starts the calling CPU's local APIC timer, firing [`TIMER_VECTOR`] at
+/// [`TIMER_FREQUENCY`] Hz.
+pub fn start_timer() {
+   let count = TIMER_TICKS_PER_MS.load(Ordering::Relaxed) * 1000 / TIMER_FREQUENCY;
+
+   unsafe {
+      write(LAPIC_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+      write(LAPIC_LVT_TIMER, LVT_TIMER_PERIODIC | TIMER_VECTOR as u32);
+      write(LAPIC_TIMER_INITIAL, count);
+   }
+}
+
+/// Returns `true` once interrupts are delivered through the APICs.
+pub fn is_enabled() -> bool {
+   return LOCAL_APIC.load(Ordering::Acquire) != 0;
+}
+
+/// Acknowledges the interrupt being handled on this CPU.
+pub fn end_of_interrupt() {
+   unsafe{ write(LAPIC_EOI, 0) };
+}
+
+/// The local APIC ID of the calling CPU.
+pub fn id() -> u32 {
+   return unsafe{ read(LAPIC_ID) } >> 24;
+}
+
+/// Local APIC timer ticks per millisecond, at a divider of 16. Zero before calibration.
+pub fn timer_ticks_per_ms() -> u32 {
+   return TIMER_TICKS_PER_MS.load(Ordering::Relaxed);
+}
+
+/// Sends an INIT IPI to the local APIC `destination`, which resets its CPU into the
+/// wait-for-SIPI state.
+pub fn send_init(destination: u32) {
+   send_ipi(destination, ICR_INIT | ICR_ASSERT);
+}
+
+/// Sends a startup IPI to the local APIC `destination`, starting its CPU in real mode at
+/// physical address `page` * 4 KiB.
+pub fn send_startup(destination: u32, page: u8) {
+   send_ipi(destination, ICR_STARTUP | ICR_ASSERT | page as u32);
+}
+
+/// Sends the interprocessor interrupt `command` to the local APIC `destination` and waits for its
+/// local APIC to accept it.
+pub fn send_ipi(destination: u32, command: u32) {
+   without_interrupts(|| unsafe {
+      write(LAPIC_ICR_HIGH, destination << 24);
+      write(LAPIC_ICR_LOW, command);
+
+      while read(LAPIC_ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+         spin_loop();
+      }
+   });
+}
+
+/// Delivers ISA IRQ `irq` to the local APIC `destination` on `vector`, honouring the interrupt
+/// source overrides.
+pub fn route_isa_irq(irq: u8, vector: u8, destination: u32) -> Result<(), ApicError> {
+   let route = ROUTING.lock().isa[irq as usize & 0xF];
+   return route_gsi(route.gsi, vector, destination, route.active_low, route.level);
+}
+
+/// Delivers global system interrupt `gsi` to the local APIC `destination` on `vector`.
+pub fn route_gsi(gsi: u32, vector: u8, destination: u32, active_low: bool, level: bool) -> Result<(), ApicError> {
+   let routing = ROUTING.lock();
+   let io_apic = routing.io_apic(gsi)?;
+
+   let mut entry = vector as u64 | (destination as u64) << 56;
+   if active_low {
+      entry |= REDIRECTION_ACTIVE_LOW;
+   }
+   if level {
+      entry |= REDIRECTION_LEVEL;
+   }
+
+   unsafe{ io_apic.set_entry(gsi, entry) };
+   return Ok(());
+}
+
+/// Stops global system interrupt `gsi` from being delivered.
+pub fn mask_gsi(gsi: u32) -> Result<(), ApicError> {
+   let routing = ROUTING.lock();
+   unsafe{ routing.io_apic(gsi)?.set_entry(gsi, REDIRECTION_MASKED) };
+   return Ok(());
+}
+
+/// Counts local APIC timer ticks across 10 ms of PIT channel 2, which is polled rather than
+/// interrupt-driven so this works with interrupts off.
+fn calibrate_timer() -> u32 {
+   const CALIBRATION_MS: u32 = 10;
+
+   unsafe {
+      write(LAPIC_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+      write(LAPIC_LVT_TIMER, LVT_MASKED);
+
+      timer::start_countdown(CALIBRATION_MS);
+      write(LAPIC_TIMER_INITIAL, u32::MAX);
+
+      while !timer::countdown_expired() {
+         spin_loop();
+      }
+
+      let elapsed = u32::MAX - read(LAPIC_TIMER_CURRENT);
+      write(LAPIC_TIMER_INITIAL, 0);
+
+      return elapsed / CALIBRATION_MS;
+   }
+}
+
+unsafe fn read(register: usize) -> u32 {
+   let base = LOCAL_APIC.load(Ordering::Acquire) as usize;
+   return ((base + register) as *const u32).read_volatile();
+}
+
+unsafe fn write(register: usize, value: u32) {
+   let base = LOCAL_APIC.load(Ordering::Acquire) as usize;
+   ((base + register) as *mut u32).write_volatile(value);
 }
 
 // IMPORTS //
+
+use {
+   crate::{
+      address::space::{self, RegionError},
+      arch::x86_64::{
+         pic::{Irq, PICS},
+         timer::{self, TIMER_FREQUENCY},
+      },
+      platform::PLATFORM,
+   },
+   acpi::platform::interrupt::{LocalInterruptLine, NmiProcessor, Polarity, TriggerMode},
+   base::{arch::without_interrupts, log},
+   core::{
+      fmt::{Display, Formatter, Result as FmtResult},
+      hint::spin_loop,
+      sync::atomic::{AtomicU32, AtomicU64, Ordering},
+   },
+   spin::Mutex,
+   x86::{
+      cpuid::CpuId,
+      msr::{rdmsr, wrmsr, IA32_APIC_BASE},
+   },
+   x86_64::{
+      structures::paging::{PageSize, PageTableFlags, Size4KiB},
+      PhysAddr, VirtAddr,
+   },
+};
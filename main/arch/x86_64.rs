@@ -5,6 +5,8 @@ pub fn initialise_platform() {
 
    COM2.lock().initialise();
 
+   enable_protection();
+
    log::debug!("Initialise timer, PIT, et cetera.");
 
    let latch = ((CLOCK_TICK_RATE + TIMER_FREQUENCY / 2) / TIMER_FREQUENCY) as u16;
@@ -23,20 +25,44 @@ pub fn initialise_platform() {
        */
       outb(0x43, 0x34);
 
-      wait_100k();
-
       /* Port 0x40 is for the counter register of channel 0 */
 
       outb(0x40, (latch & 0xFF) as u8); /* low byte  */
-
-      wait_100k();
-
       outb(0x40, (latch >> 8) as u8); /* high byte */
    }
 
+   initialise_clock();
+   rtc::initialise();
+
    log::info!("Successfully initialised x86_64 platform modules.");
 }
 
+/// Turns on the calling CPU's kernel memory protections.
+///
+/// Write protection makes read-only pages binding in ring 0 too, NXE honours the no-execute bit,
+/// and SMEP and SMAP, where supported, stop the kernel from running or touching user pages. Every
+/// CPU has to do this for itself.
+pub fn enable_protection() {
+   let features = CpuId::new().get_extended_feature_info();
+   let smep = features.as_ref().map_or(false, |features| features.has_smep());
+   let smap = features.as_ref().map_or(false, |features| features.has_smap());
+
+   unsafe {
+      Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT));
+      Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE));
+
+      Cr4::update(|flags| {
+         flags.set(Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION, smep);
+         flags.set(Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION, smap);
+      });
+   }
+
+   log::info!("Memory protection: WP on, NX on, SMEP {}, SMAP {}.",
+      if smep { "on" } else { "unsupported" },
+      if smap { "on" } else { "unsupported" },
+   );
+}
+
 // IMPORTS //
 
 use {
@@ -45,10 +71,16 @@ use {
       log,
       uart::COM2,
    },
-   x86::io::*,
+   x86::{cpuid::CpuId, io::*},
+   x86_64::registers::{
+      control::{Cr0, Cr0Flags, Cr4, Cr4Flags},
+      model_specific::{Efer, EferFlags},
+   },
 };
 
 // MODULES //
 
+pub mod pic;
+pub mod rtc;
 pub mod syscall;
 pub mod timer;
@@ -0,0 +1,206 @@
+/// Vector the primary PIC's IRQ 0 is remapped to, just past the CPU exceptions.
+pub const PIC_1_OFFSET: u8 = 32;
+
+/// Vector the secondary PIC's IRQ 8 is remapped to.
+pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
+
+/// The legacy 8259 pair, primary and secondary.
+pub static PICS: Mutex<ChainedPics> = Mutex::new(ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET));
+
+/// Legacy IRQ lines the kernel handles.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Irq {
+   /// Channel 0 of the PIT.
+   Timer = 0,
+
+   /// The PS/2 keyboard.
+   Keyboard = 1,
+
+   /// The secondary PIC, cascaded onto the primary.
+   Cascade = 2,
+
+   /// The CMOS real-time clock's periodic, alarm and update interrupts.
+   Rtc = 8,
+}
+
+impl Irq {
+   /// The interrupt vector this line arrives on once the PICs are remapped.
+   pub const fn vector(self) -> u8 {
+      return match self as u8 {
+         line @ 0..=7 => PIC_1_OFFSET + line,
+         line => PIC_2_OFFSET + line - 8,
+      };
+   }
+}
+
+const COMMAND_INIT: u8 = 0x11;
+const COMMAND_EOI: u8 = 0x20;
+const COMMAND_READ_ISR: u8 = 0x0B;
+const MODE_8086: u8 = 0x01;
+
+/// A single 8259 programmable interrupt controller.
+struct Pic {
+   offset: u8,
+   command: u16,
+   data: u16,
+}
+
+impl Pic {
+   fn handles(&self, vector: u8) -> bool {
+      return self.offset <= vector && vector < self.offset + 8;
+   }
+
+   unsafe fn end_of_interrupt(&self) {
+      outb(self.command, COMMAND_EOI);
+   }
+
+   unsafe fn in_service(&self) -> u8 {
+      outb(self.command, COMMAND_READ_ISR);
+      return inb(self.command);
+   }
+}
+
+/// The primary and secondary 8259s, with the secondary cascaded onto IRQ 2 of the primary.
+pub struct ChainedPics {
+   pics: [Pic; 2],
+}
+
+impl ChainedPics {
+   /// Describes a PIC pair whose IRQs will be remapped to start at `primary` and `secondary`.
+   pub const fn new(primary: u8, secondary: u8) -> Self {
+      return ChainedPics{
+         pics: [
+            Pic{ offset: primary, command: 0x20, data: 0x21 },
+            Pic{ offset: secondary, command: 0xA0, data: 0xA1 },
+         ],
+      };
+   }
+
+   /// Reinitialises both PICs with their new offsets and masks every line.
+   ///
+   /// Out of reset the primary delivers IRQs 0 to 7 on vectors 8 to 15, on top of the CPU's own
+   /// exceptions, so this must run before interrupts are enabled.
+   ///
+   /// ## Safety
+   ///
+   /// The IDT must be ready for the new vectors before any line is unmasked.
+   pub unsafe fn initialise(&mut self) {
+      let [primary, secondary] = &self.pics;
+
+      // The PICs are slow; an unused port write gives them time to settle between commands.
+      let wait = || outb(0x80, 0);
+
+      outb(primary.command, COMMAND_INIT);
+      wait();
+      outb(secondary.command, COMMAND_INIT);
+      wait();
+
+      outb(primary.data, primary.offset);
+      wait();
+      outb(secondary.data, secondary.offset);
+      wait();
+
+      // Tell the primary the secondary sits on IRQ 2, and the secondary its cascade identity.
+      outb(primary.data, 1 << Irq::Cascade as u8);
+      wait();
+      outb(secondary.data, 2);
+      wait();
+
+      outb(primary.data, MODE_8086);
+      wait();
+      outb(secondary.data, MODE_8086);
+      wait();
+
+      self.write_masks(0xFF, 0xFF);
+   }
+
+   /// Lets `irq` through to the CPU, along with the cascade if `irq` is on the secondary.
+   pub fn unmask(&mut self, irq: Irq) {
+      let (primary, secondary) = self.read_masks();
+      match irq as u8 {
+         line @ 0..=7 => self.write_masks(primary & !(1 << line), secondary),
+         line => self.write_masks(primary & !(1 << Irq::Cascade as u8), secondary & !(1 << (line - 8))),
+      }
+   }
+
+   /// Stops `irq` from reaching the CPU.
+   pub fn mask(&mut self, irq: Irq) {
+      let (primary, secondary) = self.read_masks();
+      match irq as u8 {
+         line @ 0..=7 => self.write_masks(primary | 1 << line, secondary),
+         line => self.write_masks(primary, secondary | 1 << (line - 8)),
+      }
+   }
+
+   /// Masks every line, for when the IO-APIC takes over.
+   pub fn disable(&mut self) {
+      self.write_masks(0xFF, 0xFF);
+   }
+
+   /// Returns `true` if `vector` belongs to one of the PICs.
+   pub fn handles(&self, vector: u8) -> bool {
+      return self.pics.iter().any(|pic| pic.handles(vector));
+   }
+
+   /// Acknowledges the interrupt on `vector`, so the PIC will deliver the next one.
+   ///
+   /// Interrupts from the secondary go through the primary as well, so both must be told.
+   ///
+   /// ## Safety
+   ///
+   /// `vector` must be the interrupt currently being handled.
+   pub unsafe fn end_of_interrupt(&mut self, vector: u8) {
+      let [primary, secondary] = &self.pics;
+
+      if secondary.handles(vector) {
+         secondary.end_of_interrupt();
+      }
+
+      if self.handles(vector) {
+         primary.end_of_interrupt();
+      }
+   }
+
+   /// Returns `true` if the interrupt on `vector` is real rather than spurious.
+   ///
+   /// A line that drops before the PIC can deliver it still raises its lowest-priority vector,
+   /// IRQ 7 or IRQ 15, without setting the in-service bit. A spurious IRQ 15 still needs an EOI
+   /// for the cascade on the primary.
+   ///
+   /// ## Safety
+   ///
+   /// `vector` must be the interrupt currently being handled.
+   pub unsafe fn is_genuine(&mut self, vector: u8) -> bool {
+      let [primary, secondary] = &self.pics;
+
+      if vector == primary.offset + 7 {
+         return primary.in_service() & 0x80 != 0;
+      }
+
+      if vector == secondary.offset + 7 && secondary.in_service() & 0x80 == 0 {
+         primary.end_of_interrupt();
+         return false;
+      }
+
+      return true;
+   }
+
+   fn read_masks(&self) -> (u8, u8) {
+      return unsafe{ (inb(self.pics[0].data), inb(self.pics[1].data)) };
+   }
+
+   fn write_masks(&mut self, primary: u8, secondary: u8) {
+      unsafe {
+         outb(self.pics[0].data, primary);
+         outb(self.pics[1].data, secondary);
+      }
+   }
+}
+
+// IMPORTS //
+
+use {
+   spin::Mutex,
+   x86::io::{inb, outb},
+};
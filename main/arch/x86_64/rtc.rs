@@ -0,0 +1,278 @@
+// CMOS registers.
+const SECONDS: u8 = 0x00;
+const MINUTES: u8 = 0x02;
+const HOURS: u8 = 0x04;
+const DAY: u8 = 0x07;
+const MONTH: u8 = 0x08;
+const YEAR: u8 = 0x09;
+const STATUS_A: u8 = 0x0A;
+const STATUS_B: u8 = 0x0B;
+const STATUS_C: u8 = 0x0C;
+
+const UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const PERIODIC_ENABLE: u8 = 1 << 6;
+const BINARY_MODE: u8 = 1 << 2;
+const HOURS_24: u8 = 1 << 1;
+const HOUR_PM: u8 = 1 << 7;
+const PERIODIC_FLAG: u8 = 1 << 6;
+
+/// Set in the index port to keep NMIs masked while a CMOS register is selected.
+const NMI_DISABLE: u8 = 1 << 7;
+
+/// Reads of the clock to try before giving up on two in a row agreeing.
+const READ_ATTEMPTS: usize = 8;
+
+/// Polls of the update-in-progress flag before giving up on the clock. An update holds the flag
+/// for about 2 ms, and each poll is at least two port accesses, so this is well over that.
+const UPDATE_POLLS: usize = 100_000;
+
+/// The CMOS index and data ports. Interrupt handlers read status register C, so lock this through
+/// [`without_interrupts`] everywhere else.
+pub static CMOS: Mutex<Cmos> = Mutex::new(Cmos::new());
+
+/// Periodic RTC interrupts taken since [`enable_periodic`].
+static PERIODIC_TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Why the clock could not be read or programmed.
+#[derive(Debug)]
+pub enum RtcError {
+   /// The FADT says there is no CMOS clock.
+   NotPresent,
+
+   /// The clock kept changing under every read.
+   Unstable,
+
+   /// The clock holds an impossible or pre-1970 time.
+   Invalid(DateTime),
+
+   /// Periodic rates run from 3 to 15.
+   InvalidRate(u8),
+
+   /// IRQ 8 could not be routed through the IO-APIC.
+   Apic(ApicError),
+}
+
+impl Display for RtcError {
+   fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+      match self {
+         RtcError::NotPresent => write!(f, "no CMOS clock"),
+         RtcError::Unstable => write!(f, "the clock did not hold still long enough to be read"),
+         RtcError::Invalid(time) => write!(f, "the clock holds an invalid time: {:?}", time),
+         RtcError::InvalidRate(rate) => write!(f, "periodic rate {} is outside 3 to 15", rate),
+         RtcError::Apic(error) => write!(f, "failed to route IRQ 8: {}", error),
+      }
+   }
+}
+
+/// The CMOS RAM behind ports 0x70 and 0x71.
+pub struct Cmos {
+   index: Pio<u8>,
+   data: Pio<u8>,
+}
+
+impl Cmos {
+   const fn new() -> Self {
+      return Cmos{
+         index: Pio::new(0x70),
+         data: Pio::new(0x71),
+      };
+   }
+
+   /// Reads CMOS register `register`.
+   pub fn read(&mut self, register: u8) -> u8 {
+      self.index.write(NMI_DISABLE | register);
+      let value = self.data.read();
+      self.unmask_nmi(register);
+      return value;
+   }
+
+   /// Writes `value` to CMOS register `register`.
+   pub fn write(&mut self, register: u8, value: u8) {
+      self.index.write(NMI_DISABLE | register);
+      self.data.write(value);
+      self.unmask_nmi(register);
+   }
+
+   /// Clears the NMI mask bit shared with the index port, leaving `register` selected.
+   fn unmask_nmi(&mut self, register: u8) {
+      self.index.write(register);
+   }
+
+   /// The clock registers as stored, once no update is in progress.
+   fn read_raw(&mut self, century: u8) -> Result<[u8; 7], RtcError> {
+      // A missing clock reads as all ones, update flag included.
+      let mut polls = 0;
+      while self.read(STATUS_A) & UPDATE_IN_PROGRESS != 0 {
+         polls += 1;
+         if polls == UPDATE_POLLS {
+            return Err(RtcError::Unstable);
+         }
+         spin_loop();
+      }
+
+      return Ok([
+         self.read(SECONDS),
+         self.read(MINUTES),
+         self.read(HOURS),
+         self.read(DAY),
+         self.read(MONTH),
+         self.read(YEAR),
+         if century != 0 { self.read(century) } else { 0 },
+      ]);
+   }
+}
+
+/// Reads the wall-clock time from the RTC, assumed to be kept in UTC.
+///
+/// The clock is read until two reads in a row agree, so a read never straddles an update. BCD and
+/// 12-hour formats are converted according to status register B, and the century comes from the
+/// FADT's century register where there is one, and is otherwise taken to be the 21st.
+pub fn read_time() -> Result<DateTime, RtcError> {
+   let power = PLATFORM.lock().as_ref().and_then(|platform| platform.power);
+   if power.map_or(false, |power| !power.has_cmos_rtc) {
+      return Err(RtcError::NotPresent);
+   }
+
+   let century = power.map_or(0, |power| power.century);
+
+   let (raw, status) = without_interrupts(|| {
+      let mut cmos = CMOS.lock();
+      let mut last = cmos.read_raw(century)?;
+
+      for _ in 0..READ_ATTEMPTS {
+         let raw = cmos.read_raw(century)?;
+         if raw == last {
+            return Ok((raw, cmos.read(STATUS_B)));
+         }
+         last = raw;
+      }
+
+      return Err(RtcError::Unstable);
+   })?;
+
+   let time = decode(raw, status, century != 0);
+
+   return match time.is_valid() {
+      true => Ok(time),
+      false => Err(RtcError::Invalid(time)),
+   };
+}
+
+/// Converts the seconds, minutes, hours, day, month, year and century registers in `raw` to a
+/// date and time, in BCD or binary and 12- or 24-hour format according to `status`, the value of
+/// status register B. Without a century register the century is taken to be the 21st.
+///
+/// The result is not checked; see [`DateTime::is_valid`].
+pub fn decode(raw: [u8; 7], status: u8, has_century: bool) -> DateTime {
+   let decode = |value: u8| match status & BINARY_MODE {
+      0 => (value & 0x0F) + (value >> 4) * 10,
+      _ => value,
+   };
+
+   let [second, minute, hour, day, month, year, high] = raw;
+
+   // In 12-hour mode the top bit marks PM, and midnight and noon are both 12.
+   let hour = match status & HOURS_24 {
+      0 => decode(hour & !HOUR_PM) % 12 + if hour & HOUR_PM != 0 { 12 } else { 0 },
+      _ => decode(hour),
+   };
+
+   let century = match has_century {
+      false => 20,
+      true => decode(high) as u16,
+   };
+
+   return DateTime{
+      year: century * 100 + decode(year) as u16,
+      month: decode(month),
+      day: decode(day),
+      hour,
+      minute: decode(minute),
+      second: decode(second),
+   };
+}
+
+/// Sets [`base::time`]'s wall clock from the RTC.
+pub fn initialise() {
+   match read_time() {
+      Ok(time) => {
+         time::set_wall_clock(time);
+         log::info!("Wall clock set from the RTC: {}", time);
+      }
+      Err(error) => log::warn!("Failed to read the RTC: {}", error),
+   }
+}
+
+/// Starts the RTC interrupting at 32768 >> (`rate` - 1) Hz, from 8 kHz at rate 3 down to 2 Hz at
+/// rate 15, and routes IRQ 8 to this CPU. Returns the frequency.
+pub fn enable_periodic(rate: u8) -> Result<u32, RtcError> {
+   if !(3..=15).contains(&rate) {
+      return Err(RtcError::InvalidRate(rate));
+   }
+
+   without_interrupts(|| {
+      let mut cmos = CMOS.lock();
+      let status_a = cmos.read(STATUS_A);
+      cmos.write(STATUS_A, (status_a & 0xF0) | rate);
+
+      let status_b = cmos.read(STATUS_B);
+      cmos.write(STATUS_B, status_b | PERIODIC_ENABLE);
+
+      // Nothing more is raised until a pending interrupt has been read out of register C.
+      cmos.read(STATUS_C);
+   });
+
+   if apic::is_enabled() {
+      apic::route_isa_irq(Irq::Rtc as u8, Irq::Rtc.vector(), apic::id()).map_err(RtcError::Apic)?;
+   } else {
+      PICS.lock().unmask(Irq::Rtc);
+   }
+
+   return Ok(32768 >> (rate - 1));
+}
+
+/// Stops the periodic interrupt.
+pub fn disable_periodic() {
+   without_interrupts(|| {
+      let mut cmos = CMOS.lock();
+      let status_b = cmos.read(STATUS_B);
+      cmos.write(STATUS_B, status_b & !PERIODIC_ENABLE);
+   });
+}
+
+/// Periodic interrupts taken so far.
+pub fn periodic_ticks() -> u64 {
+   return PERIODIC_TICKS.load(Ordering::Relaxed);
+}
+
+/// Acknowledges an RTC interrupt. Called from the IRQ 8 handler.
+pub fn handle_interrupt() {
+   // Reading register C both says why the RTC interrupted and lets it interrupt again.
+   let cause = CMOS.lock().read(STATUS_C);
+   if cause & PERIODIC_FLAG != 0 {
+      PERIODIC_TICKS.fetch_add(1, Ordering::Relaxed);
+   }
+}
+
+// IMPORTS //
+
+use {
+   super::pic::{Irq, PICS},
+   crate::{
+      apic::{self, ApicError},
+      platform::PLATFORM,
+   },
+   base::{
+      arch::without_interrupts,
+      io::HardwareIo,
+      log,
+      syscall::Pio,
+      time::{self, DateTime},
+   },
+   core::{
+      fmt::{Display, Formatter, Result as FmtResult},
+      hint::spin_loop,
+      sync::atomic::{AtomicU64, Ordering},
+   },
+   spin::Mutex,
+};
@@ -1,18 +1,141 @@
 pub const CLOCK_TICK_RATE: u32 = 1193182u32; // 8254 chip's internal oscillator frequency
 pub const TIMER_FREQUENCY: u32 = 100; // Timer frequency in Hertz.
 
-pub unsafe fn wait_100k() {
-   let start = rdtsc();
-   call_mb();
+/// How long the TSC is measured against a reference clock, in milliseconds. PIT channel 2 can
+/// count down from at most 54 ms.
+const CALIBRATION_MS: u32 = 10;
 
-   while rdtsc() - start < 1000000 {
-      call_mb();
+// HPET registers, as offsets into its register block.
+const HPET_CAPABILITIES: usize = 0x00;
+const HPET_CONFIGURATION: usize = 0x10;
+const HPET_COUNTER: usize = 0xF0;
+const HPET_ENABLE: u64 = 1 << 0;
+
+/// Measures the TSC against the HPET, or PIT channel 2 without one, and starts
+/// [`base::time`] running from it.
+///
+/// The PIT needs no setup, but the HPET is only known once the ACPI tables have been read.
+pub fn initialise_clock() {
+   let hpet = PLATFORM.lock().as_ref().and_then(|platform| platform.hpet);
+
+   let (khz, source) = match hpet.and_then(|hpet| without_interrupts(|| calibrate_with_hpet(&hpet))) {
+      Some(khz) => (khz, "HPET"),
+      None => (without_interrupts(calibrate_with_pit), "PIT"),
+   };
+
+   time::set_tsc_frequency(khz);
+   log::info!("TSC runs at {}.{:03} MHz, calibrated against the {}", khz / 1000, khz % 1000, source);
+
+   let invariant = CpuId::new()
+      .get_advanced_power_mgmt_info()
+      .map_or(false, |info| info.has_invariant_tsc());
+
+   if !invariant {
+      log::warn!("The TSC is not invariant; time will drift if the CPU changes frequency or sleeps");
+   }
+}
+
+/// Starts PIT channel 2 counting down `ms` milliseconds, at most 54, with the speaker off.
+///
+/// ## Safety
+///
+/// Nothing else may be using channel 2 or the speaker gate.
+pub unsafe fn start_countdown(ms: u32) {
+   let latch = (CLOCK_TICK_RATE * ms / 1000) as u16;
+
+   // Gate channel 2 on with the speaker off, and load a one-shot count.
+   let control = inb(0x61);
+   outb(0x61, (control & !0x02) | 0x01);
+   outb(0x43, 0b1011_0000);
+   outb(0x42, latch as u8);
+   outb(0x42, (latch >> 8) as u8);
+
+   // Restart the count by toggling the gate.
+   let control = inb(0x61);
+   outb(0x61, control & !0x01);
+   outb(0x61, control | 0x01);
+}
+
+/// Returns `true` once the countdown started by [`start_countdown`] has run out.
+pub fn countdown_expired() -> bool {
+   // Channel 2's output goes high when the count runs out.
+   return unsafe{ inb(0x61) } & 0x20 != 0;
+}
+
+/// TSC cycles per millisecond, measured across a PIT channel 2 countdown.
+fn calibrate_with_pit() -> u64 {
+   unsafe {
+      start_countdown(CALIBRATION_MS);
+      let start = rdtsc();
+
+      while !countdown_expired() {
+         spin_loop();
+      }
+
+      return (rdtsc() - start) / CALIBRATION_MS as u64;
+   }
+}
+
+/// TSC cycles per millisecond, measured against the HPET main counter. `None` if the HPET's
+/// registers cannot be mapped or it reports a nonsensical period.
+fn calibrate_with_hpet(hpet: &Hpet) -> Option<u64> {
+   let flags = PageTableFlags::PRESENT
+      | PageTableFlags::WRITABLE
+      | PageTableFlags::NO_EXECUTE
+      | Caching::Uncached.flags();
+
+   let registers = space::map_physical("hpet", PhysAddr::new(hpet.base_address), Size4KiB::SIZE, flags).ok()?;
+   let register = |offset: usize| (registers.as_u64() as usize + offset) as *mut u64;
+
+   let khz = unsafe {
+      // The upper half of the capabilities is the counter period in femtoseconds, at most 100 ns.
+      let period = register(HPET_CAPABILITIES).read_volatile() >> 32;
+
+      if period == 0 || period > 100_000_000 {
+         None
+      } else {
+         let configuration = register(HPET_CONFIGURATION).read_volatile();
+         register(HPET_CONFIGURATION).write_volatile(configuration | HPET_ENABLE);
+
+         // A 32-bit counter reads back with whatever is in the upper half, and wraps at 32 bits.
+         let width = if hpet.counter_64bit { u64::MAX } else { u32::MAX as u64 };
+
+         let target = CALIBRATION_MS as u64 * 1_000_000_000_000 / period;
+         let counter = register(HPET_COUNTER).read_volatile() & width;
+         let start = rdtsc();
+
+         while (register(HPET_COUNTER).read_volatile() & width).wrapping_sub(counter) & width < target {
+            spin_loop();
+         }
+
+         Some((rdtsc() - start) / CALIBRATION_MS as u64)
+      }
+   };
+
+   if let Err(error) = space::free(registers) {
+      log::warn!("Failed to unmap the HPET: {}", error);
    }
+
+   return khz;
 }
 
 // IMPORTS //
 
 use {
-   base::syscall::*,
-   x86::{time::rdtsc}
+   crate::{
+      address::space,
+      memory::Caching,
+      platform::{Hpet, PLATFORM},
+   },
+   base::{arch::without_interrupts, log, time},
+   core::hint::spin_loop,
+   x86::{
+      cpuid::CpuId,
+      io::{inb, outb},
+      time::rdtsc,
+   },
+   x86_64::{
+      structures::paging::{PageSize, PageTableFlags, Size4KiB},
+      PhysAddr,
+   },
 };
@@ -1,44 +1,118 @@
 pub static DOUBLE_FAULT_IST_INDEX: u16 = 0;
 
-pub static mut TSS: TaskStateSegment = {
-   let tss = TaskStateSegment::new();
-   tss
-};
+/// The boot processor's GDT and TSS.
+static mut BOOT_TABLES: CpuTables = CpuTables::new();
 
-pub static mut GDT: GlobalDescriptorTable = {
-   let mut gdt = GlobalDescriptorTable::new();
-   gdt.add_entry(Descriptor::kernel_code_segment());
-   gdt
-};
+/// One CPU's GDT and TSS.
+///
+/// Every CPU needs its own: loading a TSS marks its descriptor busy, so no two CPUs can load the
+/// same one, and each needs a double-fault stack of its own.
+pub struct CpuTables {
+   /// The descriptor table, with kernel code and data segments and the TSS.
+   pub gdt: GlobalDescriptorTable,
 
-pub fn initialise() {
-   unsafe {
-      TSS.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-         const STACK_SIZE: usize = 4096 * 5;
-         static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+   /// The task state segment, which holds the interrupt stack table.
+   pub tss: TaskStateSegment,
+
+   code: SegmentSelector,
+   data: SegmentSelector,
+   task: SegmentSelector,
+}
 
-         let stack_start = VirtAddr::from_ptr(&STACK);
-         let stack_end = stack_start + STACK_SIZE;
-         stack_end
+impl CpuTables {
+   /// Empty tables, to be filled in by [`build`](CpuTables::build).
+   pub const fn new() -> Self {
+      return CpuTables{
+         gdt: GlobalDescriptorTable::new(),
+         tss: TaskStateSegment::new(),
+         code: SegmentSelector::new(0, PrivilegeLevel::Ring0),
+         data: SegmentSelector::new(0, PrivilegeLevel::Ring0),
+         task: SegmentSelector::new(0, PrivilegeLevel::Ring0),
       };
+   }
+
+   /// Gives the TSS a double-fault stack named `stack` and fills in the GDT.
+   ///
+   /// The double-fault stack comes from the kernel address space with a guard page below it, so
+   /// this must run after the heap and the address space manager are up.
+   ///
+   /// ## Safety
+   ///
+   /// The tables must live forever and must not have been built before: the GDT refers to the TSS
+   /// by address.
+   pub unsafe fn build(&mut self, stack: &'static str) -> Result<(), RegionError> {
+      // A stack overflow faults on the guard page and the CPU cannot push the page fault frame
+      // onto the same stack, so the double fault must arrive on a stack of its own.
+      let stack = KernelStack::allocate(stack, IST_STACK_SIZE)?;
+      self.tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = stack.top;
 
-      GDT.add_entry(Descriptor::tss_segment(&TSS));
+      self.code = self.gdt.add_entry(Descriptor::kernel_code_segment());
+      self.data = self.gdt.add_entry(Descriptor::kernel_data_segment());
+      self.task = self.gdt.add_entry(Descriptor::tss_segment(&*addr_of!(self.tss)));
 
-      GDT.load();
+      return Ok(());
+   }
+
+   /// Loads the tables on the calling CPU and reloads its segment registers.
+   ///
+   /// ## Safety
+   ///
+   /// The tables must have been built, and must not be loaded on any other CPU.
+   pub unsafe fn load(&'static self) {
+      self.gdt.load();
+
+      // The bootloader's selectors index into its own GDT, so reload every one we rely on.
+      CS::set_reg(self.code);
+      SS::set_reg(self.data);
+      DS::set_reg(self.data);
+      ES::set_reg(self.data);
+      load_tss(self.task);
+   }
+}
+
+/// Builds and loads the boot processor's GDT and TSS.
+pub fn initialise() {
+   unsafe {
+      BOOT_TABLES.build("double-fault-stack").expect("failed to allocate the double fault stack");
+      BOOT_TABLES.load();
    }
 
    log::info!("Successfully initialised global descriptor table!");
 }
 
+/// The boot processor's GDT and TSS, once [`initialise`] has built them.
+pub fn boot_tables() -> &'static CpuTables {
+   return unsafe{ &*addr_of!(BOOT_TABLES) };
+}
+
+/// Builds a GDT and TSS for another CPU on the heap, with its double-fault stack named `stack`.
+/// They are never freed.
+pub fn allocate(stack: &'static str) -> Result<&'static CpuTables, RegionError> {
+   let tables = Box::leak(Box::new(CpuTables::new()));
+   unsafe{ tables.build(stack)? };
+   return Ok(tables);
+}
+
 // IMPORTS //
 
 use {
+   crate::{
+      address::space::RegionError,
+      memory::stack::{KernelStack, IST_STACK_SIZE},
+   },
+   alloc::boxed::Box,
    base::log,
+   core::ptr::addr_of,
    x86_64::{
-      VirtAddr,
+      instructions::{
+         segmentation::{Segment, CS, DS, ES, SS},
+         tables::load_tss,
+      },
+      registers::segmentation::SegmentSelector,
       structures::{
          gdt::{GlobalDescriptorTable, Descriptor},
          tss::TaskStateSegment,
       },
+      PrivilegeLevel,
    }
 };
@@ -5,6 +5,7 @@
 #![feature(async_closure)]
 #![feature(const_mut_refs)]
 #![feature(custom_test_frameworks)]
+#![feature(naked_functions)]
 #![feature(panic_info_message)]
 #![reexport_test_harness_main="test_main"]
 #![test_runner(base::test::test_runner)]
@@ -14,7 +15,7 @@
 /// Our bootloader configuration.
 pub static BOOTLOADER_CONFIG: BootloaderConfig = {
    let mut config = BootloaderConfig::new_default();
-   config.mappings.framebuffer = Mapping::FixedAddress(0x8000000);
+   config.mappings.framebuffer = Mapping::FixedAddress(address::FRAMEBUFFER_START as u64);
    config.mappings.physical_memory = Some(Mapping::Dynamic);
    config.mappings.page_table_recursive = Some(Mapping::Dynamic);
    // TODO: write out the other necessary memory mappings.
@@ -29,24 +30,51 @@ pub fn main(info: &'static mut BootInfo) -> ! {
    let buffer = framebuffer.into_option().unwrap().into_buffer();
    terminal::init_writer(buffer, fb_info, true, false);
 
+   // Set up our page tables.
+   let physical_offset = info.physical_memory_offset.clone();
+   let physical_offset = VirtAddr::new(physical_offset.into_option().unwrap());
+   let memory_regions: &'static [MemoryRegion] = info.memory_regions.as_ref();
+   unsafe{ memory::initialise(physical_offset, memory_regions) };
+
+   // Record what is already mapped before anyone asks for address space.
+   let physical_size = memory_regions.iter().map(|r| r.end).max().unwrap_or(0);
+   address::space::initialise(physical_offset, physical_size, fb_info.byte_len as u64);
+
+   log::info!("Building the heap!");
+   memory::build_heap().expect("failed to initialise heap");
+
+   // Leave the bootloader's stack for one with a guard page we know about.
+   let stack = KernelStack::allocate("kernel-stack", BOOT_STACK_SIZE).expect("failed to allocate the kernel stack");
+   unsafe{ stack.switch_to(start_kernel, info as *const BootInfo as usize) };
+}
+
+/// Continues booting on the kernel stack.
+extern "C" fn start_kernel(info: usize) -> ! {
+   let info: &'static BootInfo = unsafe{ &*(info as *const BootInfo) };
+
    // Initialise the global descriptor table.
    log::info!("Initialising global descriptor table!");
    gdt::initialise();
 
+   // Per-CPU data hangs off the GS base; interrupt handlers look for it from now on.
+   smp::initialise_boot_cpu();
+
    // Initialise the interrupt descriptor table.
    log::info!("Initialising interrupt descriptor table!");
    interrupts::initialise();
 
-   // Set up our page tables.
-   let physical_offset = info.physical_memory_offset.clone();
-   let physical_offset = VirtAddr::new(physical_offset.into_option().unwrap());
-   let mut mapper = unsafe{ memory::initialise(physical_offset) };
-   let mut frame_allocator = unsafe{
-      SystemFrameAllocator::new(info.memory_regions.as_ref())
-   };
+   // Make the kernel image write-xor-execute before anything else runs.
+   if let Err(error) = memory::protect_kernel(info) {
+      log::warn!("Failed to protect the kernel image: {}", error);
+   }
 
-   log::info!("Building the heap!");
-   memory::build_heap(&mut mapper, &mut frame_allocator).expect("failed to initialise heap");
+   // Find out what the machine has from its ACPI tables.
+   match info.rsdp_addr.clone().into_option() {
+      Some(rsdp) => if let Err(error) = platform::initialise(rsdp) {
+         log::warn!("Failed to read the ACPI tables: {:?}", error);
+      },
+      None => log::warn!("No RSDP from the bootloader; the ACPI tables cannot be read"),
+   }
 
    // Check CPU architecture and perform the proper initialisation.
    log::info!("Checking CPU architecture...");
@@ -60,6 +88,25 @@ pub fn main(info: &'static mut BootInfo) -> ! {
    #[cfg(target_arch = "aarch64")]
    arch::aarch64::initialise_platform();
 
+   // Poweroff and reboot need the FADT and the DSDT, so they can only be set up now.
+   power::initialise();
+
+   // Hand the IRQs over to the APICs; the PICs carry on if there are none.
+   if let Err(error) = apic::initialise() {
+      log::warn!("Staying on the legacy PICs: {}", error);
+   }
+
+   // The other CPUs need their local APICs to be woken, and park once they are up.
+   if apic::is_enabled() {
+      if let Err(error) = smp::initialise() {
+         log::warn!("Failed to start the other CPUs: {}", error);
+      }
+   }
+
+   // The IDT and the interrupt controllers are all set up, so let the timer and the keyboard in.
+   x86_64::instructions::interrupts::enable();
+   log::info!("Interrupts enabled.");
+
    // Example multitasking
    log::info!("Checking runtime multitasking...");
 
@@ -72,17 +119,14 @@ pub fn main(info: &'static mut BootInfo) -> ! {
       print!("{}", number);
    });
 
-   //tasks::add_future(tasks::keyboard::print_keypresses());
-   //Keyboard input still not working properly :(
-
-   tasks::run_tasks(); // works now! :D
+   tasks::add_future(tasks::keyboard::print_keypresses());
 
    // Our test harness.
    // Only called when running tests.
    #[cfg(test)]
    test_main();
 
-   hlt_loop();
+   run_loop();
 }
 
 /// This function is called on compiler or runtime panic.
@@ -103,6 +147,16 @@ fn panic(info: &PanicInfo) -> ! {
    abort();
 }
 
+/// Called when the heap cannot satisfy an allocation, even after trying to grow.
+#[alloc_error_handler]
+fn allocation_error(layout: Layout) -> ! {
+   if let Some(heap) = HEAP.try_lock() {
+      log::error!("Heap state: {:?}", heap.as_ref());
+   }
+
+   panic!("failed to allocate {} bytes aligned to {}", layout.size(), layout.align());
+}
+
 #[no_mangle]
 extern "C" fn abort() -> ! {
    loop {
@@ -122,6 +176,22 @@ extern "C" fn abort() -> ! {
 #[no_mangle]
 extern "C" fn eh_personality() {}
 
+/// Runs woken tasks, halting until the next interrupt whenever none are left.
+pub fn run_loop() -> ! {
+   loop {
+      tasks::run_tasks();
+
+      // A task woken by an interrupt handler after this check would otherwise wait for the
+      // interrupt after that; `sti; hlt` takes the pending interrupt only once halted.
+      x86_64::instructions::interrupts::disable();
+      if tasks::idle() {
+         x86_64::instructions::interrupts::enable_and_hlt();
+      } else {
+         x86_64::instructions::interrupts::enable();
+      }
+   }
+}
+
 pub fn hlt_loop() -> ! {
    loop{
       x86_64::instructions::hlt();
@@ -135,6 +205,9 @@ springboard_api::start!(main, config = &BOOTLOADER_CONFIG);
 /// Important memory addresses, address-space utilities.
 pub mod address;
 
+/// Local APIC and IO-APIC setup, which replace the legacy PICs when ACPI describes them.
+pub mod apic;
+
 /// Architecture-specific code.
 pub mod arch;
 
@@ -154,9 +227,18 @@ pub mod interrupts;
 /// Kernel memory management.
 pub mod memory;
 
+/// Platform discovery through the ACPI tables.
+pub mod platform;
+
+/// Poweroff and reboot, through ACPI where possible.
+pub mod power;
+
 /// Kernel-level process management.
 pub mod process;
 
+/// Bringing up the other CPUs.
+pub mod smp;
+
 #[cfg(test)]
 pub mod tests;
 
@@ -170,9 +252,9 @@ extern crate x86_64;
 
 
 use {
-   crate::memory::SystemFrameAllocator,
-   base::{log, tasks, terminal},
-   core::panic::PanicInfo,
-   springboard_api::{BootInfo, BootloaderConfig, config::Mapping},
+   base::{alloc::heap::HEAP, log, tasks, terminal},
+   memory::stack::{KernelStack, BOOT_STACK_SIZE},
+   core::{alloc::Layout, panic::PanicInfo},
+   springboard_api::{BootInfo, BootloaderConfig, config::Mapping, info::MemoryRegion},
    x86_64::VirtAddr,
 };
@@ -2,42 +2,159 @@ pub static mut IDT: InterruptDescriptorTable = InterruptDescriptorTable::new();
 
 pub fn initialise() {
    unsafe {
-      IDT.breakpoint.set_handler_fn(breakpoint);
-      IDT.double_fault.set_handler_fn(double_fault);
-      IDT.page_fault.set_handler_fn(page_fault);
+      exceptions::install(&mut IDT);
+
+      let address = |entry: unsafe extern "C" fn()| VirtAddr::new(entry as usize as u64);
+      IDT[Irq::Timer.vector() as usize].set_handler_addr(address(timer_entry));
+      IDT[Irq::Keyboard.vector() as usize].set_handler_addr(address(keyboard_entry));
+      IDT[Irq::Rtc.vector() as usize].set_handler_addr(address(rtc_entry));
+      IDT[(PIC_1_OFFSET + 7) as usize].set_handler_fn(spurious_primary);
+      IDT[(PIC_2_OFFSET + 7) as usize].set_handler_fn(spurious_secondary);
+      IDT[apic::SPURIOUS_VECTOR as usize].set_handler_fn(spurious_local);
 
       IDT.load();
    }
 
    log::info!("Added interrupt handlers to the IDT");
+
+   // Move the legacy IRQs off the exception vectors before anything can unmask them.
+   let mut pics = PICS.lock();
+   unsafe{ pics.initialise() };
+   pics.unmask(Irq::Timer);
+   pics.unmask(Irq::Keyboard);
+
+   log::info!("Remapped the legacy PICs to vectors {} and {}", PIC_1_OFFSET, PIC_2_OFFSET);
+}
+
+/// Defines the entry stub for an IRQ handler that uses per-CPU data, which swaps in the kernel's
+/// GS base if the IRQ came from user mode, saves the registers a call may clobber around `handler`
+/// and swaps back before returning.
+///
+/// The CPU pushes 40 bytes onto a 16-byte aligned stack, so the 72 bytes of registers leave it
+/// aligned again for the call.
+macro_rules! irq_entry {
+   ($name:ident, $handler:ident) => {
+      #[naked]
+      unsafe extern "C" fn $name() {
+         asm!(
+            "test byte ptr [rsp + 8], 3",
+            "jz 2f",
+            "swapgs",
+            "2:",
+            "push rax",
+            "push rcx",
+            "push rdx",
+            "push rsi",
+            "push rdi",
+            "push r8",
+            "push r9",
+            "push r10",
+            "push r11",
+            "cld",
+            "call {}",
+            "pop r11",
+            "pop r10",
+            "pop r9",
+            "pop r8",
+            "pop rdi",
+            "pop rsi",
+            "pop rdx",
+            "pop rcx",
+            "pop rax",
+            "test byte ptr [rsp + 8], 3",
+            "jz 2f",
+            "swapgs",
+            "2:",
+            "iretq",
+            sym $handler,
+            options(noreturn),
+         );
+      }
+   };
+}
+
+irq_entry!(timer_entry, timer);
+irq_entry!(keyboard_entry, keyboard);
+irq_entry!(rtc_entry, rtc);
+
+extern "C" fn timer() {
+   let _interrupt = percpu::enter_interrupt();
+
+   // Every CPU's local APIC timer lands here; counting only the boot processor's keeps the ticks
+   // at the timer's rate.
+   if percpu::current().id == 0 {
+      time::tick();
+   }
+
+   end_of_interrupt(Irq::Timer.vector());
+}
+
+extern "C" fn keyboard() {
+   let _interrupt = percpu::enter_interrupt();
+   // The controller raises the line again only once this byte has been read.
+   let scancode = unsafe{ inb(0x60) };
+   keyboard::add_scancode(scancode);
+
+   end_of_interrupt(Irq::Keyboard.vector());
+}
+
+extern "C" fn rtc() {
+   let _interrupt = percpu::enter_interrupt();
+   rtc::handle_interrupt();
+   end_of_interrupt(Irq::Rtc.vector());
 }
 
-extern "x86-interrupt" fn breakpoint(frame: InterruptStackFrame) {
-   log::error!("EXCEPTION: BREAKPOINT\n{:#?}", frame);
+extern "x86-interrupt" fn spurious_primary(_frame: InterruptStackFrame) {
+   spurious(PIC_1_OFFSET + 7);
 }
 
-extern "x86-interrupt" fn double_fault(frame: InterruptStackFrame, _: u64) -> ! {
-   log::error!("EXCEPTION: DOUBLE FAULT\n{:#?}", frame);
-   loop{}
+extern "x86-interrupt" fn spurious_secondary(_frame: InterruptStackFrame) {
+   spurious(PIC_2_OFFSET + 7);
 }
 
-extern "x86-interrupt" fn page_fault(frame: InterruptStackFrame, code: PageFaultErrorCode) {
-   use x86_64::registers::control::Cr2;
+extern "x86-interrupt" fn spurious_local(_frame: InterruptStackFrame) {
+   // The local APIC does not expect an EOI for its spurious vector.
+}
 
-   log::error!("EXCEPTION: PAGE FAULT");
-   log::error!("Accessed address: {:?}", Cr2::read());
-   log::error!("Error code: {:?}", code);
-   log::error!("{:#?}", frame);
+/// Acknowledges the interrupt on `vector` with whichever controller delivered it.
+fn end_of_interrupt(vector: u8) {
+   match apic::is_enabled() {
+      true => apic::end_of_interrupt(),
+      false => unsafe{ PICS.lock().end_of_interrupt(vector) },
+   }
+}
 
-   loop{}
+/// Handles IRQ 7 or 15, which the PICs also raise for interrupts that vanished before delivery.
+/// Those must not be acknowledged, or the PIC would drop a real interrupt of lower priority.
+fn spurious(vector: u8) {
+   let mut pics = PICS.lock();
+   unsafe {
+      if pics.is_genuine(vector) {
+         pics.end_of_interrupt(vector);
+      }
+   }
 }
 
+// MODULES //
+
+/// Entry stubs and reporting for the CPU exceptions.
+pub mod exceptions;
+
 // IMPORTS //
 
 use {
-   base::log,
-   x86_64::structures::idt::{
-      InterruptDescriptorTable, InterruptStackFrame,
-      PageFaultErrorCode,
-   }
+   crate::{
+      apic,
+      arch::x86_64::{
+         pic::{Irq, PICS, PIC_1_OFFSET, PIC_2_OFFSET},
+         rtc,
+      },
+   },
+   base::{log, percpu, tasks::keyboard, time},
+   core::arch::asm,
+   x86::io::inb,
+   x86_64::{
+      structures::idt::{InterruptDescriptorTable, InterruptStackFrame},
+      VirtAddr,
+   },
 };
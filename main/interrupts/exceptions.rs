@@ -0,0 +1,522 @@
+/// The architecturally defined exceptions the IDT has entries for.
+///
+/// Control protection (#CP) and hypervisor injection (#HV) have no entries in our `x86_64`
+/// version. Neither can be raised without CET or SEV-SNP, which the kernel does not enable.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Exception {
+   /// #DE: division by zero, or a quotient too large for its register.
+   DivideError = 0,
+
+   /// #DB: a debug register or single-step trap.
+   Debug = 1,
+
+   /// A non-maskable interrupt.
+   NonMaskableInterrupt = 2,
+
+   /// #BP: an `int3` instruction.
+   Breakpoint = 3,
+
+   /// #OF: an `into` instruction with the overflow flag set.
+   Overflow = 4,
+
+   /// #BR: a `bound` instruction out of range.
+   BoundRangeExceeded = 5,
+
+   /// #UD: an undefined or unsupported instruction.
+   InvalidOpcode = 6,
+
+   /// #NM: an x87 or SIMD instruction while they are disabled.
+   DeviceNotAvailable = 7,
+
+   /// #DF: an exception while delivering another.
+   DoubleFault = 8,
+
+   /// #TS: a bad TSS during a task switch.
+   InvalidTss = 10,
+
+   /// #NP: a segment descriptor without its present bit.
+   SegmentNotPresent = 11,
+
+   /// #SS: a stack segment limit or presence violation, or a non-canonical stack address.
+   StackSegmentFault = 12,
+
+   /// #GP: any protection violation without an exception of its own.
+   GeneralProtectionFault = 13,
+
+   /// #PF: a page translation that is missing or not allowed.
+   PageFault = 14,
+
+   /// #MF: a pending x87 floating-point error.
+   X87FloatingPoint = 16,
+
+   /// #AC: an unaligned access in user mode with alignment checking on.
+   AlignmentCheck = 17,
+
+   /// #MC: the CPU detected a hardware error.
+   MachineCheck = 18,
+
+   /// #XM: an unmasked SIMD floating-point error.
+   SimdFloatingPoint = 19,
+
+   /// #VE: an EPT violation reported to the guest.
+   Virtualization = 20,
+
+   /// #VC: a VMM communication exception under SEV-ES.
+   VmmCommunication = 29,
+
+   /// #SX: a security event under SVM.
+   Security = 30,
+}
+
+impl Exception {
+   /// The exception raised on `vector`, if there is one.
+   pub fn from_vector(vector: u8) -> Option<Exception> {
+      return EXCEPTIONS.iter().copied().find(|exception| *exception as u8 == vector);
+   }
+
+   /// The assembler mnemonic, such as `#GP`.
+   pub fn mnemonic(self) -> &'static str {
+      return match self {
+         Exception::DivideError => "#DE",
+         Exception::Debug => "#DB",
+         Exception::NonMaskableInterrupt => "NMI",
+         Exception::Breakpoint => "#BP",
+         Exception::Overflow => "#OF",
+         Exception::BoundRangeExceeded => "#BR",
+         Exception::InvalidOpcode => "#UD",
+         Exception::DeviceNotAvailable => "#NM",
+         Exception::DoubleFault => "#DF",
+         Exception::InvalidTss => "#TS",
+         Exception::SegmentNotPresent => "#NP",
+         Exception::StackSegmentFault => "#SS",
+         Exception::GeneralProtectionFault => "#GP",
+         Exception::PageFault => "#PF",
+         Exception::X87FloatingPoint => "#MF",
+         Exception::AlignmentCheck => "#AC",
+         Exception::MachineCheck => "#MC",
+         Exception::SimdFloatingPoint => "#XM",
+         Exception::Virtualization => "#VE",
+         Exception::VmmCommunication => "#VC",
+         Exception::Security => "#SX",
+      };
+   }
+
+   /// Returns `true` if the CPU pushes an error code for this exception.
+   pub fn has_error_code(self) -> bool {
+      return matches!(
+         self,
+         Exception::DoubleFault
+         | Exception::InvalidTss
+         | Exception::SegmentNotPresent
+         | Exception::StackSegmentFault
+         | Exception::GeneralProtectionFault
+         | Exception::PageFault
+         | Exception::AlignmentCheck
+         | Exception::VmmCommunication
+         | Exception::Security
+      );
+   }
+
+   /// Returns `true` if the error code names the segment selector at fault.
+   pub fn has_selector_error_code(self) -> bool {
+      return matches!(
+         self,
+         Exception::InvalidTss
+         | Exception::SegmentNotPresent
+         | Exception::StackSegmentFault
+         | Exception::GeneralProtectionFault
+      );
+   }
+
+   /// Returns `true` if the interrupted code can simply carry on once the exception is reported.
+   pub fn is_benign(self) -> bool {
+      return matches!(self, Exception::Debug | Exception::NonMaskableInterrupt | Exception::Breakpoint);
+   }
+}
+
+impl Display for Exception {
+   fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+      return write!(f, "{} ({:?}, vector {})", self.mnemonic(), self, *self as u8);
+   }
+}
+
+const EXCEPTIONS: [Exception; 21] = [
+   Exception::DivideError,
+   Exception::Debug,
+   Exception::NonMaskableInterrupt,
+   Exception::Breakpoint,
+   Exception::Overflow,
+   Exception::BoundRangeExceeded,
+   Exception::InvalidOpcode,
+   Exception::DeviceNotAvailable,
+   Exception::DoubleFault,
+   Exception::InvalidTss,
+   Exception::SegmentNotPresent,
+   Exception::StackSegmentFault,
+   Exception::GeneralProtectionFault,
+   Exception::PageFault,
+   Exception::X87FloatingPoint,
+   Exception::AlignmentCheck,
+   Exception::MachineCheck,
+   Exception::SimdFloatingPoint,
+   Exception::Virtualization,
+   Exception::VmmCommunication,
+   Exception::Security,
+];
+
+/// Everything the entry stubs save, lowest address first: the general-purpose registers, the
+/// vector, the error code (zero for exceptions without one) and the frame the CPU pushed.
+#[derive(Debug)]
+#[repr(C)]
+pub struct ExceptionContext {
+   pub r15: u64,
+   pub r14: u64,
+   pub r13: u64,
+   pub r12: u64,
+   pub r11: u64,
+   pub r10: u64,
+   pub r9: u64,
+   pub r8: u64,
+   pub rbp: u64,
+   pub rdi: u64,
+   pub rsi: u64,
+   pub rdx: u64,
+   pub rcx: u64,
+   pub rbx: u64,
+   pub rax: u64,
+   pub vector: u64,
+   pub error_code: u64,
+   pub frame: InterruptStackFrameValue,
+}
+
+impl ExceptionContext {
+   /// Returns `true` if the exception came from ring 3.
+   pub fn from_user_mode(&self) -> bool {
+      return self.frame.code_segment & 0b11 == 3;
+   }
+}
+
+impl Display for ExceptionContext {
+   /// Dumps the saved frame and general-purpose registers, then the control registers as they
+   /// are now.
+   fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+      writeln!(f, "RIP {:#018x}  CS  {:#06x}  RFLAGS {:#010x}", self.frame.instruction_pointer.as_u64(), self.frame.code_segment, self.frame.cpu_flags)?;
+      writeln!(f, "RSP {:#018x}  SS  {:#06x}", self.frame.stack_pointer.as_u64(), self.frame.stack_segment)?;
+      writeln!(f, "RAX {:#018x}  RBX {:#018x}  RCX {:#018x}", self.rax, self.rbx, self.rcx)?;
+      writeln!(f, "RDX {:#018x}  RSI {:#018x}  RDI {:#018x}", self.rdx, self.rsi, self.rdi)?;
+      writeln!(f, "RBP {:#018x}  R8  {:#018x}  R9  {:#018x}", self.rbp, self.r8, self.r9)?;
+      writeln!(f, "R10 {:#018x}  R11 {:#018x}  R12 {:#018x}", self.r10, self.r11, self.r12)?;
+      writeln!(f, "R13 {:#018x}  R14 {:#018x}  R15 {:#018x}", self.r13, self.r14, self.r15)?;
+
+      let (l4table, cr3flags) = Cr3::read_raw();
+      writeln!(f, "CR0 {:#018x}  CR2 {:#018x}", Cr0::read_raw(), Cr2::read().as_u64())?;
+      writeln!(f, "CR3 {:#018x}  CR4 {:#018x}", l4table.start_address().as_u64() | cr3flags as u64, Cr4::read_raw())?;
+      write!(f, "EFER {:#x}", Efer::read_raw())?;
+
+      return Ok(());
+   }
+}
+
+/// A segment selector error code, as pushed by #TS, #NP, #SS and #GP.
+#[derive(Copy, Clone, Debug)]
+pub struct SelectorErrorCode(pub u64);
+
+impl SelectorErrorCode {
+   /// Returns `true` if the fault happened while delivering an external event.
+   pub fn external(&self) -> bool {
+      return self.0 & 1 != 0;
+   }
+
+   /// The descriptor table the selector indexes: GDT, IDT or LDT.
+   pub fn table(&self) -> &'static str {
+      return match (self.0 >> 1) & 0b11 {
+         0 => "GDT",
+         2 => "LDT",
+         _ => "IDT",
+      };
+   }
+
+   /// The descriptor index the selector names.
+   pub fn index(&self) -> u64 {
+      return (self.0 >> 3) & 0x1FFF;
+   }
+}
+
+impl Display for SelectorErrorCode {
+   fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+      if self.0 == 0 {
+         return write!(f, "not selector-related");
+      }
+
+      return write!(
+         f,
+         "{} index {} (selector {:#x}){}",
+         self.table(),
+         self.index(),
+         self.0 & 0xFFF8,
+         if self.external() { ", external event" } else { "" },
+      );
+   }
+}
+
+/// What happens to the code that took an exception once it has been reported.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FaultAction {
+   /// Carry on where it left off.
+   Resume,
+
+   /// Kill the task that faulted and leave the kernel running.
+   KillTask,
+
+   /// Stop the kernel.
+   Halt,
+}
+
+/// Points every exception entry in `idt` at its entry stub. Double faults run on their own stack.
+pub fn install(idt: &mut InterruptDescriptorTable) {
+   let address = |entry: unsafe extern "C" fn()| VirtAddr::new(entry as usize as u64);
+
+   unsafe {
+      idt.divide_error.set_handler_addr(address(divide_error));
+      idt.debug.set_handler_addr(address(debug));
+      idt.non_maskable_interrupt.set_handler_addr(address(non_maskable_interrupt));
+      idt.breakpoint.set_handler_addr(address(breakpoint));
+      idt.overflow.set_handler_addr(address(overflow));
+      idt.bound_range_exceeded.set_handler_addr(address(bound_range_exceeded));
+      idt.invalid_opcode.set_handler_addr(address(invalid_opcode));
+      idt.device_not_available.set_handler_addr(address(device_not_available));
+      idt.double_fault.set_handler_addr(address(double_fault)).set_stack_index(DOUBLE_FAULT_IST_INDEX);
+      idt.invalid_tss.set_handler_addr(address(invalid_tss));
+      idt.segment_not_present.set_handler_addr(address(segment_not_present));
+      idt.stack_segment_fault.set_handler_addr(address(stack_segment_fault));
+      idt.general_protection_fault.set_handler_addr(address(general_protection_fault));
+      idt.page_fault.set_handler_addr(address(page_fault));
+      idt.x87_floating_point.set_handler_addr(address(x87_floating_point));
+      idt.alignment_check.set_handler_addr(address(alignment_check));
+      idt.machine_check.set_handler_addr(address(machine_check));
+      idt.simd_floating_point.set_handler_addr(address(simd_floating_point));
+      idt.virtualization.set_handler_addr(address(virtualization));
+      idt.vmm_communication_exception.set_handler_addr(address(vmm_communication));
+      idt.security_exception.set_handler_addr(address(security));
+   }
+}
+
+/// Called by the entry stubs with everything they saved. Whatever is left in `context` when this
+/// returns is restored into the interrupted code.
+extern "C" fn dispatch(context: &mut ExceptionContext) {
+   let _interrupt = percpu::enter_interrupt();
+
+   let exception = match Exception::from_vector(context.vector as u8) {
+      Some(exception) => exception,
+      None => {
+         log::error!("EXCEPTION: unknown vector {}\n{}", context.vector, context);
+         halt();
+      }
+   };
+
+   // A not-present fault inside a lazily-backed region is just the first touch of that page.
+   let mut reason = None;
+   if exception == Exception::PageFault {
+      let code = PageFaultErrorCode::from_bits_truncate(context.error_code);
+      if !code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+         match space::resolve_fault(Cr2::read()) {
+            Ok(()) => return,
+            Err(error) => reason = Some(error),
+         }
+      }
+   }
+
+   report(exception, context);
+
+   if exception == Exception::PageFault {
+      match reason {
+         Some(reason) => log::error!("Unresolved: {}", reason),
+         None => log::error!("Unresolved: protection violation"),
+      }
+   }
+
+   match decide(exception, context) {
+      FaultAction::Resume => {},
+      FaultAction::KillTask => {
+         // There are no user tasks to kill yet, so a fault in one is as fatal as a kernel fault.
+         log::error!("Cannot kill the faulting task; halting");
+         halt();
+      }
+      FaultAction::Halt => halt(),
+   }
+}
+
+/// Logs `exception`, its decoded error code and the register dump.
+pub fn report(exception: Exception, context: &ExceptionContext) {
+   log::error!("EXCEPTION: {} in {} mode", exception, if context.from_user_mode() { "user" } else { "kernel" });
+
+   if exception.has_selector_error_code() {
+      log::error!("Error code {:#x}: {}", context.error_code, SelectorErrorCode(context.error_code));
+   } else if exception == Exception::PageFault {
+      let code = PageFaultErrorCode::from_bits_truncate(context.error_code);
+      log::error!("Accessed address: {:?}", Cr2::read());
+      log::error!("Error code: {:?}", code);
+      log::error!(
+         "Access: {} {} in {} mode",
+         if code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) { "instruction fetch" }
+         else if code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) { "write" }
+         else { "read" },
+         if code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) { "of a present page" }
+         else { "of a non-present page" },
+         if code.contains(PageFaultErrorCode::USER_MODE) { "user" } else { "kernel" },
+      );
+   } else if exception == Exception::DoubleFault {
+      // Overflowing a kernel stack faults on its guard page, and that fault cannot be delivered on
+      // the same stack, so it ends up here on the double-fault stack.
+      let address = Cr2::read();
+      match space::guard_owner(context.frame.stack_pointer).or_else(|| space::guard_owner(address)) {
+         Some(name) => log::error!("Kernel stack overflow: `{}` ran into its guard page at {:?}", name, address),
+         None => log::error!("Last page fault address: {:?}", address),
+      }
+   } else if exception.has_error_code() {
+      log::error!("Error code: {:#x}", context.error_code);
+   }
+
+   log::error!("\n{}", context);
+}
+
+/// Decides what becomes of the code that took `exception`.
+fn decide(exception: Exception, context: &ExceptionContext) -> FaultAction {
+   if exception.is_benign() {
+      return FaultAction::Resume;
+   }
+
+   // A double or machine check fault leaves no trustworthy state to go back to.
+   if context.from_user_mode() && !matches!(exception, Exception::DoubleFault | Exception::MachineCheck) {
+      return FaultAction::KillTask;
+   }
+
+   return FaultAction::Halt;
+}
+
+fn halt() -> ! {
+   loop {
+      x86_64::instructions::interrupts::disable();
+      x86_64::instructions::hlt();
+   }
+}
+
+/// Saves the general-purpose registers below the vector and error code the exception's stub
+/// pushed, calls [`dispatch`], and returns from the exception with whatever it left behind.
+///
+/// Every path into here pushes 56 bytes onto a 16-byte aligned stack, so the 120 bytes of
+/// registers leave the stack aligned again for the call.
+///
+/// An exception from user mode arrives with the user's GS base loaded, so the kernel's per-CPU
+/// base is swapped in on the way in and back out on the way out; the saved CS says which.
+#[naked]
+unsafe extern "C" fn common_entry() {
+   asm!(
+      "test byte ptr [rsp + 24], 3",
+      "jz 2f",
+      "swapgs",
+      "2:",
+      "push rax",
+      "push rbx",
+      "push rcx",
+      "push rdx",
+      "push rsi",
+      "push rdi",
+      "push rbp",
+      "push r8",
+      "push r9",
+      "push r10",
+      "push r11",
+      "push r12",
+      "push r13",
+      "push r14",
+      "push r15",
+      "mov rdi, rsp",
+      "cld",
+      "call {}",
+      "pop r15",
+      "pop r14",
+      "pop r13",
+      "pop r12",
+      "pop r11",
+      "pop r10",
+      "pop r9",
+      "pop r8",
+      "pop rbp",
+      "pop rdi",
+      "pop rsi",
+      "pop rdx",
+      "pop rcx",
+      "pop rbx",
+      "pop rax",
+      "test byte ptr [rsp + 24], 3",
+      "jz 2f",
+      "swapgs",
+      "2:",
+      // Drop the vector and error code.
+      "add rsp, 16",
+      "iretq",
+      sym dispatch,
+      options(noreturn),
+   );
+}
+
+/// Defines the entry stub for an exception, which pushes a zero error code if the CPU did not,
+/// then the vector, and jumps to [`common_entry`].
+macro_rules! exception_entry {
+   ($name:ident, $vector:literal) => {
+      #[naked]
+      unsafe extern "C" fn $name() {
+         asm!("push 0", concat!("push ", $vector), "jmp {}", sym common_entry, options(noreturn));
+      }
+   };
+
+   ($name:ident, $vector:literal, error_code) => {
+      #[naked]
+      unsafe extern "C" fn $name() {
+         asm!(concat!("push ", $vector), "jmp {}", sym common_entry, options(noreturn));
+      }
+   };
+}
+
+exception_entry!(divide_error, 0);
+exception_entry!(debug, 1);
+exception_entry!(non_maskable_interrupt, 2);
+exception_entry!(breakpoint, 3);
+exception_entry!(overflow, 4);
+exception_entry!(bound_range_exceeded, 5);
+exception_entry!(invalid_opcode, 6);
+exception_entry!(device_not_available, 7);
+exception_entry!(double_fault, 8, error_code);
+exception_entry!(invalid_tss, 10, error_code);
+exception_entry!(segment_not_present, 11, error_code);
+exception_entry!(stack_segment_fault, 12, error_code);
+exception_entry!(general_protection_fault, 13, error_code);
+exception_entry!(page_fault, 14, error_code);
+exception_entry!(x87_floating_point, 16);
+exception_entry!(alignment_check, 17, error_code);
+exception_entry!(machine_check, 18);
+exception_entry!(simd_floating_point, 19);
+exception_entry!(virtualization, 20);
+exception_entry!(vmm_communication, 29, error_code);
+exception_entry!(security, 30, error_code);
+
+// IMPORTS //
+
+use {
+   crate::{address::space, gdt::DOUBLE_FAULT_IST_INDEX},
+   base::{log, percpu},
+   core::{
+      arch::asm,
+      fmt::{Display, Formatter, Result as FmtResult},
+   },
+   x86_64::{
+      registers::{
+         control::{Cr0, Cr2, Cr3, Cr4},
+         model_specific::Efer,
+      },
+      structures::idt::{InterruptDescriptorTable, InterruptStackFrameValue, PageFaultErrorCode},
+      VirtAddr,
+   },
+};
@@ -1,39 +1,64 @@
-pub unsafe fn initialise(physical_offset: VirtAddr) -> OffsetPageTable<'static> {
+/// The kernel's page-table mapper, set up by [`initialise`].
+pub static MAPPER: Mutex<Option<OffsetPageTable<'static>>> = Mutex::new(None);
+
+/// The physical frame allocator, set up by [`initialise`].
+pub static FRAME_ALLOCATOR: Mutex<Option<SystemFrameAllocator>> = Mutex::new(None);
+
+/// Sets up the kernel mapper and the frame allocator, and sets the DMA pool aside.
+///
+/// ## Safety
+///
+/// The caller must guarantee that the complete physical memory is mapped at `physical_offset`,
+/// and that this is only called once.
+pub unsafe fn initialise(physical_offset: VirtAddr, memory_map: &'static [MemoryRegion]) {
    let l4table = active_l4_page_table(physical_offset);
 
    log::info!("Got the level four page table.");
 
-   return OffsetPageTable::new(l4table, physical_offset);
+   // The bootloader normally leaves this on already, but everything mapped from here on may be
+   // non-executable, and the bit is reserved until NXE is set.
+   assert!(no_execute_supported(), "the CPU cannot mark pages non-executable");
+   Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE));
+
+   initialise_page_attributes();
+
+   *MAPPER.lock() = Some(OffsetPageTable::new(l4table, physical_offset));
+   *FRAME_ALLOCATOR.lock() = Some(SystemFrameAllocator::new(memory_map, physical_offset));
+
+   reserve_dma_pool();
 }
 
-pub fn build_heap(
-   mapper: &mut impl Mapper<Size4KiB>,
-   frame_allocator: &mut impl FrameAllocator<Size4KiB>,
-) -> Result<(), MapToError<Size4KiB>> {
+/// Runs `f` with the kernel mapper and frame allocator locked, in that order.
+pub fn with_kernel_memory<R>(
+   f: impl FnOnce(&mut OffsetPageTable<'static>, &mut SystemFrameAllocator) -> R,
+) -> R {
+   let mut mapper = MAPPER.lock();
+   let mut frames = FRAME_ALLOCATOR.lock();
+
+   return f(
+      mapper.as_mut().expect("must first initialise the kernel mapper"),
+      frames.as_mut().expect("must first initialise the frame allocator"),
+   );
+}
+
+/// Reserves the heap's virtual window, maps its first [`HEAP_SIZE`] bytes and lets it grow into
+/// the rest of the window as it needs to.
+pub fn build_heap() -> Result<(), RegionError> {
    let mut heap = HEAP.lock();
    let start_address = HEAP_START;
-   let end_address = HEAP_START + HEAP_SIZE - 1usize;
-
-   let page_range = {
-      let heap_start_page = Page::containing_address(VirtAddr::new(start_address as u64));
-      let heap_end_page = Page::containing_address(VirtAddr::new(end_address as u64));
-      Page::range_inclusive(heap_start_page, heap_end_page)
-   };
+   let end_address = HEAP_START + HEAP_SIZE;
 
-   for page in page_range {
-      let frame = frame_allocator
-         .allocate_frame()
-         .ok_or(MapToError::FrameAllocationFailed)?;
+   let mut space = KERNEL_SPACE.lock();
+   let start = space.reserve_at("heap", VirtAddr::new(start_address as u64), HEAP_RESERVE as u64, Backing::Reserved)?;
 
-      let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-      unsafe {
-         mapper.map_to(page, frame, flags, frame_allocator)?.flush();
-      }
-   }
+   // Lazy only so that each growth can commit more of the window; every page is mapped up front.
+   space.map_lazy(start, HEAP_FLAGS)?;
+   with_kernel_memory(|mapper, frames| space.commit(start, 0, HEAP_SIZE as u64, HEAP_FLAGS, mapper, frames))?;
 
    unsafe {
       let mut blocks = Heap::new();
       blocks.add_to_heap(start_address, end_address);
+      blocks.set_extender(extend_heap);
 
       *heap = Some(blocks);
    }
@@ -43,6 +68,204 @@ pub fn build_heap(
    return Ok(());
 }
 
+/// Flags the heap is mapped with.
+const HEAP_FLAGS: PageTableFlags = PageTableFlags::PRESENT
+   .union(PageTableFlags::WRITABLE)
+   .union(PageTableFlags::NO_EXECUTE);
+
+/// Grows the heap by at least `size` bytes past `end`, within the heap's reserved window.
+///
+/// The new pages are mapped here rather than on first touch: the page-fault handler cannot wait
+/// for the address space or the mapper, so with other CPUs taking those locks a lazily-backed
+/// heap page could fault at a moment it cannot be resolved. Called with the heap locked, which
+/// comes before the address space, the mapper and the frame allocator in the lock order.
+fn extend_heap(end: usize, size: usize) -> Option<usize> {
+   let size = align_up(max(size, HEAP_GROWTH) as u64, Size4KiB::SIZE) as usize;
+
+   if end + size > HEAP_START + HEAP_RESERVE {
+      log::warn!("heap window exhausted; cannot grow by {} bytes", size);
+      return None;
+   }
+
+   let mut space = KERNEL_SPACE.lock();
+   let start = VirtAddr::new(HEAP_START as u64);
+   let committed = with_kernel_memory(|mapper, frames| {
+      space.commit(start, (end - HEAP_START) as u64, size as u64, HEAP_FLAGS, mapper, frames)
+   });
+
+   if let Err(error) = committed {
+      log::warn!("failed to map {} more bytes of heap: {}", size, error);
+      return None;
+   }
+
+   return Some(end + size);
+}
+
+/// Flags for kernel code: read-only and executable.
+const KERNEL_CODE_FLAGS: PageTableFlags = PageTableFlags::PRESENT;
+
+/// Flags for kernel read-only data, including data that is only written during relocation.
+const KERNEL_RODATA_FLAGS: PageTableFlags = PageTableFlags::PRESENT.union(PageTableFlags::NO_EXECUTE);
+
+/// Flags for kernel data and bss.
+const KERNEL_DATA_FLAGS: PageTableFlags = PageTableFlags::PRESENT
+   .union(PageTableFlags::WRITABLE)
+   .union(PageTableFlags::NO_EXECUTE);
+
+/// Remaps the kernel image so that no page is both writable and executable.
+///
+/// The segments are read from the kernel's ELF program headers, which the bootloader leaves in
+/// memory: code becomes read-only and executable, read-only data (and the relocation read-only
+/// range) read-only and non-executable, and data and bss writable and non-executable. Each
+/// segment is also recorded in the kernel address space. The physical memory window and the
+/// framebuffer are made non-executable as well.
+pub fn protect_kernel(info: &BootInfo) -> Result<(), RegionError> {
+   let mut space = KERNEL_SPACE.lock();
+
+   with_kernel_memory(|mapper, _| {
+      let image = unsafe {
+         let start = mapper.phys_offset() + info.kernel_addr;
+         slice::from_raw_parts(start.as_ptr::<u8>(), info.kernel_len as usize)
+      };
+
+      let headers = match ProgramHeaders::new(image) {
+         Some(headers) => headers,
+         None => {
+            log::warn!("Kernel image is not a 64-bit ELF file; leaving its mappings alone.");
+            return Ok(());
+         }
+      };
+
+      let relro = headers.clone().find(|header| header.kind == PT_GNU_RELRO);
+
+      for header in headers.filter(|header| header.kind == PT_LOAD) {
+         let start = VirtAddr::new(info.kernel_image_offset + header.virtual_address);
+         let end = start + header.memory_size;
+
+         let (start, end) = (start.align_down(Size4KiB::SIZE), end.align_up(Size4KiB::SIZE));
+
+         if header.flags & PF_X != 0 {
+            protect_range(&mut space, mapper, "kernel-code", start, end, KERNEL_CODE_FLAGS)?;
+         } else if header.flags & PF_W == 0 {
+            protect_range(&mut space, mapper, "kernel-rodata", start, end, KERNEL_RODATA_FLAGS)?;
+         } else {
+            // The relocation read-only range sits at the start of the writable segment.
+            let mut data = start;
+
+            if let Some(relro) = relro {
+               let relroStart = VirtAddr::new(info.kernel_image_offset + relro.virtual_address);
+               let relroEnd = (relroStart + relro.memory_size).align_down(Size4KiB::SIZE);
+
+               if relroStart.align_down(Size4KiB::SIZE) == start && relroEnd > start && relroEnd <= end {
+                  protect_range(&mut space, mapper, "kernel-relro", start, relroEnd, KERNEL_RODATA_FLAGS)?;
+                  data = relroEnd;
+               }
+            }
+
+            protect_range(&mut space, mapper, "kernel-data", data, end, KERNEL_DATA_FLAGS)?;
+         }
+      }
+
+      for name in ["physical-memory", "framebuffer"] {
+         let start = space.regions().find(|region| region.name == name).map(|region| region.start);
+         if let Some(start) = start {
+            space.protect(start, KERNEL_DATA_FLAGS, mapper)?;
+         }
+      }
+
+      log::info!("Kernel image remapped write-xor-execute.");
+      return Ok(());
+   })
+}
+
+/// Records `start..end` of the kernel image in the address space and applies `flags` to it.
+fn protect_range(
+   space: &mut AddressSpace,
+   mapper: &mut OffsetPageTable<'static>,
+   name: &'static str,
+   start: VirtAddr,
+   end: VirtAddr,
+   flags: PageTableFlags,
+) -> Result<(), RegionError> {
+   if end <= start {
+      return Ok(());
+   }
+
+   space.reserve_at(name, start, end - start, Backing::Fixed)?;
+   space.protect(start, flags, mapper)?;
+
+   return Ok(());
+}
+
+/// A loadable segment.
+const PT_LOAD: u32 = 1;
+
+/// The range that is read-only once relocations have been applied.
+const PT_GNU_RELRO: u32 = 0x6474_e552;
+
+/// Segment is executable.
+const PF_X: u32 = 1;
+
+/// Segment is writable.
+const PF_W: u32 = 2;
+
+/// An ELF64 program header.
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+struct ProgramHeader {
+   kind: u32,
+   flags: u32,
+   offset: u64,
+   virtual_address: u64,
+   physical_address: u64,
+   file_size: u64,
+   memory_size: u64,
+   align: u64,
+}
+
+/// Iterates over the program headers of an ELF64 image.
+#[derive(Clone)]
+struct ProgramHeaders<'a> {
+   image: &'a [u8],
+   offset: usize,
+   entry_size: usize,
+   remaining: usize,
+}
+
+impl<'a> ProgramHeaders<'a> {
+   fn new(image: &'a [u8]) -> Option<Self> {
+      if image.len() < 64 || image[..4] != *b"\x7fELF" || image[4] != 2 {
+         return None;
+      }
+
+      let offset = u64::from_le_bytes(image[0x20..0x28].try_into().ok()?) as usize;
+      let entry_size = u16::from_le_bytes(image[0x36..0x38].try_into().ok()?) as usize;
+      let count = u16::from_le_bytes(image[0x38..0x3a].try_into().ok()?) as usize;
+
+      if entry_size < size_of::<ProgramHeader>() || offset + entry_size * count > image.len() {
+         return None;
+      }
+
+      return Some(ProgramHeaders{ image, offset, entry_size, remaining: count });
+   }
+}
+
+impl Iterator for ProgramHeaders<'_> {
+   type Item = ProgramHeader;
+
+   fn next(&mut self) -> Option<Self::Item> {
+      if self.remaining == 0 {
+         return None;
+      }
+
+      let header = unsafe{ ptr::read_unaligned(self.image[self.offset..].as_ptr() as *const ProgramHeader) };
+      self.offset += self.entry_size;
+      self.remaining -= 1;
+
+      return Some(header);
+   }
+}
+
 pub unsafe fn active_l4_page_table(phys_offset: VirtAddr) -> &'static mut PageTable {
    use x86_64::registers::control::Cr3;
 
@@ -58,11 +281,13 @@ pub unsafe fn active_l4_page_table(phys_offset: VirtAddr) -> &'static mut PageTa
 /// Translates the given virtual address to the mapped physical address, or
 /// `None` if the address is not mapped.
 ///
+/// Huge entries are honoured at every level: a 1 GiB entry in the level 3 table and a 2 MiB entry
+/// in the level 2 table both end the walk early.
+///
 /// This function is unsafe because the caller must guarantee that the
 /// complete physical memory is mapped to virtual memory at the passed
 /// `physical_memory_offset`.
 pub fn translate_address(address: VirtAddr, physical_offset: VirtAddr) -> Option<PhysAddr> {
-   use x86_64::structures::paging::page_table::FrameError;
    use x86_64::registers::control::Cr3;
 
    let (l4Frame, _) = Cr3::read();
@@ -74,40 +299,738 @@ pub fn translate_address(address: VirtAddr, physical_offset: VirtAddr) -> Option
       address.p1_index(),
    ];
 
-   let mut frame = l4Frame;
+   let mut frame = l4Frame.start_address();
 
    // Traverse the multi-level page table.
-   for &index in &tableIndices {
+   for (level, &index) in tableIndices.iter().enumerate() {
       // convert the frame into a page table reference
-      let virt = physical_offset + frame.start_address().as_u64();
+      let virt = physical_offset + frame.as_u64();
       let table_pointer: *const PageTable = virt.as_ptr();
       let table = unsafe {&*table_pointer};
 
       // read the page table entry and update `frame`
       let entry = &table[index];
-      frame = match entry.frame() {
-         Ok(frame) => frame,
-         Err(FrameError::FrameNotPresent) => return None,
-         Err(FrameError::HugeFrame) => panic!("huge pages not supported"),
+      if !entry.flags().contains(PageTableFlags::PRESENT) {
+         return None;
+      }
+
+      if entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+         let mask = match level {
+            1 => Size1GiB::SIZE - 1,
+            2 => Size2MiB::SIZE - 1,
+            // The huge bit is reserved in the level 4 table and means PAT in the level 1 table.
+            _ => return None,
+         };
+
+         // Bit 12 of a huge entry's address is its PAT bit, not part of the frame.
+         return Some(PhysAddr::new(entry.addr().as_u64() & !mask) + (address.as_u64() & mask));
+      }
+
+      frame = entry.addr();
+   }
+
+   return Some(frame + u64::from(address.page_offset()));
+}
+
+/// Returns `true` if the CPU can mark pages non-executable.
+pub fn no_execute_supported() -> bool {
+   return CpuId::new()
+      .get_extended_processor_and_feature_identifiers()
+      .map_or(false, |features| features.has_execute_disable());
+}
+
+/// Returns `true` if the CPU can map 1 GiB pages.
+pub fn gigantic_pages_supported() -> bool {
+   return CpuId::new()
+      .get_extended_processor_and_feature_identifiers()
+      .map_or(false, |features| features.has_1gib_pages());
+}
+
+/// Maps `size` bytes of physical memory starting at `physical` to `start`, using the largest page
+/// size that the alignment of both addresses and the remaining length allow.
+///
+/// Large physical windows such as the framebuffer are mapped with 2 MiB and 1 GiB pages where
+/// possible, which keeps page-table overhead and TLB pressure down.
+///
+/// ## Safety
+///
+/// The caller must ensure that the physical range may be aliased at `start`.
+pub unsafe fn map_physical_range(
+   mapper: &mut (impl MapperAllSizes + Sized),
+   start: VirtAddr,
+   physical: PhysAddr,
+   size: u64,
+   flags: PageTableFlags,
+   frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+   let gigantic = gigantic_pages_supported();
+   let mut offset = 0;
+
+   while offset < size {
+      let virt = start + offset;
+      let phys = physical + offset;
+      let remaining = size - offset;
+
+      offset += if gigantic && fits::<Size1GiB>(virt, phys, remaining) {
+         let page = Page::<Size1GiB>::containing_address(virt);
+         let frame = PhysFrame::<Size1GiB>::containing_address(phys);
+         mapper.map_to(page, frame, flags | PageTableFlags::HUGE_PAGE, frame_allocator)
+            .map_err(demote)?
+            .flush();
+
+         Size1GiB::SIZE
+      } else if fits::<Size2MiB>(virt, phys, remaining) {
+         let page = Page::<Size2MiB>::containing_address(virt);
+         let frame = PhysFrame::<Size2MiB>::containing_address(phys);
+         mapper.map_to(page, frame, flags | PageTableFlags::HUGE_PAGE, frame_allocator)
+            .map_err(demote)?
+            .flush();
+
+         Size2MiB::SIZE
+      } else {
+         let page = Page::<Size4KiB>::containing_address(virt);
+         let frame = PhysFrame::<Size4KiB>::containing_address(phys);
+         mapper.map_to(page, frame, flags, frame_allocator)?.flush();
+
+         Size4KiB::SIZE
+      };
+   }
+
+   return Ok(());
+}
+
+/// Backs `size` bytes starting at `start` with freshly allocated frames.
+///
+/// Wherever the virtual address is 2 MiB-aligned and at least 2 MiB remain, a physically
+/// contiguous 2 MiB frame is requested and mapped as a huge page; otherwise, or if no contiguous
+/// run is available, the range falls back to 4 KiB pages. If mapping fails partway, everything
+/// mapped so far is unmapped and its frames given back.
+pub fn map_anonymous(
+   mapper: &mut (impl MapperAllSizes + Sized),
+   start: VirtAddr,
+   size: u64,
+   flags: PageTableFlags,
+   frame_allocator: &mut SystemFrameAllocator,
+) -> Result<(), MapToError<Size4KiB>> {
+   const HUGE_FRAMES: usize = (Size2MiB::SIZE / Size4KiB::SIZE) as usize;
+
+   let mut offset = 0;
+
+   while offset < size {
+      let virt = start + offset;
+      let remaining = size - offset;
+
+      if virt.is_aligned(Size2MiB::SIZE) && remaining >= Size2MiB::SIZE {
+         if let Some(first) = frame_allocator.allocate_contiguous(HUGE_FRAMES, HUGE_FRAMES) {
+            let page = Page::<Size2MiB>::containing_address(virt);
+            let frame = PhysFrame::<Size2MiB>::containing_address(first.start_address());
+
+            match unsafe {
+               mapper.map_to(page, frame, flags | PageTableFlags::HUGE_PAGE, frame_allocator)
+            } {
+               Ok(flush) => flush.flush(),
+               Err(error) => {
+                  unsafe { frame_allocator.deallocate_contiguous(first, HUGE_FRAMES) };
+                  unmap_anonymous(mapper, start, offset, frame_allocator);
+                  return Err(demote(error));
+               }
+            }
+
+            offset += Size2MiB::SIZE;
+            continue;
+         }
+      }
+
+      let page = Page::<Size4KiB>::containing_address(virt);
+      let frame = match frame_allocator.allocate_frame() {
+         Some(frame) => frame,
+         None => {
+            unmap_anonymous(mapper, start, offset, frame_allocator);
+            return Err(MapToError::FrameAllocationFailed);
+         }
       };
+
+      match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+         Ok(flush) => flush.flush(),
+         Err(error) => {
+            unsafe { frame_allocator.deallocate_frame(frame) };
+            unmap_anonymous(mapper, start, offset, frame_allocator);
+            return Err(error);
+         }
+      }
+
+      offset += Size4KiB::SIZE;
+   }
+
+   return Ok(());
+}
+
+/// Undoes [`map_anonymous`] for the `size` bytes at `start`, freeing the frames behind them.
+/// Unmapped pages in the range are skipped.
+fn unmap_anonymous(
+   mapper: &mut (impl MapperAllSizes + Sized),
+   start: VirtAddr,
+   size: u64,
+   frame_allocator: &mut SystemFrameAllocator,
+) {
+   let mut offset = 0;
+
+   while offset < size {
+      let virt = start + offset;
+
+      // A huge page only ever sits at a 2 MiB boundary, and unmapping one fails cleanly if the
+      // range there was mapped with 4 KiB pages instead.
+      if virt.is_aligned(Size2MiB::SIZE) && size - offset >= Size2MiB::SIZE {
+         if let Ok((frame, flush)) = mapper.unmap(Page::<Size2MiB>::containing_address(virt)) {
+            flush.flush();
+            let first = PhysFrame::containing_address(frame.start_address());
+            unsafe { frame_allocator.deallocate_contiguous(first, (Size2MiB::SIZE / Size4KiB::SIZE) as usize) };
+
+            offset += Size2MiB::SIZE;
+            continue;
+         }
+      }
+
+      if let Ok((frame, flush)) = mapper.unmap(Page::<Size4KiB>::containing_address(virt)) {
+         flush.flush();
+         unsafe { frame_allocator.deallocate_frame(frame) };
+      }
+
+      offset += Size4KiB::SIZE;
+   }
+}
+
+/// Whether a page of size `S` can map `virt` to `phys` without overrunning `remaining` bytes.
+fn fits<S: PageSize>(virt: VirtAddr, phys: PhysAddr, remaining: u64) -> bool {
+   return virt.is_aligned(S::SIZE) && phys.is_aligned(S::SIZE) && remaining >= S::SIZE;
+}
+
+/// Converts a huge-page mapping error into the 4 KiB error the mapping API reports.
+fn demote<S: PageSize>(error: MapToError<S>) -> MapToError<Size4KiB> {
+   return match error {
+      MapToError::FrameAllocationFailed => MapToError::FrameAllocationFailed,
+      MapToError::ParentEntryHugePage => MapToError::ParentEntryHugePage,
+      MapToError::PageAlreadyMapped(frame) => {
+         MapToError::PageAlreadyMapped(PhysFrame::containing_address(frame.start_address()))
+      }
+   };
+}
+
+/// How a [`DmaBuffer`] is cached.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Caching {
+   /// Normal write-back caching, for devices that snoop the CPU caches.
+   WriteBack,
+
+   /// No caching at all, for descriptor rings and anything else the device and the CPU poll.
+   Uncached,
+
+   /// Writes are buffered and combined but never cached for reading, for buffers the CPU only
+   /// fills, such as framebuffers. Falls back to [`Uncached`](Caching::Uncached) without PAT.
+   WriteCombining,
+}
+
+impl Caching {
+   /// The page flags that select this memory type, given the PAT layout set up by
+   /// [`initialise_page_attributes`].
+   pub fn flags(self) -> PageTableFlags {
+      return match self {
+         Caching::WriteBack => PageTableFlags::empty(),
+         Caching::WriteCombining if WRITE_COMBINING.load(Ordering::Relaxed) => PageTableFlags::WRITE_THROUGH,
+         Caching::Uncached | Caching::WriteCombining => PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH,
+      };
+   }
+}
+
+/// Physical addresses a device with 32-bit DMA can reach are below this.
+pub const DMA_LIMIT_32BIT: PhysAddr = PhysAddr::new_truncate(1 << 32);
+
+/// No limit on where a [`DmaBuffer`] may lie.
+pub const DMA_LIMIT_NONE: PhysAddr = PhysAddr::new_truncate(u64::MAX);
+
+/// Flags every DMA buffer is mapped with, before the caching flags.
+const DMA_FLAGS: PageTableFlags = PageTableFlags::PRESENT
+   .union(PageTableFlags::WRITABLE)
+   .union(PageTableFlags::NO_EXECUTE);
+
+/// Whether PAT entry 1 has been reprogrammed to write-combining.
+static WRITE_COMBINING: AtomicBool = AtomicBool::new(false);
+
+/// A physically contiguous buffer for a device to read and write, with its kernel mapping and
+/// the physical address to program into the device.
+///
+/// Write-back buffers come from the frame allocator. Buffers with any other caching come from
+/// the DMA pool, whose frames have no alias in the physical memory window: mapping the same frames
+/// with two types is undefined, and speculative fills through a write-back alias would defeat the
+/// uncached mapping.
+///
+/// Dropping the buffer frees it as [`free`](DmaBuffer::free) does, so the device must be done
+/// with it first.
+#[derive(Debug)]
+#[must_use = "dropping a DMA buffer frees it"]
+pub struct DmaBuffer {
+   name: &'static str,
+   virt: VirtAddr,
+   phys: PhysAddr,
+   size: u64,
+   caching: Caching,
+}
+
+impl DmaBuffer {
+   /// Allocates a zeroed buffer of at least `size` bytes whose physical address is aligned to
+   /// `align` bytes and whose last byte lies below `limit`, and maps it with `caching`.
+   pub fn allocate(
+      name: &'static str,
+      size: u64,
+      align: u64,
+      limit: PhysAddr,
+      caching: Caching,
+   ) -> Result<Self, DmaError> {
+      if size == 0 || !align.is_power_of_two() {
+         return Err(DmaError::InvalidRequest);
+      }
+
+      let size = align_up(size, FRAME_SIZE);
+      let count = (size / FRAME_SIZE) as usize;
+      let alignFrames = (max(align, FRAME_SIZE) / FRAME_SIZE) as usize;
+
+      let first = match caching {
+         Caching::WriteBack => FRAME_ALLOCATOR.lock()
+            .as_mut()
+            .expect("must first initialise the frame allocator")
+            .allocate_contiguous_below(count, alignFrames, limit),
+         Caching::Uncached | Caching::WriteCombining => DMA_POOL.lock()
+            .as_mut()
+            .and_then(|pool| pool.allocate(count, alignFrames, limit)),
+      }.ok_or(DmaError::OutOfMemory)?;
+
+      let phys = first.start_address();
+
+      let virt = match space::map_physical(name, phys, size, DMA_FLAGS | caching.flags()) {
+         Ok(virt) => virt,
+         Err(error) => {
+            unsafe{ release_frames(first, count, caching) };
+            return Err(DmaError::Region(error));
+         }
+      };
+
+      unsafe{ ptr::write_bytes(virt.as_mut_ptr::<u8>(), 0, size as usize) };
+
+      return Ok(DmaBuffer{
+         name,
+         virt,
+         phys,
+         size,
+         caching,
+      });
+   }
+
+   /// The name the mapping is recorded under in the kernel address space.
+   pub fn name(&self) -> &'static str {
+      return self.name;
    }
 
-   return Some(frame.start_address() + u64::from(address.page_offset()));
+   /// Where the buffer is mapped.
+   pub fn virt(&self) -> VirtAddr {
+      return self.virt;
+   }
+
+   /// Where the buffer is in physical memory.
+   pub fn phys(&self) -> PhysAddr {
+      return self.phys;
+   }
+
+   /// Size of the buffer in bytes, a multiple of the frame size.
+   pub fn size(&self) -> u64 {
+      return self.size;
+   }
+
+   /// How the mapping is cached.
+   pub fn caching(&self) -> Caching {
+      return self.caching;
+   }
+
+   /// A pointer to the start of the buffer.
+   pub fn as_mut_ptr<T>(&self) -> *mut T {
+      return self.virt.as_mut_ptr();
+   }
+
+   /// The physical address of the byte at `offset` into the buffer.
+   pub fn phys_at(&self, offset: u64) -> PhysAddr {
+      assert!(offset < self.size, "offset {:#x} is outside the {:#x}-byte buffer", offset, self.size);
+      return self.phys + offset;
+   }
+
+   /// Unmaps the buffer and returns its frames.
+   ///
+   /// ## Safety
+   ///
+   /// No device may still be reading or writing the buffer.
+   pub unsafe fn free(self) -> Result<(), RegionError> {
+      return ManuallyDrop::new(self).release();
+   }
+
+   /// Unmaps the buffer and returns its frames; if it cannot be unmapped, the frames are kept.
+   unsafe fn release(&self) -> Result<(), RegionError> {
+      space::free(self.virt)?;
+      release_frames(PhysFrame::containing_address(self.phys), (self.size / FRAME_SIZE) as usize, self.caching);
+
+      return Ok(());
+   }
+}
+
+impl Drop for DmaBuffer {
+   fn drop(&mut self) {
+      if let Err(error) = unsafe{ self.release() } {
+         log::warn!("failed to free DMA buffer `{}`: {}", self.name, error);
+      }
+   }
+}
+
+/// Size of the pool buffers that are not write-back are carved from.
+pub const DMA_POOL_SIZE: u64 = 4 * 1024 * 1024;
+
+/// Number of frames in the DMA pool.
+const DMA_POOL_FRAMES: usize = (DMA_POOL_SIZE / FRAME_SIZE) as usize;
+
+/// The DMA pool, set aside by [`initialise`].
+static DMA_POOL: Mutex<Option<DmaPool>> = Mutex::new(None);
+
+/// Frames below 4 GiB set aside at boot for buffers that are not write-back.
+///
+/// The pool's frames are unmapped from the physical memory window before the other CPUs start,
+/// so no CPU ever holds them in its TLB or caches as write-back, and handing them out with any
+/// memory type needs neither a TLB shootdown nor a cache flush.
+struct DmaPool {
+   first: PhysFrame,
+   used: [u64; DMA_POOL_FRAMES / 64],
+}
+
+impl DmaPool {
+   /// Takes `count` contiguous frames aligned to `align` frames whose last byte lies below
+   /// `limit`.
+   fn allocate(&mut self, count: usize, align: usize, limit: PhysAddr) -> Option<PhysFrame> {
+      let base = (self.first.start_address().as_u64() / FRAME_SIZE) as usize;
+      let end = min(DMA_POOL_FRAMES, ((limit.as_u64() / FRAME_SIZE) as usize).saturating_sub(base));
+      let mut start = align_up(base as u64, align as u64) as usize - base;
+
+      while start + count <= end {
+         match (start..start + count).rev().find(|&index| self.test(index)) {
+            // Restart the search past the frame in use, keeping the alignment.
+            Some(index) => start = align_up((base + index + 1) as u64, align as u64) as usize - base,
+            None => {
+               for index in start..start + count {
+                  self.used[index / 64] |= 1 << (index % 64);
+               }
+
+               return Some(self.first + start as u64);
+            }
+         }
+      }
+
+      return None;
+   }
+
+   /// Returns `count` contiguous frames starting at `frame` to the pool.
+   fn deallocate(&mut self, frame: PhysFrame, count: usize) {
+      let first = (frame - self.first) as usize;
+
+      for index in first..first + count {
+         debug_assert!(self.test(index), "DMA pool frame {} freed twice", index);
+         self.used[index / 64] &= !(1 << (index % 64));
+      }
+   }
+
+   fn test(&self, index: usize) -> bool {
+      return self.used[index / 64] & (1 << (index % 64)) != 0;
+   }
+}
+
+/// Takes the DMA pool from the frame allocator and unmaps it from the physical memory window.
+///
+/// Must run before the other CPUs start: only this CPU can have the pool's frames in its TLB or
+/// caches, so flushing its own is enough. The page tables split off the window's huge pages stay
+/// in use for as long as the window does.
+fn reserve_dma_pool() {
+   let count = DMA_POOL_FRAMES;
+   let align = (Size2MiB::SIZE / FRAME_SIZE) as usize;
+
+   let first = FRAME_ALLOCATOR.lock()
+      .as_mut()
+      .expect("must first initialise the frame allocator")
+      .allocate_contiguous_below(count, align, DMA_LIMIT_32BIT);
+
+   let first = match first {
+      Some(first) => first,
+      None => {
+         log::warn!("no room below 4 GiB for the DMA pool; only write-back DMA buffers will work");
+         return;
+      }
+   };
+
+   let unmapped = with_kernel_memory(|mapper, frames| {
+      let window = mapper.phys_offset();
+
+      for frame in PhysFrame::range(first, first + count as u64) {
+         let virt = window + frame.start_address().as_u64();
+         split_huge_pages(mapper, virt, frames)?;
+
+         if let Ok((_, flush)) = mapper.unmap(Page::<Size4KiB>::containing_address(virt)) {
+            flush.flush();
+         }
+      }
+
+      return Ok::<_, MapToError<Size4KiB>>(());
+   });
+
+   if let Err(error) = unmapped {
+      // Part of the window may be unmapped already, so the frames cannot go back.
+      log::warn!("failed to unmap the DMA pool from the physical memory window: {:?}", error);
+      return;
+   }
+
+   // Lines cached through the window must not be written back over what a device puts in a
+   // buffer later.
+   unsafe{ asm!("wbinvd", options(nostack)) };
+
+   *DMA_POOL.lock() = Some(DmaPool{
+      first,
+      used: [0; DMA_POOL_FRAMES / 64],
+   });
+}
+
+/// Splits the 1 GiB and 2 MiB pages on the way to `virt` until it is mapped by a 4 KiB page.
+/// Does nothing if `virt` is not mapped.
+///
+/// The new page tables are never freed; they map the rest of the huge page from then on.
+fn split_huge_pages(
+   mapper: &mut OffsetPageTable<'static>,
+   virt: VirtAddr,
+   frames: &mut SystemFrameAllocator,
+) -> Result<(), MapToError<Size4KiB>> {
+   let window = mapper.phys_offset();
+   let page = Page::<Size4KiB>::containing_address(virt);
+   let mut table: *mut PageTable = mapper.level_4_table();
+
+   for (index, size) in [(page.p4_index(), 0), (page.p3_index(), Size1GiB::SIZE), (page.p2_index(), Size2MiB::SIZE)] {
+      let entry = unsafe{ &mut (*table)[index] };
+      if !entry.flags().contains(PageTableFlags::PRESENT) {
+         return Ok(());
+      }
+
+      if entry.flags().contains(PageTableFlags::HUGE_PAGE) && size != 0 {
+         let frame = frames.allocate_frame().ok_or(MapToError::FrameAllocationFailed)?;
+         let flags = entry.flags();
+         let step = size / 512;
+
+         // Bit 12 of a huge entry's address is its PAT bit. It stays there in the 2 MiB pieces of
+         // a 1 GiB page, and moves to bit 7, where the huge bit was, in the 4 KiB pieces of a
+         // 2 MiB page.
+         let pat = entry.addr().as_u64() & HUGE_PAGE_PAT;
+         let base = entry.addr().as_u64() & !(size - 1);
+
+         let (childFlags, childPat) = match size == Size2MiB::SIZE {
+            true if pat != 0 => (flags, 0),
+            true => (flags - PageTableFlags::HUGE_PAGE, 0),
+            false => (flags, pat),
+         };
+
+         unsafe {
+            let child: &mut PageTable = &mut *(window + frame.start_address().as_u64()).as_mut_ptr();
+            for (index, childEntry) in child.iter_mut().enumerate() {
+               childEntry.set_addr(PhysAddr::new((base + index as u64 * step) | childPat), childFlags);
+            }
+         }
+
+         let parentFlags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | (flags & PageTableFlags::USER_ACCESSIBLE);
+         entry.set_frame(frame, parentFlags);
+         tlb::flush_all();
+      }
+
+      table = (window + entry.addr().as_u64()).as_mut_ptr();
+   }
+
+   return Ok(());
 }
 
+/// Why a [`DmaBuffer`] could not be allocated.
+#[derive(Debug)]
+pub enum DmaError {
+   /// The size was zero or the alignment not a power of two.
+   InvalidRequest,
+
+   /// No run of free frames below the limit was large enough, in the DMA pool for buffers that
+   /// are not write-back.
+   OutOfMemory,
+
+   /// The buffer could not be mapped.
+   Region(RegionError),
+}
+
+impl Display for DmaError {
+   fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+      match self {
+         DmaError::InvalidRequest => write!(f, "DMA buffer size is zero or alignment is not a power of two"),
+         DmaError::OutOfMemory => write!(f, "no contiguous physical memory left below the limit"),
+         DmaError::Region(error) => write!(f, "failed to map DMA buffer: {}", error),
+      }
+   }
+}
+
+/// Returns `count` frames starting at `first` to wherever a buffer with `caching` took them from.
+unsafe fn release_frames(first: PhysFrame, count: usize, caching: Caching) {
+   match caching {
+      Caching::WriteBack => FRAME_ALLOCATOR.lock()
+         .as_mut()
+         .expect("must first initialise the frame allocator")
+         .deallocate_contiguous(first, count),
+      Caching::Uncached | Caching::WriteCombining => DMA_POOL.lock()
+         .as_mut()
+         .expect("DMA buffer came from a pool that does not exist")
+         .deallocate(first, count),
+   }
+}
+
+/// Reprograms PAT entry 1, selected by `WRITE_THROUGH` alone, from write-through to
+/// write-combining; the other entries keep their power-on types. Every CPU must run this before
+/// it touches a write-combining mapping.
+pub fn initialise_page_attributes() {
+   const WB: u64 = 0x06;
+   const WC: u64 = 0x01;
+   const UC_MINUS: u64 = 0x07;
+   const UC: u64 = 0x00;
+   const WT: u64 = 0x04;
+
+   let supported = CpuId::new()
+      .get_feature_info()
+      .map_or(false, |features| features.has_pat());
+
+   if !supported {
+      log::warn!("CPU has no PAT; write-combining DMA buffers will be uncached");
+      return;
+   }
+
+   let entries = [WB, WC, UC_MINUS, UC, WB, WT, UC_MINUS, UC];
+   let value = entries.iter().enumerate().fold(0, |value, (index, entry)| value | entry << (index * 8));
+
+   unsafe {
+      // Nothing may be cached under the old types while they change.
+      asm!("wbinvd", options(nostack));
+      wrmsr(IA32_PAT, value);
+      asm!("wbinvd", options(nostack));
+   }
+   tlb::flush_all();
+
+   WRITE_COMBINING.store(true, Ordering::Relaxed);
+}
+
+/// A bitmap-backed physical frame allocator.
+///
+/// Every 4 KiB frame below the highest usable address owns a single bit: set when the frame is in
+/// use (or not usable at all), cleared when it is free. A second bitmap of the same shape marks the
+/// frames that can never be allocated. Both live in the first usable region large enough to hold
+/// them and are accessed through the physical memory offset.
 pub struct SystemFrameAllocator {
+   /// The bootloader-provided memory map this allocator was built from.
    memory_map: &'static [MemoryRegion],
+
+   /// One bit per physical frame.
+   bitmap: &'static mut [u64],
+
+   /// One bit per physical frame, set for frames outside the usable regions, the null frame and
+   /// the frames holding the bitmaps, none of which may be freed.
+   reserved: &'static mut [u64],
+
+   /// Number of frames covered by the bitmap.
+   frames: usize,
+
+   /// Number of frames the memory map reports as usable.
+   usable: usize,
+
+   /// Number of usable frames currently handed out.
+   used: usize,
+
+   /// Index of the first bitmap word that may contain a free frame.
    next: usize,
 }
 
 impl SystemFrameAllocator {
-   pub unsafe fn new(memory_map: &'static [MemoryRegion]) -> Self {
-      return SystemFrameAllocator{
+   /// Builds the frame bitmap from the bootloader's memory map.
+   ///
+   /// ## Safety
+   ///
+   /// The caller must guarantee that the complete physical memory is mapped at `physical_offset`,
+   /// and that every region marked `Usable` really is unused.
+   pub unsafe fn new(memory_map: &'static [MemoryRegion], physical_offset: VirtAddr) -> Self {
+      let highest = memory_map.iter()
+         .filter(|r| r.kind == MemoryRegionKind::Usable)
+         .map(|r| r.end)
+         .max()
+         .unwrap_or(0);
+
+      let frames = (highest / FRAME_SIZE) as usize;
+      let words = (frames + 63) / 64;
+      let bitmapBytes = align_up((2 * words * size_of::<u64>()) as u64, FRAME_SIZE);
+
+      // Carve both bitmaps out of the first usable region that can hold them.
+      let bitmapStart = memory_map.iter()
+         .filter(|r| r.kind == MemoryRegionKind::Usable)
+         .map(|r| (max(align_up(r.start, FRAME_SIZE), FRAME_SIZE), r.end))
+         .find(|&(start, end)| start + bitmapBytes <= end)
+         .map(|(start, _)| start)
+         .expect("no usable region large enough to hold the frame bitmap");
+
+      let pointer: *mut u64 = (physical_offset + bitmapStart).as_mut_ptr();
+      let bitmap = slice::from_raw_parts_mut(pointer, words);
+      bitmap.fill(u64::MAX);
+
+      let reserved = slice::from_raw_parts_mut(pointer.add(words), words);
+      reserved.fill(u64::MAX);
+
+      let mut allocator = SystemFrameAllocator{
          memory_map,
+         bitmap,
+         reserved,
+         frames,
+         usable: 0,
+         used: 0,
          next: 0,
       };
+
+      for region in memory_map.iter().filter(|r| r.kind == MemoryRegionKind::Usable) {
+         let first = align_up(region.start, FRAME_SIZE) / FRAME_SIZE;
+         let last = region.end / FRAME_SIZE;
+
+         for frame in first..last {
+            allocator.clear(frame as usize);
+            allocator.reserved[frame as usize / 64] &= !(1 << (frame % 64));
+            allocator.usable += 1;
+         }
+      }
+
+      // Never hand out the null frame, nor the frames backing the bitmaps.
+      if !allocator.test(0) {
+         allocator.set(0);
+         allocator.usable -= 1;
+      }
+      allocator.reserved[0] |= 1;
+
+      let first = (bitmapStart / FRAME_SIZE) as usize;
+      for frame in first..first + (bitmapBytes / FRAME_SIZE) as usize {
+         allocator.set(frame);
+         allocator.reserved[frame / 64] |= 1 << (frame % 64);
+         allocator.used += 1;
+      }
+
+      return allocator;
+   }
+
+   /// The bootloader-provided memory map this allocator was built from.
+   pub fn memory_map(&self) -> &'static [MemoryRegion] {
+      return self.memory_map;
    }
 
+   /// Iterates over every frame the memory map reports as usable, whether it is free or not.
    pub fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
       let regions = self.memory_map.iter();
       let usable = regions
@@ -117,34 +1040,208 @@ impl SystemFrameAllocator {
       let frameAddresses = addressRanges.flat_map(|r| r.step_by(4096));
       return frameAddresses.map(|address| PhysFrame::containing_address(PhysAddr::new(address)));
    }
+
+   /// Allocates `count` physically contiguous frames, the first of which is aligned to
+   /// `align` frames.
+   pub fn allocate_contiguous(&mut self, count: usize, align: usize) -> Option<PhysFrame> {
+      return self.allocate_contiguous_below(count, align, PhysAddr::new(self.frames as u64 * FRAME_SIZE));
+   }
+
+   /// Like [`allocate_contiguous`](SystemFrameAllocator::allocate_contiguous), but every frame
+   /// lies below `limit`, for devices that cannot address all of physical memory.
+   pub fn allocate_contiguous_below(&mut self, count: usize, align: usize, limit: PhysAddr) -> Option<PhysFrame> {
+      if count == 0 {
+         return None;
+      }
+
+      let align = max(align, 1);
+      let end = min(self.frames, (limit.as_u64() / FRAME_SIZE) as usize);
+      let mut start = 0;
+
+      while start + count <= end {
+         match (start..start + count).rev().find(|&frame| self.test(frame)) {
+            // Restart the search past the frame in use, keeping the alignment.
+            Some(frame) => start = align_up((frame + 1) as u64, align as u64) as usize,
+            None => {
+               for frame in start..start + count {
+                  self.set(frame);
+               }
+
+               self.used += count;
+               return Some(Self::frame_at(start));
+            }
+         }
+      }
+
+      return None;
+   }
+
+   /// Returns `count` contiguous frames starting at `frame` to the allocator.
+   ///
+   /// ## Safety
+   ///
+   /// The caller must ensure that none of the frames are still in use.
+   pub unsafe fn deallocate_contiguous(&mut self, frame: PhysFrame, count: usize) {
+      let first = (frame.start_address().as_u64() / FRAME_SIZE) as usize;
+
+      for index in first..first + count {
+         self.release(index);
+      }
+   }
+
+   /// Number of usable frames currently handed out.
+   pub fn used_frames(&self) -> usize {
+      return self.used;
+   }
+
+   /// Number of usable frames still available.
+   pub fn free_frames(&self) -> usize {
+      return self.usable - self.used;
+   }
+
+   /// Number of frames the memory map reports as usable.
+   pub fn total_frames(&self) -> usize {
+      return self.usable;
+   }
+
+   fn release(&mut self, index: usize) {
+      assert!(index < self.frames, "frame {:#x} is out of range", index as u64 * FRAME_SIZE);
+      assert!(self.is_releasable(index), "frame {:#x} was never allocatable", index as u64 * FRAME_SIZE);
+      assert!(self.test(index), "double free of frame {:#x}", index as u64 * FRAME_SIZE);
+
+      self.clear(index);
+      self.used -= 1;
+      self.next = min(self.next, index / 64);
+   }
+
+   /// Returns `true` if frame `index` lies wholly in a usable region and is neither the null frame
+   /// nor part of the bitmaps. Reserved frames are marked in use from the start, so without this
+   /// check freeing one would put it on the free list.
+   #[inline]
+   fn is_releasable(&self, index: usize) -> bool {
+      return self.reserved[index / 64] & (1 << (index % 64)) == 0;
+   }
+
+   fn frame_at(index: usize) -> PhysFrame {
+      return PhysFrame::containing_address(PhysAddr::new(index as u64 * FRAME_SIZE));
+   }
+
+   #[inline]
+   fn test(&self, index: usize) -> bool {
+      return self.bitmap[index / 64] & (1 << (index % 64)) != 0;
+   }
+
+   #[inline]
+   fn set(&mut self, index: usize) {
+      self.bitmap[index / 64] |= 1 << (index % 64);
+   }
+
+   #[inline]
+   fn clear(&mut self, index: usize) {
+      self.bitmap[index / 64] &= !(1 << (index % 64));
+   }
 }
 
 unsafe impl FrameAllocator<Size4KiB> for SystemFrameAllocator {
    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
-      let frame = self.usable_frames().nth(self.next);
-      self.next += 1;
-      return frame;
+      // Words before `next` are known to be full, so the search is amortised O(1).
+      while self.next < self.bitmap.len() {
+         let word = self.bitmap[self.next];
+
+         if word != u64::MAX {
+            let index = self.next * 64 + word.trailing_ones() as usize;
+            if index >= self.frames {
+               break;
+            }
+
+            self.set(index);
+            self.used += 1;
+            return Some(Self::frame_at(index));
+         }
+
+         self.next += 1;
+      }
+
+      return None;
+   }
+}
+
+impl FrameDeallocator<Size4KiB> for SystemFrameAllocator {
+   unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+      self.release((frame.start_address().as_u64() / FRAME_SIZE) as usize);
+   }
+}
+
+impl Debug for SystemFrameAllocator {
+   fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+      f.debug_struct("SystemFrameAllocator")
+         .field("total", &self.total_frames())
+         .field("used", &self.used_frames())
+         .field("free", &self.free_frames())
+         .finish()
    }
 }
 
+/// Size of a single physical frame.
+pub const FRAME_SIZE: u64 = 4096;
+
+/// The PAT bit of a 2 MiB or 1 GiB page table entry, which falls inside its address field.
+const HUGE_PAGE_PAT: u64 = 1 << 12;
+
+/// Rounds `value` up to the next multiple of `align`.
+#[inline]
+pub const fn align_up(value: u64, align: u64) -> u64 {
+   return (value + align - 1) / align * align;
+}
+
+// MODULES //
+
+/// Kernel stacks with guard pages.
+pub mod stack;
+
+/// The memory map and a `/proc/meminfo`-style usage report: physical frames, the heap and
+/// page-table overhead.
+pub mod usage;
+
 // IMPORTS //
 
 use {
-   base::{alloc::heap::{HEAP, Heap, HEAP_SIZE, HEAP_START}, log},
-   springboard_api::info::{
-      MemoryRegion, MemoryRegionKind,
+   crate::address::space::{self, AddressSpace, Backing, RegionError, KERNEL_SPACE},
+   base::{alloc::heap::{HEAP, Heap, HEAP_GROWTH, HEAP_RESERVE, HEAP_SIZE, HEAP_START}, log},
+   core::{
+      arch::asm,
+      cmp::{max, min},
+      fmt::{Debug, Display, Formatter, Result as FmtResult},
+      mem::{size_of, ManuallyDrop},
+      ptr,
+      slice,
+      sync::atomic::{AtomicBool, Ordering},
+   },
+   spin::Mutex,
+   springboard_api::{
+      info::{MemoryRegion, MemoryRegionKind},
+      BootInfo,
+   },
+   x86::{
+      cpuid::CpuId,
+      msr::{wrmsr, IA32_PAT},
    },
    x86_64::{
+      instructions::tlb,
+      registers::model_specific::{Efer, EferFlags},
       structures::paging::{
          FrameAllocator,
+         FrameDeallocator,
          PageTable,
          PhysFrame,
          Size4KiB,
-         Mapper,
          OffsetPageTable,
          Page,
+         PageSize,
          PageTableFlags,
-         mapper::MapToError,
+         Size1GiB,
+         Size2MiB,
+         mapper::{MapToError, Mapper, MapperAllSizes},
       },
       PhysAddr, VirtAddr,
    },
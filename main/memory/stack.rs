@@ -0,0 +1,112 @@
+/// Size of the stack the kernel switches to once memory management is up.
+pub const BOOT_STACK_SIZE: u64 = 64 * 1024;
+
+/// Size of each stack in the interrupt stack table.
+pub const IST_STACK_SIZE: u64 = 5 * 4096;
+
+/// Flags kernel stacks are mapped with.
+const STACK_FLAGS: PageTableFlags = PageTableFlags::PRESENT
+   .union(PageTableFlags::WRITABLE)
+   .union(PageTableFlags::NO_EXECUTE);
+
+/// A kernel stack in its own region of the kernel address space, with an unmapped guard page
+/// directly below it.
+///
+/// Running off the bottom of the stack touches the guard page instead of silently corrupting
+/// whatever lies below, and [`space::guard_owner`] can name the stack that overflowed.
+#[derive(Copy, Clone, Debug)]
+pub struct KernelStack {
+   /// The name the stack and its guard page are recorded under.
+   pub name: &'static str,
+
+   /// Lowest usable address; the guard page ends here.
+   pub bottom: VirtAddr,
+
+   /// One past the highest usable address, which is the initial stack pointer.
+   pub top: VirtAddr,
+}
+
+impl KernelStack {
+   /// Reserves and maps a stack of `size` bytes with a guard page below it.
+   ///
+   /// Stacks are backed eagerly: a fault on a not-present stack page would need that same stack
+   /// to deliver the exception.
+   pub fn allocate(name: &'static str, size: u64) -> Result<Self, RegionError> {
+      let size = align_up(size, Size4KiB::SIZE);
+
+      let mut space = KERNEL_SPACE.lock();
+      let bottom = space.reserve_guarded(name, size)?;
+
+      if let Err(error) = memory::with_kernel_memory(|mapper, frames| space.map(bottom, STACK_FLAGS, mapper, frames)) {
+         space.release(bottom)?;
+         space.release(bottom - Size4KiB::SIZE)?;
+         return Err(error);
+      }
+
+      return Ok(KernelStack{
+         name,
+         bottom,
+         top: bottom + size,
+      });
+   }
+
+   /// The guard page below the stack.
+   pub fn guard(&self) -> VirtAddr {
+      return self.bottom - Size4KiB::SIZE;
+   }
+
+   /// Size of the usable part of the stack in bytes.
+   pub fn size(&self) -> u64 {
+      return self.top - self.bottom;
+   }
+
+   /// Returns `true` if `address` lies within the stack or its guard page.
+   pub fn contains(&self, address: VirtAddr) -> bool {
+      return self.guard() <= address && address < self.top;
+   }
+
+   /// Switches to this stack and calls `entry` with `argument`, abandoning the current stack.
+   ///
+   /// ## Safety
+   ///
+   /// Nothing on the current stack may be referenced after the switch, and the stack must not be
+   /// in use by anything else.
+   pub unsafe fn switch_to(&self, entry: extern "C" fn(usize) -> !, argument: usize) -> ! {
+      asm!(
+         "mov rsp, {top}",
+         "xor rbp, rbp",
+         "call {entry}",
+         "ud2",
+         top = in(reg) self.top.as_u64(),
+         entry = in(reg) entry,
+         in("rdi") argument,
+         options(noreturn),
+      );
+   }
+
+   /// Unmaps the stack and releases it together with its guard page.
+   ///
+   /// ## Safety
+   ///
+   /// The stack must no longer be in use, including as an interrupt stack.
+   pub unsafe fn free(self) -> Result<(), RegionError> {
+      space::free(self.bottom)?;
+      KERNEL_SPACE.lock().release(self.guard())?;
+
+      return Ok(());
+   }
+}
+
+// IMPORTS //
+
+use {
+   crate::{
+      address::space::{self, RegionError, KERNEL_SPACE},
+      memory::{self, align_up},
+   },
+   core::arch::asm,
+   x86_64::{
+      structures::paging::{PageSize, PageTableFlags, Size4KiB},
+      VirtAddr,
+   },
+};
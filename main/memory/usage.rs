@@ -0,0 +1,201 @@
+/// A snapshot of how physical memory, the heap and the page tables are being used.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct MemoryUsage {
+   /// Frames the memory map reports as usable.
+   pub total_frames: usize,
+
+   /// Usable frames handed out, including the frame allocator's own bitmap.
+   pub used_frames: usize,
+
+   /// Usable frames still free.
+   pub free_frames: usize,
+
+   /// Bytes the heap has been given.
+   pub heap_total: usize,
+
+   /// Bytes of heap blocks handed out, after rounding up to block sizes.
+   pub heap_allocated: usize,
+
+   /// Bytes of heap actually requested.
+   pub heap_user: usize,
+
+   /// How the free part of the heap is split up.
+   pub heap_fragmentation: Fragmentation,
+
+   /// Frames holding page tables reachable from the active level 4 table, the level 4 table
+   /// included.
+   pub page_table_frames: usize,
+}
+
+impl Display for MemoryUsage {
+   fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+      let frames = |count: usize| count as u64 * FRAME_SIZE / 1024;
+      let bytes = |count: usize| count / 1024;
+
+      writeln!(f, "MemTotal:      {:>10} KiB", frames(self.total_frames))?;
+      writeln!(f, "MemUsed:       {:>10} KiB", frames(self.used_frames))?;
+      writeln!(f, "MemFree:       {:>10} KiB", frames(self.free_frames))?;
+      writeln!(f, "HeapTotal:     {:>10} KiB", bytes(self.heap_total))?;
+      writeln!(f, "HeapAllocated: {:>10} KiB", bytes(self.heap_allocated))?;
+      writeln!(f, "HeapUser:      {:>10} KiB", bytes(self.heap_user))?;
+      writeln!(f, "HeapFree:      {:>10} KiB ({}% fragmented)", bytes(self.heap_fragmentation.free), self.heap_fragmentation.percent())?;
+      writeln!(f, "PageTables:    {:>10} KiB", frames(self.page_table_frames))?;
+
+      return Ok(());
+   }
+}
+
+/// The bootloader's memory map, one region per line, followed by the total of each kind.
+pub struct MemoryMap(pub &'static [MemoryRegion]);
+
+impl Display for MemoryMap {
+   fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+      let mut totals = [0u64; 4];
+
+      for region in self.0.iter() {
+         let size = region.end - region.start;
+         writeln!(f, "{:#014x}-{:#014x} {:>10} KiB {:?}", region.start, region.end, size / 1024, region.kind)?;
+
+         totals[kind_index(region.kind)] += size;
+      }
+
+      for (name, total) in ["usable", "bootloader", "firmware", "other"].iter().zip(totals) {
+         writeln!(f, "{:<10} {:>10} KiB", name, total / 1024)?;
+      }
+
+      return Ok(());
+   }
+}
+
+/// Takes a [`MemoryUsage`] snapshot.
+///
+/// The heap, the frame allocator and the mapper are locked one after the other, so the numbers
+/// may be a few frames apart if other CPUs are allocating.
+pub fn usage() -> MemoryUsage {
+   let mut usage = MemoryUsage::default();
+
+   if let Some(heap) = HEAP.lock().as_ref() {
+      usage.heap_total = heap.total;
+      usage.heap_allocated = heap.allocated;
+      usage.heap_user = heap.user;
+      usage.heap_fragmentation = heap.fragmentation();
+   }
+
+   if let Some(frames) = FRAME_ALLOCATOR.lock().as_ref() {
+      usage.total_frames = frames.total_frames();
+      usage.used_frames = frames.used_frames();
+      usage.free_frames = frames.free_frames();
+   }
+
+   if let Some(mapper) = MAPPER.lock().as_mut() {
+      usage.page_table_frames = page_table_frames(mapper);
+   }
+
+   return usage;
+}
+
+/// The memory map the frame allocator was built from.
+pub fn memory_map() -> Option<MemoryMap> {
+   return FRAME_ALLOCATOR.lock()
+      .as_ref()
+      .map(|frames| MemoryMap(frames.memory_map()));
+}
+
+/// Writes the memory map, the current [`usage`] and the kernel address space to `out`.
+pub fn write_report(out: &mut dyn Write) -> FmtResult {
+   return write_snapshot(out, memory_map(), usage(), KERNEL_SPACE.lock().clone());
+}
+
+/// Writes the [`write_report`] report to the serial port.
+pub fn report() {
+   // Snapshot first: `extend_heap` logs with the heap and the address space locked, so taking
+   // those locks with the serial port held would invert the order.
+   let map = memory_map();
+   let usage = usage();
+   let space = KERNEL_SPACE.lock().clone();
+
+   without_interrupts(|| {
+      let _ = write_snapshot(&mut *COM2.lock(), map, usage, space);
+   });
+}
+
+fn write_snapshot(
+   out: &mut dyn Write,
+   map: Option<MemoryMap>,
+   usage: MemoryUsage,
+   space: AddressSpace,
+) -> FmtResult {
+   if let Some(map) = map {
+      writeln!(out, "Memory map:")?;
+      write!(out, "{}", map)?;
+   }
+
+   writeln!(out, "Memory usage:")?;
+   write!(out, "{}", usage)?;
+
+   writeln!(out, "Kernel address space:")?;
+   write!(out, "{}", space)?;
+
+   return Ok(());
+}
+
+/// Counts the page tables reachable from the active level 4 table.
+fn page_table_frames(mapper: &mut OffsetPageTable<'static>) -> usize {
+   let offset = mapper.phys_offset();
+   let (l4frame, _) = Cr3::read();
+   let l4table = mapper.level_4_table();
+
+   let mut tables = 1;
+   for entry in l4table.iter() {
+      // The recursive entry points back at the level 4 table itself.
+      if entry.flags().contains(PageTableFlags::PRESENT) && entry.addr() != l4frame.start_address() {
+         tables += count_tables(offset, entry.addr(), 3);
+      }
+   }
+
+   return tables;
+}
+
+/// Counts the table at `table`, a level `level` table, and every table below it.
+fn count_tables(offset: VirtAddr, table: PhysAddr, level: u8) -> usize {
+   if level == 1 {
+      return 1;
+   }
+
+   let table = unsafe{ &*(offset + table.as_u64()).as_ptr::<PageTable>() };
+   return 1 + table.iter()
+      .filter(|entry| entry.flags().contains(PageTableFlags::PRESENT))
+      .filter(|entry| !entry.flags().contains(PageTableFlags::HUGE_PAGE))
+      .map(|entry| count_tables(offset, entry.addr(), level - 1))
+      .sum::<usize>();
+}
+
+fn kind_index(kind: MemoryRegionKind) -> usize {
+   return match kind {
+      MemoryRegionKind::Usable => 0,
+      MemoryRegionKind::Bootloader => 1,
+      MemoryRegionKind::UnknownUefi(_) | MemoryRegionKind::UnknownBios(_) => 2,
+      _ => 3,
+   };
+}
+
+// IMPORTS //
+
+use {
+   crate::{
+      address::space::{AddressSpace, KERNEL_SPACE},
+      memory::{FRAME_ALLOCATOR, FRAME_SIZE, MAPPER},
+   },
+   base::{
+      alloc::{heap::HEAP, Fragmentation},
+      arch::without_interrupts,
+      uart::COM2,
+   },
+   core::fmt::{Display, Formatter, Result as FmtResult, Write},
+   springboard_api::info::{MemoryRegion, MemoryRegionKind},
+   x86_64::{
+      registers::control::Cr3,
+      structures::paging::{OffsetPageTable, PageTable, PageTableFlags},
+      PhysAddr, VirtAddr,
+   },
+};
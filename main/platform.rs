@@ -0,0 +1,448 @@
+/// Flags ACPI tables are mapped with when they lie outside the physical memory window.
+const TABLE_FLAGS: PageTableFlags = PageTableFlags::PRESENT.union(PageTableFlags::NO_EXECUTE);
+
+/// What the ACPI tables say about the machine. `None` until [`initialise`] succeeds.
+pub static PLATFORM: Mutex<Option<Platform>> = Mutex::new(None);
+
+/// The machine as described by the ACPI tables, copied out so that none of them stay mapped.
+#[derive(Clone, Debug)]
+pub struct Platform {
+   /// Physical address of the RSDP.
+   pub rsdp: u64,
+
+   /// ACPI revision of the root table.
+   pub revision: u8,
+
+   /// Processors from the MADT, the boot processor first.
+   pub cpus: Vec<Cpu>,
+
+   /// The APIC layout from the MADT, if the machine has APICs.
+   pub interrupts: Option<InterruptController>,
+
+   /// The first HPET, if there is one.
+   pub hpet: Option<Hpet>,
+
+   /// Fixed power-management hardware from the FADT.
+   pub power: Option<PowerManagement>,
+
+   /// Memory-mapped PCI configuration space from the MCFG, one entry per segment and bus range.
+   pub pci_segments: Vec<PciSegment>,
+
+   /// Where the DSDT lies in physical memory.
+   pub dsdt: Option<AmlRegion>,
+}
+
+impl Platform {
+   /// The processor the kernel booted on.
+   pub fn boot_cpu(&self) -> Option<&Cpu> {
+      return self.cpus.iter().find(|cpu| cpu.is_bsp);
+   }
+
+   /// Physical address of the configuration space of `bus:device.function` on `segment`.
+   pub fn pci_config_address(&self, segment: u16, bus: u8, device: u8, function: u8) -> Option<u64> {
+      return self.pci_segments.iter()
+         .find(|region| region.segment == segment && region.bus_start <= bus && bus <= region.bus_end)
+         .map(|region| region.config_address(bus, device, function));
+   }
+}
+
+impl Display for Platform {
+   fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+      writeln!(f, "ACPI revision {}, RSDP at {:#x}", self.revision, self.rsdp)?;
+
+      let usable = self.cpus.iter().filter(|cpu| cpu.usable).count();
+      write!(f, "{} CPUs ({} usable):", self.cpus.len(), usable)?;
+      for cpu in self.cpus.iter() {
+         write!(f, " {}{}", cpu.apic_id, if cpu.is_bsp { "*" } else { "" })?;
+      }
+      writeln!(f)?;
+
+      match self.interrupts.as_ref() {
+         Some(apic) => writeln!(
+            f,
+            "Local APIC at {:#x}, {} IO-APICs, {} interrupt source overrides{}",
+            apic.local_apic_address,
+            apic.io_apics.len(),
+            apic.overrides.len(),
+            if apic.legacy_pics { ", legacy PICs present" } else { "" },
+         )?,
+         None => writeln!(f, "No APIC; legacy PICs only")?,
+      }
+
+      match self.hpet.as_ref() {
+         Some(hpet) => writeln!(
+            f,
+            "HPET {} at {:#x}: {} comparators, {}-bit counter",
+            hpet.number,
+            hpet.base_address,
+            hpet.comparators,
+            if hpet.counter_64bit { 64 } else { 32 },
+         )?,
+         None => writeln!(f, "No HPET")?,
+      }
+
+      if let Some(power) = self.power.as_ref() {
+         writeln!(
+            f,
+            "SCI on IRQ {}, PM1a control {:#x}, PM timer {}, reset register {}",
+            power.sci_interrupt,
+            power.pm1a_control.map_or(0, |register| register.address),
+            if power.pm_timer.is_some() { "present" } else { "absent" },
+            if power.reset.is_some() { "present" } else { "absent" },
+         )?;
+      }
+
+      for segment in self.pci_segments.iter() {
+         writeln!(
+            f,
+            "PCI segment {} buses {}-{} at {:#x}",
+            segment.segment,
+            segment.bus_start,
+            segment.bus_end,
+            segment.base_address,
+         )?;
+      }
+
+      return Ok(());
+   }
+}
+
+/// A processor listed in the MADT.
+#[derive(Copy, Clone, Debug)]
+pub struct Cpu {
+   /// ACPI processor UID.
+   pub uid: u32,
+
+   /// Local APIC ID.
+   pub apic_id: u32,
+
+   /// Whether this is the processor the kernel booted on.
+   pub is_bsp: bool,
+
+   /// Whether the firmware says the processor can be started.
+   pub usable: bool,
+}
+
+impl From<&Processor> for Cpu {
+   fn from(processor: &Processor) -> Self {
+      return Cpu{
+         uid: processor.processor_uid,
+         apic_id: processor.local_apic_id,
+         is_bsp: !processor.is_ap,
+         usable: processor.state != ProcessorState::Disabled,
+      };
+   }
+}
+
+/// The APICs from the MADT and how the legacy IRQs are wired to them.
+#[derive(Clone, Debug)]
+pub struct InterruptController {
+   /// Physical address of every processor's local APIC registers.
+   pub local_apic_address: u64,
+
+   /// The IO-APICs.
+   pub io_apics: Vec<IoApic>,
+
+   /// ISA IRQs that are not identity-mapped onto global system interrupts, or that are not
+   /// edge-triggered and active high.
+   pub overrides: Vec<InterruptSourceOverride>,
+
+   /// Local APIC inputs wired to NMI.
+   pub nmi_lines: Vec<NmiLine>,
+
+   /// Global system interrupts wired to NMI.
+   pub nmi_sources: Vec<NmiSource>,
+
+   /// Whether the 8259 PICs are also present and need masking.
+   pub legacy_pics: bool,
+}
+
+/// A high precision event timer.
+#[derive(Copy, Clone, Debug)]
+pub struct Hpet {
+   /// Physical address of the register block.
+   pub base_address: u64,
+
+   /// Which HPET this is, when there are several.
+   pub number: u8,
+
+   /// Number of comparators, each of which can raise its own interrupt.
+   pub comparators: u8,
+
+   /// Whether the main counter is 64 bits wide.
+   pub counter_64bit: bool,
+
+   /// Whether the HPET can stand in for the PIT and RTC interrupts.
+   pub legacy_replacement: bool,
+
+   /// Smallest periodic tick the HPET supports without losing interrupts, in counter ticks.
+   pub minimum_tick: u16,
+}
+
+/// The fixed power-management hardware from the FADT.
+#[derive(Copy, Clone, Debug)]
+pub struct PowerManagement {
+   /// ISA IRQ the SCI arrives on.
+   pub sci_interrupt: u16,
+
+   /// Port that `acpi_enable` and `acpi_disable` are written to, or zero if the machine is always
+   /// in ACPI mode.
+   pub smi_command: u32,
+
+   /// Value that hands the fixed hardware from the firmware to the OS.
+   pub acpi_enable: u8,
+
+   /// Value that hands the fixed hardware back to the firmware.
+   pub acpi_disable: u8,
+
+   /// PM1a event block.
+   pub pm1a_event: Option<GenericAddress>,
+
+   /// PM1b event block, if split.
+   pub pm1b_event: Option<GenericAddress>,
+
+   /// PM1a control block, where sleep states are entered.
+   pub pm1a_control: Option<GenericAddress>,
+
+   /// PM1b control block, if split.
+   pub pm1b_control: Option<GenericAddress>,
+
+   /// The ACPI power-management timer.
+   pub pm_timer: Option<GenericAddress>,
+
+   /// Register that resets the machine when [`reset_value`](Self::reset_value) is written to it,
+   /// if the FADT flags it as supported.
+   pub reset: Option<GenericAddress>,
+
+   /// Value to write to the reset register.
+   pub reset_value: u8,
+
+   /// CMOS index of the century register, or zero if there is none.
+   pub century: u8,
+
+   /// Whether there is an 8042 keyboard controller.
+   pub has_8042: bool,
+
+   /// Whether there is a CMOS real-time clock.
+   pub has_cmos_rtc: bool,
+}
+
+/// Memory-mapped configuration space for a range of PCI buses.
+#[derive(Copy, Clone, Debug)]
+pub struct PciSegment {
+   /// Physical address of bus `bus_start`'s configuration space.
+   pub base_address: u64,
+
+   /// PCI segment group number.
+   pub segment: u16,
+
+   /// First bus decoded.
+   pub bus_start: u8,
+
+   /// Last bus decoded.
+   pub bus_end: u8,
+}
+
+impl PciSegment {
+   /// Physical address of the configuration space of `bus:device.function`. `bus` must lie in
+   /// this segment's range.
+   pub fn config_address(&self, bus: u8, device: u8, function: u8) -> u64 {
+      let bus = (bus - self.bus_start) as u64;
+      return self.base_address + (bus << 20 | (device as u64) << 15 | (function as u64) << 12);
+   }
+}
+
+/// An AML table in physical memory.
+#[derive(Copy, Clone, Debug)]
+pub struct AmlRegion {
+   /// Physical address of the table body, past its header.
+   pub address: u64,
+
+   /// Length of the body in bytes.
+   pub length: u32,
+}
+
+/// Maps ACPI tables for the `acpi` crate.
+///
+/// Tables in RAM are read through the physical memory window, which needs no new mapping. A table
+/// outside it gets a region of its own in the kernel address space for as long as the crate holds
+/// on to it.
+#[derive(Copy, Clone, Debug)]
+pub struct KernelAcpiHandler;
+
+impl AcpiHandler for KernelAcpiHandler {
+   unsafe fn map_physical_region<T>(&self, physical_address: usize, size: usize) -> PhysicalMapping<Self, T> {
+      let physical = PhysAddr::new(physical_address as u64);
+      let virt = match direct_map(physical, size as u64) {
+         Some(virt) => virt,
+         None => space::map_physical("acpi-table", physical, size as u64, TABLE_FLAGS)
+            .expect("failed to map an ACPI table"),
+      };
+
+      let offset = physical - physical.align_down(Size4KiB::SIZE);
+      let mapped = align_up(size as u64 + offset, Size4KiB::SIZE);
+
+      return PhysicalMapping::new(
+         physical_address,
+         NonNull::new_unchecked(virt.as_mut_ptr()),
+         size,
+         mapped as usize,
+         *self,
+      );
+   }
+
+   fn unmap_physical_region<T>(region: &PhysicalMapping<Self, T>) {
+      let physical = PhysAddr::new(region.physical_start() as u64);
+      let virt = VirtAddr::from_ptr(region.virtual_start().as_ptr());
+
+      if direct_map(physical, region.region_length() as u64) != Some(virt) {
+         if let Err(error) = space::free(virt.align_down(Size4KiB::SIZE)) {
+            log::warn!("Failed to unmap ACPI table at {:?}: {}", physical, error);
+         }
+      }
+   }
+}
+
+/// Reads the ACPI tables found through the RSDP at physical address `rsdp` into [`PLATFORM`] and
+/// logs a summary.
+///
+/// Only the MADT is required. A missing HPET, FADT or MCFG just leaves that part of the
+/// description empty.
+pub fn initialise(rsdp: u64) -> Result<(), AcpiError> {
+   let tables = tables(rsdp)?;
+   let info = tables.platform_info()?;
+
+   let mut cpus = Vec::new();
+   if let Some(processors) = info.processor_info.as_ref() {
+      cpus.push(Cpu::from(&processors.boot_processor));
+      cpus.extend(processors.application_processors.iter().map(Cpu::from));
+   }
+
+   let interrupts = match info.interrupt_model {
+      InterruptModel::Apic(ref apic) => Some(InterruptController{
+         local_apic_address: apic.local_apic_address,
+         io_apics: apic.io_apics.to_vec(),
+         overrides: apic.interrupt_source_overrides.to_vec(),
+         nmi_lines: apic.local_apic_nmi_lines.to_vec(),
+         nmi_sources: apic.nmi_sources.to_vec(),
+         legacy_pics: apic.also_has_legacy_pics,
+      }),
+      _ => None,
+   };
+
+   let hpet = HpetInfo::new(&tables).ok().map(|hpet| Hpet{
+      base_address: hpet.base_address as u64,
+      number: hpet.hpet_number,
+      comparators: hpet.num_comparators(),
+      counter_64bit: hpet.main_counter_is_64bits(),
+      legacy_replacement: hpet.legacy_irq_capable(),
+      minimum_tick: hpet.clock_tick_unit,
+   });
+
+   let power = tables.find_table::<Fadt>().ok().map(|fadt| {
+      // The FADT is packed, so its flags have to be copied out before calling methods on them.
+      let boot = fadt.iapc_boot_arch;
+      let flags = fadt.flags;
+
+      PowerManagement{
+         sci_interrupt: fadt.sci_interrupt,
+         smi_command: fadt.smi_cmd_port,
+         acpi_enable: fadt.acpi_enable,
+         acpi_disable: fadt.acpi_disable,
+         pm1a_event: fadt.pm1a_event_block().ok(),
+         pm1b_event: fadt.pm1b_event_block().ok().flatten(),
+         pm1a_control: fadt.pm1a_control_block().ok(),
+         pm1b_control: fadt.pm1b_control_block().ok().flatten(),
+         pm_timer: fadt.pm_timer_block().ok().flatten(),
+         reset: match flags.supports_system_reset_via_fadt() {
+            true => fadt.reset_register().ok(),
+            false => None,
+         },
+         reset_value: fadt.reset_value,
+         century: fadt.century,
+         has_8042: boot.motherboard_implements_8042(),
+         has_cmos_rtc: !boot.is_cmos_rtc_not_present(),
+      }
+   });
+
+   let pci_segments = match tables.find_table::<Mcfg>() {
+      Ok(mcfg) => mcfg.entries().iter()
+         .map(|entry| PciSegment{
+            base_address: entry.base_address,
+            segment: entry.pci_segment_group,
+            bus_start: entry.bus_number_start,
+            bus_end: entry.bus_number_end,
+         })
+         .collect(),
+      Err(_) => Vec::new(),
+   };
+
+   let dsdt = tables.dsdt().ok().map(|dsdt| AmlRegion{
+      address: dsdt.address as u64,
+      length: dsdt.length,
+   });
+
+   let platform = Platform{
+      rsdp,
+      revision: tables.revision,
+      cpus,
+      interrupts,
+      hpet,
+      power,
+      pci_segments,
+      dsdt,
+   };
+
+   for line in platform.to_string().lines() {
+      log::info!("{}", line);
+   }
+
+   *PLATFORM.lock() = Some(platform);
+   return Ok(());
+}
+
+/// Parses the ACPI root tables found through the RSDP at physical address `rsdp`.
+pub fn tables(rsdp: u64) -> Result<AcpiTables<KernelAcpiHandler>, AcpiError> {
+   return unsafe{ AcpiTables::from_rsdp(KernelAcpiHandler, rsdp as usize) };
+}
+
+/// Where `size` bytes at `physical` appear in the physical memory window, if they lie inside it.
+fn direct_map(physical: PhysAddr, size: u64) -> Option<VirtAddr> {
+   let space = KERNEL_SPACE.lock();
+   let window = space.regions().find(|region| region.name == "physical-memory")?;
+
+   return match physical.as_u64().checked_add(size)? <= window.size {
+      true => Some(window.start + physical.as_u64()),
+      false => None,
+   };
+}
+
+// IMPORTS //
+
+use {
+   crate::{
+      address::space::{self, KERNEL_SPACE},
+      memory::align_up,
+   },
+   acpi::{
+      address::GenericAddress,
+      fadt::Fadt,
+      mcfg::Mcfg,
+      platform::{
+         interrupt::{InterruptSourceOverride, IoApic, NmiLine, NmiSource},
+         Processor, ProcessorState,
+      },
+      AcpiError, AcpiHandler, AcpiTables, HpetInfo, InterruptModel, PhysicalMapping,
+   },
+   alloc::{string::ToString, vec::Vec},
+   base::log,
+   core::{
+      fmt::{Display, Formatter, Result as FmtResult},
+      ptr::NonNull,
+   },
+   spin::Mutex,
+   x86_64::{
+      structures::paging::{PageSize, PageTableFlags, Size4KiB},
+      PhysAddr, VirtAddr,
+   },
+};
@@ -0,0 +1,299 @@
+/// SLP_EN in a PM1 control register: enter the sleep state in SLP_TYP.
+const SLEEP_ENABLE: u16 = 1 << 13;
+
+/// SLP_TYP field of a PM1 control register.
+const SLEEP_TYPE_SHIFT: u16 = 10;
+const SLEEP_TYPE_MASK: u16 = 0b111 << SLEEP_TYPE_SHIFT;
+
+/// SCI_EN in a PM1 control register: set once the firmware has handed over to ACPI.
+const SCI_ENABLE: u16 = 1 << 0;
+
+/// How long to wait for the firmware to switch into ACPI mode, in polls of the PM1 control block.
+const ACPI_ENABLE_POLLS: usize = 1_000_000;
+
+/// QEMU's `isa-debug-exit` device, at its default port.
+const DEBUG_EXIT_PORT: u16 = 0xF4;
+
+/// The sleep types to write for S5, read from the DSDT at boot.
+static SOFT_OFF: Mutex<Option<SleepType>> = Mutex::new(None);
+
+/// SLP_TYPa and SLP_TYPb values for a sleep state, from its `\_Sx` package.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct SleepType {
+   /// Written to the PM1a control block.
+   pub a: u16,
+
+   /// Written to the PM1b control block.
+   pub b: u16,
+}
+
+/// Looks up the S5 sleep type in the DSDT and installs [`poweroff`] and [`reboot`] as the handlers
+/// behind `base::syscall::shutdown` and `base::syscall::reboot`.
+///
+/// Without ACPI the handlers still work, through the emulator and keyboard controller fallbacks.
+pub fn initialise() {
+   let dsdt = PLATFORM.lock().as_ref().and_then(|platform| platform.dsdt);
+
+   let soft_off = dsdt.and_then(|dsdt| {
+      let mapping = unsafe{ KernelAcpiHandler.map_physical_region::<u8>(dsdt.address as usize, dsdt.length as usize) };
+      let aml = unsafe{ slice::from_raw_parts(mapping.virtual_start().as_ptr() as *const u8, dsdt.length as usize) };
+      find_sleep_type(aml, *b"_S5_")
+   });
+
+   match soft_off {
+      Some(sleep) => log::info!("ACPI S5 sleep type: {:#x}/{:#x}", sleep.a, sleep.b),
+      None => log::warn!("No \\_S5 package in the DSDT; poweroff will rely on fallbacks"),
+   }
+
+   *SOFT_OFF.lock() = soft_off;
+   syscall::set_power_handlers(PowerHandlers{ poweroff, reboot });
+}
+
+/// Turns the machine off.
+///
+/// Enters S5 through the FADT's PM1 control blocks if the DSDT gave a sleep type, and otherwise
+/// tries the emulators' fixed ACPI ports and QEMU's `isa-debug-exit` device.
+pub fn poweroff() -> ! {
+   interrupts::disable();
+
+   let soft_off = SOFT_OFF.try_lock().and_then(|soft_off| *soft_off);
+   let power = PLATFORM.try_lock().and_then(|platform| platform.as_ref().and_then(|platform| platform.power));
+
+   if let (Some(sleep), Some(power)) = (soft_off, power) {
+      log::info!("Entering ACPI S5");
+      unsafe{ enter_sleep_state(&power, sleep) };
+      log::warn!("ACPI S5 did not take effect");
+   }
+
+   unsafe {
+      // The PM1a control block QEMU, Bochs and VirtualBox put at fixed ports, with S5 preset.
+      outw(0x604, 0x2000);
+      outw(0xB004, 0x2000);
+      outw(0x4004, 0x3400);
+
+      // Exits QEMU with status 1 when started with `-device isa-debug-exit`; last, since it is an
+      // error exit rather than a clean poweroff.
+      outl(DEBUG_EXIT_PORT, 0);
+   }
+
+   log::error!("Failed to power off; halting");
+   halt();
+}
+
+/// Restarts the machine.
+///
+/// Tries the FADT reset register, then pulsing the reset line through the 8042 keyboard
+/// controller, and finally a triple fault, which no machine survives.
+pub fn reboot() -> ! {
+   interrupts::disable();
+
+   let power = PLATFORM.try_lock().and_then(|platform| platform.as_ref().and_then(|platform| platform.power));
+
+   if let Some(reset) = power.and_then(|power| power.reset.map(|reset| (reset, power.reset_value))) {
+      log::info!("Resetting through the ACPI reset register");
+      unsafe{ write_register(&reset.0, reset.1 as u32) };
+      spin_for(ACPI_ENABLE_POLLS);
+   }
+
+   if power.map_or(true, |power| power.has_8042) {
+      log::info!("Resetting through the keyboard controller");
+      unsafe {
+         // Wait for the controller's input buffer to drain before sending the command.
+         for _ in 0..ACPI_ENABLE_POLLS {
+            if inb(0x64) & 0x02 == 0 {
+               break;
+            }
+            spin_loop();
+         }
+         outb(0x64, 0xFE);
+      }
+      spin_for(ACPI_ENABLE_POLLS);
+   }
+
+   log::info!("Resetting with a triple fault");
+   unsafe {
+      // With an empty IDT the breakpoint cannot be delivered, and neither can the resulting
+      // double fault.
+      let empty = DescriptorTablePointer{ limit: 0, base: VirtAddr::zero() };
+      lidt(&empty);
+      asm!("int3", options(nomem, nostack));
+   }
+
+   halt();
+}
+
+/// Finds the `\_Sx` package named `name` in the AML byte stream `aml` and reads its first two
+/// elements, without interpreting the AML.
+///
+/// Firmware declares the package as `Name(_S5, Package(){a, b, ...})`, which compiles to NameOp
+/// (0x08), an optional root prefix, the name, PackageOp (0x12), a package length, an element
+/// count and then the elements as integer constants.
+pub fn find_sleep_type(aml: &[u8], name: [u8; 4]) -> Option<SleepType> {
+   let mut start = 0;
+
+   while let Some(found) = aml[start..].windows(4).position(|window| window == name) {
+      let index = start + found;
+      start = index + 1;
+
+      let declared = match index {
+         0 => false,
+         1 => aml[0] == 0x08,
+         _ => aml[index - 1] == 0x08 || (aml[index - 2] == 0x08 && aml[index - 1] == b'\\'),
+      };
+
+      if !declared || aml.get(index + 4) != Some(&0x12) {
+         continue;
+      }
+
+      // The top two bits of the first package length byte count the bytes that follow it.
+      let mut cursor = index + 5;
+      let length = *aml.get(cursor)?;
+      cursor += 1 + (length >> 6) as usize;
+
+      // Skip the element count.
+      cursor += 1;
+
+      let (a, next) = read_integer(aml, cursor)?;
+      let (b, _) = read_integer(aml, next)?;
+      return Some(SleepType{ a: a as u16, b: b as u16 });
+   }
+
+   return None;
+}
+
+/// Reads an AML integer constant at `cursor`, returning it and the offset just past it.
+fn read_integer(aml: &[u8], cursor: usize) -> Option<(u64, usize)> {
+   let opcode = *aml.get(cursor)?;
+   let bytes = |count: usize| -> Option<(u64, usize)> {
+      let data = aml.get(cursor + 1..cursor + 1 + count)?;
+      let value = data.iter().rev().fold(0u64, |value, byte| value << 8 | *byte as u64);
+      Some((value, cursor + 1 + count))
+   };
+
+   return match opcode {
+      0x00 => Some((0, cursor + 1)), // ZeroOp
+      0x01 => Some((1, cursor + 1)), // OneOp
+      0xFF => Some((u64::MAX, cursor + 1)), // OnesOp
+      0x0A => bytes(1), // BytePrefix
+      0x0B => bytes(2), // WordPrefix
+      0x0C => bytes(4), // DWordPrefix
+      0x0E => bytes(8), // QWordPrefix
+      _ => None,
+   };
+}
+
+/// Writes `sleep` with SLP_EN to the PM1 control blocks, switching to ACPI mode first if the
+/// firmware still owns the fixed hardware. Returns only if the machine did not go to sleep.
+unsafe fn enter_sleep_state(power: &PowerManagement, sleep: SleepType) {
+   let control_a = match power.pm1a_control {
+      Some(control) => control,
+      None => return,
+   };
+
+   let owned_by_firmware = read_register(&control_a).map_or(false, |value| value & SCI_ENABLE as u32 == 0);
+   if owned_by_firmware && power.smi_command != 0 {
+      outb(power.smi_command as u16, power.acpi_enable);
+      for _ in 0..ACPI_ENABLE_POLLS {
+         if read_register(&control_a).map_or(true, |value| value & SCI_ENABLE as u32 != 0) {
+            break;
+         }
+         spin_loop();
+      }
+   }
+
+   let controls = [(Some(control_a), sleep.a), (power.pm1b_control, sleep.b)];
+   for (control, sleep_type) in controls.iter().filter_map(|(control, sleep_type)| Some((control.as_ref()?, sleep_type))) {
+      let value = read_register(control).unwrap_or(0) as u16 & !SLEEP_TYPE_MASK;
+      write_register(control, (value | (sleep_type & 0b111) << SLEEP_TYPE_SHIFT | SLEEP_ENABLE) as u32);
+   }
+
+   spin_for(ACPI_ENABLE_POLLS);
+}
+
+/// Reads a port-mapped ACPI register. Other address spaces are not supported.
+unsafe fn read_register(register: &GenericAddress) -> Option<u32> {
+   if register.address_space != AddressSpace::SystemIo {
+      return None;
+   }
+
+   let port = register.address as u16;
+   return match register.bit_width {
+      8 => Some(inb(port) as u32),
+      16 => Some(inw(port) as u32),
+      32 => Some(inl(port)),
+      _ => None,
+   };
+}
+
+/// Writes a port- or memory-mapped ACPI register, ignoring registers in other address spaces.
+unsafe fn write_register(register: &GenericAddress, value: u32) {
+   match register.address_space {
+      AddressSpace::SystemIo => {
+         let port = register.address as u16;
+         match register.bit_width {
+            8 => outb(port, value as u8),
+            16 => outw(port, value as u16),
+            32 => outl(port, value),
+            _ => {},
+         }
+      }
+      AddressSpace::SystemMemory => {
+         let flags = PageTableFlags::PRESENT
+            | PageTableFlags::WRITABLE
+            | PageTableFlags::NO_EXECUTE
+            | Caching::Uncached.flags();
+
+         let physical = PhysAddr::new(register.address);
+         if let Ok(virt) = space::map_physical("acpi-register", physical, Size4KiB::SIZE, flags) {
+            match register.bit_width {
+               8 => virt.as_mut_ptr::<u8>().write_volatile(value as u8),
+               16 => virt.as_mut_ptr::<u16>().write_volatile(value as u16),
+               _ => virt.as_mut_ptr::<u32>().write_volatile(value),
+            }
+         }
+      }
+      _ => {},
+   }
+}
+
+fn spin_for(polls: usize) {
+   for _ in 0..polls {
+      spin_loop();
+   }
+}
+
+fn halt() -> ! {
+   loop {
+      interrupts::disable();
+      x86_64::instructions::hlt();
+   }
+}
+
+// IMPORTS //
+
+use {
+   crate::{
+      address::space,
+      memory::Caching,
+      platform::{KernelAcpiHandler, PowerManagement, PLATFORM},
+   },
+   acpi::{
+      address::{AddressSpace, GenericAddress},
+      AcpiHandler,
+   },
+   base::{
+      log,
+      syscall::{self, PowerHandlers},
+   },
+   core::{arch::asm, hint::spin_loop, slice},
+   spin::Mutex,
+   x86::io::{inb, inl, inw, outb, outl, outw},
+   x86_64::{
+      instructions::{interrupts, tables::lidt},
+      structures::{
+         paging::{PageSize, PageTableFlags, Size4KiB},
+         DescriptorTablePointer,
+      },
+      PhysAddr, VirtAddr,
+   },
+};
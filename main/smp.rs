@@ -0,0 +1,381 @@
+/// How long an application processor has to check in after its startup IPIs.
+const STARTUP_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// CPUs running kernel code, the boot processor included.
+static ONLINE: AtomicUsize = AtomicUsize::new(1);
+
+/// Set by an application processor once it no longer needs the trampoline.
+static CHECKED_IN: AtomicBool = AtomicBool::new(false);
+
+/// Why the application processors could not be started at all.
+#[derive(Debug)]
+pub enum SmpError {
+   /// The ACPI tables list no processors, or the APICs are not in use.
+   NoApic,
+
+   /// No free frame below 1 MiB for the trampoline.
+   NoLowMemory,
+
+   /// The kernel's level 4 table lies above 4 GiB, out of reach of the trampoline's 32-bit load.
+   PageTablesTooHigh,
+
+   /// The trampoline could not be identity mapped.
+   Map(MapToError<Size4KiB>),
+}
+
+impl Display for SmpError {
+   fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+      match self {
+         SmpError::NoApic => write!(f, "no APIC or no processor list"),
+         SmpError::NoLowMemory => write!(f, "no memory below 1 MiB for the trampoline"),
+         SmpError::PageTablesTooHigh => write!(f, "the level 4 page table lies above 4 GiB"),
+         SmpError::Map(error) => write!(f, "failed to identity map the trampoline: {:?}", error),
+      }
+   }
+}
+
+/// What an application processor needs once it reaches long mode, prepared by the boot processor
+/// so that the application processor never has to allocate before it is set up.
+struct ApStart {
+   tables: &'static CpuTables,
+   cpu: Box<PerCpu>,
+}
+
+/// Gives the boot processor its per-CPU area, as CPU 0. Runs once its GDT and TSS are loaded, and
+/// before interrupts are enabled.
+pub fn initialise_boot_cpu() {
+   let apic_id = CpuId::new().get_feature_info().map_or(0, |features| features.initial_local_apic_id() as u32);
+   let cpu = PerCpu::new(0, apic_id, &gdt::boot_tables().tss);
+   unsafe{ percpu::install(Box::new(cpu)) };
+}
+
+/// Number of CPUs running kernel code.
+pub fn online_cpus() -> usize {
+   return ONLINE.load(Ordering::Acquire);
+}
+
+/// Starts every usable application processor the MADT lists, one at a time, and leaves each
+/// parked in [`idle`]. Returns how many came up.
+///
+/// Each is sent INIT, then up to two startup IPIs pointing at a real-mode trampoline copied below
+/// 1 MiB. The trampoline switches straight to long mode on the kernel's page tables and calls
+/// [`ap_entry`] on a fresh kernel stack. Must run after the APICs are enabled, with the boot
+/// processor free to wait.
+pub fn initialise() -> Result<usize, SmpError> {
+   if !apic::is_enabled() {
+      return Err(SmpError::NoApic);
+   }
+
+   let cpus: Vec<Cpu> = match PLATFORM.lock().as_ref() {
+      Some(platform) => platform.cpus.iter().copied().filter(|cpu| !cpu.is_bsp && cpu.usable).collect(),
+      None => return Err(SmpError::NoApic),
+   };
+
+   if cpus.is_empty() {
+      return Ok(0);
+   }
+
+   let (l4table, _) = Cr3::read();
+   if l4table.start_address().as_u64() >= 1 << 32 {
+      return Err(SmpError::PageTablesTooHigh);
+   }
+
+   let trampoline = Trampoline::install(l4table.start_address())?;
+   let mut started = 0;
+
+   for (index, cpu) in cpus.iter().enumerate() {
+      // The boot processor is CPU 0.
+      let index = index + 1;
+
+      match trampoline.start(index, cpu.apic_id) {
+         Ok(()) => started += 1,
+         Err(error) => log::warn!("CPU {} (APIC {}) did not start: {}", index, cpu.apic_id, error),
+      }
+   }
+
+   trampoline.remove();
+   log::info!("{} of {} application processors started; {} CPUs online", started, cpus.len(), online_cpus());
+
+   return Ok(started);
+}
+
+/// Where an application processor lands in long mode. Installs its per-CPU area, loads its own
+/// GDT, TSS and IDT, turns on the same protections and page attributes as the boot processor,
+/// enables its local APIC and timer and parks.
+extern "C" fn ap_entry(start: usize) -> ! {
+   let ApStart{ tables, cpu } = *unsafe{ Box::from_raw(start as *mut ApStart) };
+
+   // First, so that an exception from here on finds this CPU's area and not the boot processor's.
+   let cpu = unsafe{ percpu::install(cpu) };
+
+   unsafe {
+      tables.load();
+      interrupts::IDT.load();
+   }
+
+   // The boot processor may drop the trampoline now.
+   CHECKED_IN.store(true, Ordering::Release);
+
+   x86_64::enable_protection();
+   memory::initialise_page_attributes();
+   apic::initialise_local();
+
+   // Without a tick, a halt in `time::sleep` would only end at the next IPI.
+   apic::start_timer();
+
+   ONLINE.fetch_add(1, Ordering::AcqRel);
+   log::info!("CPU {} online (APIC {})", cpu.id, cpu.apic_id);
+
+   idle();
+}
+
+/// Halts between interrupts forever. Application processors wait here until there is work for
+/// them, woken by their local APIC timers, IPIs and routed interrupts.
+pub fn idle() -> ! {
+   instructions::interrupts::enable();
+   loop {
+      instructions::hlt();
+   }
+}
+
+/// The real-mode startup code, copied into a frame below 1 MiB and identity mapped for as long
+/// as application processors are being started.
+struct Trampoline {
+   frame: PhysFrame,
+   window: VirtAddr,
+}
+
+impl Trampoline {
+   /// Copies the trampoline below 1 MiB, identity maps it and fills in everything shared by all
+   /// application processors.
+   fn install(l4table: PhysAddr) -> Result<Self, SmpError> {
+      let frame = memory::with_kernel_memory(|mapper, frames| {
+         // Frame 0 holds the real-mode IVT, and a startup IPI cannot point at it anyway.
+         let frame = match frames.allocate_contiguous_below(1, 1, PhysAddr::new(0x10_0000)) {
+            Some(frame) if frame.start_address().as_u64() == 0 => frames.allocate_contiguous_below(1, 1, PhysAddr::new(0x10_0000)),
+            frame => frame,
+         }.ok_or(SmpError::NoLowMemory)?;
+
+         let page = Page::<Size4KiB>::containing_address(VirtAddr::new(frame.start_address().as_u64()));
+         unsafe {
+            mapper.map_to(page, frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE, frames)
+               .map_err(SmpError::Map)?
+               .flush();
+         }
+
+         return Ok(frame);
+      })?;
+
+      let trampoline = Trampoline{ frame, window: VirtAddr::new(frame.start_address().as_u64()) };
+      let base = frame.start_address().as_u64();
+
+      unsafe {
+         let start = addr_of!(smp_trampoline_start);
+         let length = addr_of!(smp_trampoline_end) as usize - start as usize;
+         copy_nonoverlapping(start, trampoline.window.as_mut_ptr::<u8>(), length);
+
+         // Real mode addresses everything relative to the trampoline, but the GDT base and the
+         // far jump into long mode need linear addresses.
+         trampoline.write(addr_of!(smp_trampoline_gdtr) as usize + 2, (base + trampoline.offset(addr_of!(smp_trampoline_gdt)) as u64) as u32);
+         trampoline.write(addr_of!(smp_trampoline_far_jump) as usize, (base + trampoline.offset(addr_of!(smp_trampoline_long_mode)) as u64) as u32);
+         trampoline.write(addr_of!(smp_trampoline_cr3) as usize, l4table.as_u64());
+         trampoline.write(addr_of!(smp_trampoline_entry) as usize, ap_entry as usize as u64);
+      }
+
+      return Ok(trampoline);
+   }
+
+   /// Starts the CPU with local APIC ID `apic_id` as CPU `index`, and waits for it to check in.
+   fn start(&self, index: usize, apic_id: u32) -> Result<(), &'static str> {
+      let name = Box::leak(format!("cpu{}-stack", index).into_boxed_str());
+      let stack = KernelStack::allocate(name, BOOT_STACK_SIZE).map_err(|_| "failed to allocate its stack")?;
+
+      let name = Box::leak(format!("cpu{}-double-fault-stack", index).into_boxed_str());
+      let tables = gdt::allocate(name).map_err(|_| "failed to allocate its GDT and TSS")?;
+
+      let start = Box::into_raw(Box::new(ApStart{ tables, cpu: Box::new(PerCpu::new(index, apic_id, &tables.tss)) }));
+
+      unsafe {
+         self.write(addr_of!(smp_trampoline_stack) as usize, stack.top.as_u64());
+         self.write(addr_of!(smp_trampoline_argument) as usize, start as u64);
+      }
+
+      CHECKED_IN.store(false, Ordering::Release);
+      fence(Ordering::SeqCst);
+
+      let page = (self.frame.start_address().as_u64() >> 12) as u8;
+      apic::send_init(apic_id);
+      time::busy_wait(Duration::from_millis(10));
+
+      // The second startup IPI is only for CPUs that missed the first; one already running the
+      // trampoline ignores it.
+      apic::send_startup(apic_id, page);
+      time::busy_wait(Duration::from_micros(200));
+      apic::send_startup(apic_id, page);
+
+      let sent = Instant::now();
+      while sent.elapsed() < STARTUP_TIMEOUT {
+         if CHECKED_IN.load(Ordering::Acquire) {
+            return Ok(());
+         }
+         spin_loop();
+      }
+
+      // Hold the CPU in reset, so that it cannot wake up late on the trampoline's stack and
+      // argument once they belong to the next CPU. No more startup IPIs are sent to it.
+      apic::send_init(apic_id);
+
+      // The start parameters, stack and tables are leaked: the CPU may have got as far as taking
+      // them before it was stopped.
+      return Err("timed out");
+   }
+
+   /// Unmaps the trampoline and gives its frame back.
+   fn remove(self) {
+      memory::with_kernel_memory(|mapper, frames| {
+         let page = Page::<Size4KiB>::containing_address(self.window);
+         if let Ok((_, flush)) = mapper.unmap(page) {
+            flush.flush();
+         }
+
+         unsafe{ frames.deallocate_contiguous(self.frame, 1) };
+      });
+   }
+
+   /// Offset of `symbol` from the start of the trampoline.
+   fn offset(&self, symbol: *const u8) -> usize {
+      return symbol as usize - unsafe{ addr_of!(smp_trampoline_start) } as usize;
+   }
+
+   /// Writes `value` over the copy of the trampoline variable whose original is at `symbol`.
+   unsafe fn write<T>(&self, symbol: usize, value: T) {
+      let offset = self.offset(symbol as *const u8);
+      (self.window + offset).as_mut_ptr::<T>().write_unaligned(value);
+   }
+}
+
+extern "C" {
+   static smp_trampoline_start: u8;
+   static smp_trampoline_long_mode: u8;
+   static smp_trampoline_gdt: u8;
+   static smp_trampoline_gdtr: u8;
+   static smp_trampoline_far_jump: u8;
+   static smp_trampoline_cr3: u8;
+   static smp_trampoline_stack: u8;
+   static smp_trampoline_entry: u8;
+   static smp_trampoline_argument: u8;
+   static smp_trampoline_end: u8;
+}
+
+// The startup IPI leaves the CPU in real mode at CS:0, with CS pointing at the trampoline. It
+// turns on PAE, long mode and NX, loads the kernel's page tables, and enables protection and
+// paging together, landing in a 64-bit code segment from its own small GDT. CR0 is loaded whole
+// (PG, ET and PE) rather than ORed into, since INIT leaves CD and NW set and the CPU would run
+// uncached. Everything past that is RIP-relative, so the copy runs wherever it is placed.
+global_asm!(
+   ".pushsection .text.smp_trampoline, \"ax\"",
+   ".global smp_trampoline_start",
+   ".global smp_trampoline_long_mode",
+   ".global smp_trampoline_gdt",
+   ".global smp_trampoline_gdtr",
+   ".global smp_trampoline_far_jump",
+   ".global smp_trampoline_cr3",
+   ".global smp_trampoline_stack",
+   ".global smp_trampoline_entry",
+   ".global smp_trampoline_argument",
+   ".global smp_trampoline_end",
+   ".code16",
+   "smp_trampoline_start:",
+   "   cli",
+   "   cld",
+   "   mov %cs, %ax",
+   "   mov %ax, %ds",
+   "   mov %cr4, %eax",
+   "   or $(1 << 5), %eax",
+   "   mov %eax, %cr4",
+   "   mov (smp_trampoline_cr3 - smp_trampoline_start), %eax",
+   "   mov %eax, %cr3",
+   "   mov $0xC0000080, %ecx",
+   "   rdmsr",
+   "   or $((1 << 8) | (1 << 11)), %eax",
+   "   wrmsr",
+   "   lgdtl (smp_trampoline_gdtr - smp_trampoline_start)",
+   "   mov $0x80000011, %eax",
+   "   mov %eax, %cr0",
+   "   ljmpl *(smp_trampoline_far_jump - smp_trampoline_start)",
+   ".code64",
+   "smp_trampoline_long_mode:",
+   "   mov $0x10, %ax",
+   "   mov %ax, %ds",
+   "   mov %ax, %es",
+   "   mov %ax, %ss",
+   "   mov smp_trampoline_stack(%rip), %rsp",
+   "   mov smp_trampoline_argument(%rip), %rdi",
+   "   xor %ebp, %ebp",
+   "   mov smp_trampoline_entry(%rip), %rax",
+   "   call *%rax",
+   "   ud2",
+   ".balign 8",
+   "smp_trampoline_gdt:",
+   "   .quad 0",
+   "   .quad 0x00209A0000000000",
+   "   .quad 0x0000920000000000",
+   "smp_trampoline_gdtr:",
+   "   .word smp_trampoline_gdtr - smp_trampoline_gdt - 1",
+   "   .long 0",
+   ".balign 8",
+   "smp_trampoline_far_jump:",
+   "   .long 0",
+   "   .word 0x08",
+   ".balign 8",
+   "smp_trampoline_cr3:",
+   "   .quad 0",
+   "smp_trampoline_stack:",
+   "   .quad 0",
+   "smp_trampoline_entry:",
+   "   .quad 0",
+   "smp_trampoline_argument:",
+   "   .quad 0",
+   "smp_trampoline_end:",
+   ".popsection",
+   options(att_syntax),
+);
+
+// IMPORTS //
+
+use {
+   crate::{
+      apic,
+      arch::x86_64,
+      gdt::{self, CpuTables},
+      interrupts,
+      memory::{
+         self,
+         stack::{KernelStack, BOOT_STACK_SIZE},
+      },
+      platform::{Cpu, PLATFORM},
+   },
+   alloc::{boxed::Box, format, vec::Vec},
+   base::{
+      log,
+      percpu::{self, PerCpu},
+      time::{self, Duration, Instant},
+   },
+   core::{
+      arch::global_asm,
+      fmt::{Display, Formatter, Result as FmtResult},
+      hint::spin_loop,
+      ptr::{addr_of, copy_nonoverlapping},
+      sync::atomic::{fence, AtomicBool, AtomicUsize, Ordering},
+   },
+   x86::cpuid::CpuId,
+   ::x86_64::{
+      instructions,
+      registers::control::Cr3,
+      structures::paging::{
+         mapper::MapToError,
+         Mapper, Page, PageTableFlags, PhysFrame, Size4KiB,
+      },
+      PhysAddr, VirtAddr,
+   },
+};
@@ -4,3 +4,301 @@ pub fn trivial_assertion() {
    assert_eq!(1, 1);
    println!("[ok]");
 }
+
+/// QEMU's `Name (_S5, Package (0x04) { Zero, Zero, Zero, Zero })`.
+#[cfg(test)]
+#[test_case]
+pub fn sleep_type_qemu() {
+   print!("S5 sleep type from QEMU's DSDT: ");
+   let aml = [0x08, b'_', b'S', b'5', b'_', 0x12, 0x06, 0x04, 0x00, 0x00, 0x00, 0x00];
+   assert_eq!(find_sleep_type(&aml, *b"_S5_"), Some(SleepType{ a: 0, b: 0 }));
+   println!("[ok]");
+}
+
+/// `Name (\_S5, Package (0x04) { 0x07, 0x07, Zero, Zero })`, with a root prefix and byte
+/// constants, after a method that only refers to `\_S5`.
+#[cfg(test)]
+#[test_case]
+pub fn sleep_type_root_prefixed() {
+   print!("S5 sleep type behind a root prefix: ");
+   let aml = [
+      // Method (_PTS, 1) { Store (\_S5, Local0) }
+      0x14, 0x0D, b'_', b'P', b'T', b'S', 0x01, 0x70, b'\\', b'_', b'S', b'5', b'_', 0x60,
+      // Name (\_S5, Package (0x04) { 0x07, 0x07, Zero, Zero })
+      0x08, b'\\', b'_', b'S', b'5', b'_', 0x12, 0x08, 0x04, 0x0A, 0x07, 0x0A, 0x07, 0x00, 0x00,
+   ];
+   assert_eq!(find_sleep_type(&aml, *b"_S5_"), Some(SleepType{ a: 7, b: 7 }));
+   println!("[ok]");
+}
+
+#[cfg(test)]
+#[test_case]
+pub fn sleep_type_missing() {
+   print!("No sleep type without a package: ");
+   let aml = [0x08, b'_', b'S', b'4', b'_', 0x12, 0x06, 0x04, 0x00, 0x00, 0x00, 0x00];
+   assert_eq!(find_sleep_type(&aml, *b"_S5_"), None);
+   assert_eq!(find_sleep_type(&aml[..9], *b"_S4_"), None);
+   println!("[ok]");
+}
+
+#[cfg(test)]
+#[test_case]
+pub fn date_time_round_trip() {
+   print!("Unix time round trip: ");
+   let epoch = DateTime{ year: 1970, month: 1, day: 1, hour: 0, minute: 0, second: 0 };
+   assert_eq!(DateTime::from_unix(0), epoch);
+   assert_eq!(epoch.to_unix(), 0);
+
+   for seconds in [1, 86_399, 86_400, 951_782_400, 1_709_251_199, 4_107_542_400] {
+      let time = DateTime::from_unix(seconds);
+      assert!(time.is_valid());
+      assert_eq!(time.to_unix(), seconds);
+   }
+   println!("[ok]");
+}
+
+#[cfg(test)]
+#[test_case]
+pub fn date_time_leap_days() {
+   print!("Leap days: ");
+   let leap = DateTime{ year: 2024, month: 2, day: 29, hour: 23, minute: 59, second: 59 };
+   assert_eq!(DateTime::from_unix(1_709_251_199), leap);
+   assert_eq!(DateTime::from_unix(951_782_400), DateTime{ year: 2000, month: 2, day: 29, hour: 0, minute: 0, second: 0 });
+   assert_eq!(DateTime::from_unix(4_107_542_400), DateTime{ year: 2100, month: 3, day: 1, hour: 0, minute: 0, second: 0 });
+
+   assert!(leap.is_valid());
+   assert!(DateTime{ year: 2000, ..leap }.is_valid());
+   assert!(!DateTime{ year: 2023, ..leap }.is_valid());
+   assert!(!DateTime{ year: 2100, ..leap }.is_valid());
+   assert!(!DateTime{ day: 30, ..leap }.is_valid());
+   assert!(!DateTime{ year: 1969, month: 12, day: 31, ..leap }.is_valid());
+   assert!(!DateTime{ month: 13, ..leap }.is_valid());
+   assert!(!DateTime{ hour: 24, ..leap }.is_valid());
+   println!("[ok]");
+}
+
+#[cfg(test)]
+#[test_case]
+pub fn rtc_decoding() {
+   print!("RTC register decoding: ");
+   let expected = DateTime{ year: 2024, month: 2, day: 29, hour: 23, minute: 59, second: 58 };
+
+   // BCD, 24-hour, with and without a century register.
+   assert_eq!(rtc::decode([0x58, 0x59, 0x23, 0x29, 0x02, 0x24, 0x20], 0x02, true), expected);
+   assert_eq!(rtc::decode([0x58, 0x59, 0x23, 0x29, 0x02, 0x24, 0x00], 0x02, false), expected);
+
+   // Binary, 24-hour.
+   assert_eq!(rtc::decode([58, 59, 23, 29, 2, 24, 20], 0x06, true), expected);
+
+   // BCD, 12-hour: 11 PM, noon and midnight.
+   assert_eq!(rtc::decode([0x58, 0x59, 0x91, 0x29, 0x02, 0x24, 0x20], 0x00, true), expected);
+   assert_eq!(rtc::decode([0x58, 0x59, 0x92, 0x29, 0x02, 0x24, 0x20], 0x00, true).hour, 12);
+   assert_eq!(rtc::decode([0x58, 0x59, 0x12, 0x29, 0x02, 0x24, 0x20], 0x00, true).hour, 0);
+
+   // Binary, 12-hour.
+   assert_eq!(rtc::decode([58, 59, 0x80 | 11, 29, 2, 24, 20], 0x04, true), expected);
+   println!("[ok]");
+}
+
+#[cfg(test)]
+#[test_case]
+pub fn slab_transitions() {
+   print!("Slab partial, full and empty transitions: ");
+   let mut cache = SlabCache::new("test-slab", 64, 8);
+   let perSlab = cache.objects_per_slab();
+   let mut objects = Vec::new();
+
+   // The first allocation takes a slab from the heap, which stays partial until it is full.
+   objects.push(cache.allocate().unwrap());
+   assert_eq!((cache.slabs, cache.active), (1, 1));
+
+   while objects.len() < perSlab {
+      objects.push(cache.allocate().unwrap());
+   }
+   assert_eq!(cache.slabs, 1);
+
+   // With the only slab full, the next allocation needs a second one.
+   objects.push(cache.allocate().unwrap());
+   assert_eq!(cache.slabs, 2);
+
+   // Emptying the second slab keeps it back, and the next allocation reuses it.
+   unsafe{ cache.deallocate(objects.pop().unwrap()) };
+   assert_eq!((cache.slabs, cache.active), (2, perSlab));
+   objects.push(cache.allocate().unwrap());
+   assert_eq!(cache.slabs, 2);
+   unsafe{ cache.deallocate(objects.pop().unwrap()) };
+
+   // A free makes the full slab partial again, and partial slabs are served before empty ones.
+   let freed = objects.swap_remove(0);
+   unsafe{ cache.deallocate(freed) };
+   let again = cache.allocate().unwrap();
+   assert_eq!(again, freed);
+   objects.push(again);
+
+   // Only one empty slab is kept, so emptying the first as well returns one to the heap.
+   for object in objects.drain(..) {
+      unsafe{ cache.deallocate(object) };
+   }
+   assert_eq!((cache.slabs, cache.active), (1, 0));
+
+   cache.shrink();
+   assert_eq!(cache.slabs, 0);
+   assert_eq!(cache.allocations, cache.frees);
+   println!("[ok]");
+}
+
+/// Free bytes, free blocks and the largest free block.
+#[cfg(test)]
+fn shape(fragmentation: Fragmentation) -> (usize, usize, usize) {
+   return (fragmentation.free, fragmentation.free_blocks, fragmentation.largest_free);
+}
+
+#[cfg(test)]
+#[repr(align(8192))]
+struct HeapArena([u8; 8192]);
+
+#[cfg(test)]
+#[test_case]
+pub fn buddy_split_merge() {
+   print!("Buddy heap splitting, merging and accounting: ");
+   static mut ARENA: HeapArena = HeapArena([0; 8192]);
+
+   let base = unsafe{ addr_of_mut!(ARENA) } as usize;
+   let layout = |size: usize, align: usize| Layout::from_size_align(size, align).unwrap();
+   let mut heap = Heap::<32>::new();
+
+   // 344 bytes of bitmaps leave free blocks of 32, 128, 512, 1024, 2048 and 4096 bytes.
+   unsafe{ heap.add_to_heap(base, base + 8192) };
+   assert_eq!(heap.total, 8192);
+   let initial = shape(heap.fragmentation());
+   assert_eq!(initial, (7840, 6, 4096));
+
+   unsafe {
+      // Two small requests split the 32-byte block into 16-byte buddies. Their accounting keeps
+      // the 8-byte minimum.
+      let a = heap.allocate(layout(8, 8)).unwrap();
+      let b = heap.allocate(layout(12, 4)).unwrap();
+      assert_eq!((a.as_ptr() as usize, b.as_ptr() as usize), (base + 0x160, base + 0x170));
+      assert_eq!((heap.allocated, heap.user), (8 + 16, 8 + 12));
+
+      // 256 bytes split the 512-byte block, leaving its upper half free.
+      let c = heap.allocate(layout(256, 8)).unwrap();
+      assert_eq!(c.as_ptr() as usize, base + 0x200);
+      assert_eq!(shape(heap.fragmentation()), (7840 - 32 - 256, 5, 4096));
+
+      // Freeing each merges it with its buddy back into the block it was split from.
+      heap.deallocate(b, layout(12, 4));
+      heap.deallocate(c, layout(256, 8));
+      assert_eq!(shape(heap.fragmentation()), (7840 - 16, 6, 4096));
+      heap.deallocate(a, layout(8, 8));
+   }
+
+   assert_eq!(shape(heap.fragmentation()), initial);
+   assert_eq!((heap.allocated, heap.user, heap.total), (0, 0, 8192));
+   println!("[ok]");
+}
+
+#[cfg(test)]
+#[test_case]
+pub fn buddy_regions_stay_apart() {
+   print!("Buddy blocks never merge across regions: ");
+   static mut ARENA: HeapArena = HeapArena([0; 8192]);
+
+   let base = unsafe{ addr_of_mut!(ARENA) } as usize;
+   let layout = |size: usize| Layout::from_size_align(size, 8).unwrap();
+   let mut heap = Heap::<32>::new();
+
+   // The first region ends in a 2048-byte block at 0x1000, whose buddy at 0x1800 is the start of
+   // the second region.
+   unsafe {
+      heap.add_to_heap(base, base + 0x1800);
+      heap.add_to_heap(base + 0x1800, base + 0x2000);
+   }
+   assert_eq!(heap.total, 0x2000);
+   let initial = shape(heap.fragmentation());
+
+   unsafe {
+      let last = heap.allocate(layout(2048)).unwrap();
+      let next = heap.allocate(layout(1024)).unwrap();
+      assert_eq!((last.as_ptr() as usize, next.as_ptr() as usize), (base + 0x1000, base + 0x1c00));
+
+      heap.deallocate(last, layout(2048));
+      heap.deallocate(next, layout(1024));
+   }
+
+   let after = shape(heap.fragmentation());
+   assert_eq!(after, initial);
+   assert_eq!(after.2, 2048);
+   assert_eq!((heap.allocated, heap.user), (0, 0));
+   println!("[ok]");
+}
+
+#[cfg(test)]
+#[test_case]
+pub fn best_fit_allocator() {
+   print!("Best-fit selection and coalescing: ");
+
+   #[repr(align(64))]
+   struct Arena([u8; 1024]);
+   static mut ARENA: Arena = Arena([0; 1024]);
+
+   let base = unsafe{ addr_of_mut!(ARENA) } as usize;
+   let layout = |size: usize| Layout::from_size_align(size, 16).unwrap();
+   let mut fit = FIT_ALLOCATOR.lock();
+
+   // Two separate ranges, 256 and 128 bytes, which can never merge.
+   let (total, allocated, user) = (fit.total, fit.allocated, fit.user);
+   unsafe {
+      fit.add_to_heap(base, base + 256);
+      fit.add_to_heap(base + 512, base + 640);
+   }
+   assert_eq!(fit.total - total, 384);
+
+   unsafe {
+      // 96 bytes leave less over in the smaller range, and 128 fit it exactly.
+      let small = fit.allocate(layout(96)).unwrap();
+      assert_eq!(small.as_ptr() as usize, base + 512);
+      fit.deallocate(small, layout(96));
+
+      let exact = fit.allocate(layout(128)).unwrap();
+      assert_eq!(exact.as_ptr() as usize, base + 512);
+
+      // Requests are rounded up to the granule, but only the requested bytes count as user bytes.
+      let blocks = [fit.allocate(layout(64)).unwrap(), fit.allocate(layout(64)).unwrap(), fit.allocate(layout(50)).unwrap()];
+      assert_eq!(fit.allocated - allocated, 128 + 64 + 64 + 64);
+      assert_eq!(fit.user - user, 128 + 64 + 64 + 50);
+      assert_eq!(blocks.map(|block| block.as_ptr() as usize), [base, base + 64, base + 128]);
+
+      // Freeing the outer two leaves two blocks; the middle one joins them and the tail.
+      fit.deallocate(blocks[0], layout(64));
+      fit.deallocate(blocks[2], layout(50));
+      assert_eq!(fit.fragmentation().free_blocks, 2);
+
+      fit.deallocate(blocks[1], layout(64));
+      let fragmentation = fit.fragmentation();
+      assert_eq!((fragmentation.free_blocks, fragmentation.largest_free), (1, 256));
+
+      fit.deallocate(exact, layout(128));
+      let fragmentation = fit.fragmentation();
+      assert_eq!((fragmentation.free_blocks, fragmentation.free), (2, 384));
+   }
+
+   assert_eq!((fit.allocated, fit.user), (allocated, user));
+   println!("[ok]");
+}
+
+// IMPORTS //
+
+#[cfg(test)]
+use {
+   crate::{
+      arch::x86_64::rtc,
+      power::{find_sleep_type, SleepType},
+   },
+   alloc::vec::Vec,
+   base::{
+      alloc::{heap::Heap, paging::FIT_ALLOCATOR, slab::SlabCache, Fragmentation, Layout},
+      time::DateTime,
+   },
+   core::ptr::addr_of_mut,
+};